@@ -1,30 +1,70 @@
-use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
-use eater::{EaterSim, EaterVm};
+use criterion::measurement::WallTime;
+use criterion::{
+    criterion_group, criterion_main, BatchSize, BenchmarkGroup, BenchmarkId, Criterion,
+};
+use eater::{Cpu, EaterBatch, EaterMemo, EaterSim, EaterThreaded, EaterVm};
+
+#[allow(dead_code)]
+#[path = "../src/fixtures.rs"]
+mod fixtures;
+
+/// Counts down from 255 to zero without any output, so the benchmark measures execution.
+const COUNTDOWN: [u8; 16] = [
+    0x1f, 0x3e, 0x84, 0x61, 0xf0, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0xff,
+];
+
+fn bench_cpu<C: Cpu>(group: &mut BenchmarkGroup<WallTime>, name: &str, cpu: &mut C) {
+    for (program, mem) in [("print 3's", fixtures::EXAMPLE), ("countdown", &COUNTDOWN)] {
+        group.bench_function(BenchmarkId::new(name, program), |b| {
+            b.iter(|| {
+                cpu.load(mem).unwrap();
+                cpu.reset();
+                cpu.run().unwrap();
+            })
+        });
+    }
+}
 
 fn bench_vm(c: &mut Criterion) {
     let mut group = c.benchmark_group("vm");
 
-    let mut interp = EaterVm::new();
-    let mut sim = EaterSim::new();
+    bench_cpu(&mut group, "Interpreter", &mut EaterVm::new());
+    bench_cpu(&mut group, "Simulator", &mut EaterSim::new());
+    bench_cpu(&mut group, "Threaded", &mut EaterThreaded::new());
+    // Reusing the executor replays every run from its cache
+    bench_cpu(&mut group, "Memoized (warm)", &mut EaterMemo::new());
+    for (program, mem) in [("print 3's", fixtures::EXAMPLE), ("countdown", &COUNTDOWN)] {
+        group.bench_function(BenchmarkId::new("Memoized (cold)", program), |b| {
+            b.iter_batched(
+                EaterMemo::new,
+                |mut memo| {
+                    memo.load(mem).unwrap();
+                    memo.run().unwrap();
+                },
+                BatchSize::SmallInput,
+            )
+        });
+    }
+}
 
-    let program = [
-        0x1e, 0x2f, 0xe0, 0x75, 0x61, 0xf0, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-        0x03,
-    ];
+fn bench_batch(c: &mut Criterion) {
+    const LIMIT: u64 = 256;
+    let programs = fixtures::random_programs(4096);
+    let mut group = c.benchmark_group("batch");
 
-    group.bench_function(BenchmarkId::new("Interpreter", "print 3's"), |b| {
+    group.bench_function(BenchmarkId::new("Interpreter", "4096 programs"), |b| {
         b.iter(|| {
-            interp.load(&program);
-            interp.run();
+            for program in &programs {
+                let mut vm = EaterVm::with_output(Vec::new());
+                vm.load(program).unwrap();
+                while vm.cycles() < LIMIT && !vm.step_instruction().unwrap() {}
+            }
         })
     });
-    group.bench_function(BenchmarkId::new("Simulator", "print 3's"), |b| {
-        b.iter(|| {
-            sim.load(&program);
-            sim.run();
-        })
+    group.bench_function(BenchmarkId::new("Batch", "4096 programs"), |b| {
+        b.iter(|| EaterBatch::new(&programs).run(LIMIT))
     });
 }
 
-criterion_group!(benches, bench_vm);
+criterion_group!(benches, bench_vm, bench_batch);
 criterion_main!(benches);
@@ -0,0 +1,23 @@
+//! Run the interpreter and the cycle simulator in lockstep on arbitrary programs.
+//!
+//! `cargo fuzz run diff` from `episodes/ep01`. A divergence panics with the minimized case: its
+//! memory as hex, then a comment with the starting pc, A, flags and opcode policy. `eater run`
+//! loads the memory but always starts from reset, so a case that starts from another state has to
+//! be rebuilt as a `Case` and run with `Case::check`.
+
+#![no_main]
+
+use eater::diff::Case;
+use libfuzzer_sys::fuzz_target;
+
+/// Instructions per case; enough to go around any 16-byte program's loops a few times.
+const LIMIT: u64 = 256;
+
+fuzz_target!(|data: &[u8]| {
+    if let Some(case) = Case::from_bytes(data) {
+        if case.check(LIMIT).is_err() {
+            let case = case.minimize(LIMIT);
+            panic!("{}\n{}", case.check(LIMIT).unwrap_err(), case);
+        }
+    }
+});
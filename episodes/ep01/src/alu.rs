@@ -0,0 +1,68 @@
+use crate::cpu::Flags;
+
+/// The 74LS283 adder with its B input passed through XOR gates.
+///
+/// Subtraction inverts B and sets the carry-in, so it computes `a + !b + 1`. The carry flag is
+/// the adder's carry-out in both cases, which means it is set on subtraction when no borrow
+/// occurred (`a >= b`). The zero flag is set when every output bit is low.
+pub(crate) fn alu(a: u8, b: u8, subtract: bool) -> (u8, Flags) {
+    let b = if subtract { !b } else { b };
+    let sum = a as u16 + b as u16 + subtract as u16;
+    let result = sum as u8;
+
+    let mut flags = Flags::CLEAR;
+    flags.set(Flags::Z, result == 0);
+    flags.set(Flags::C, sum > 0xff);
+
+    (result, flags)
+}
+
+/// Conformance table of `(a, b, subtract, result, flags)` for every ADD/SUB edge case, shared by
+/// the backend tests.
+#[cfg(test)]
+pub(crate) fn conformance_cases() -> Vec<(u8, u8, bool, u8, Flags)> {
+    let zc = Flags::Z | Flags::C;
+
+    vec![
+        // ADD
+        (0x00, 0x00, false, 0x00, Flags::Z),
+        (0x00, 0x01, false, 0x01, Flags::CLEAR),
+        (0x7f, 0x01, false, 0x80, Flags::CLEAR),
+        (0xff, 0x00, false, 0xff, Flags::CLEAR),
+        (0xfe, 0x01, false, 0xff, Flags::CLEAR),
+        (0xff, 0x01, false, 0x00, zc),
+        (0x80, 0x80, false, 0x00, zc),
+        (0xff, 0xff, false, 0xfe, Flags::C),
+        (0xf0, 0x20, false, 0x10, Flags::C),
+        // SUB: carry is set when there is no borrow
+        (0x00, 0x00, true, 0x00, zc),
+        (0x05, 0x05, true, 0x00, zc),
+        (0xff, 0xff, true, 0x00, zc),
+        (0x05, 0x03, true, 0x02, Flags::C),
+        (0xff, 0x00, true, 0xff, Flags::C),
+        (0x80, 0x01, true, 0x7f, Flags::C),
+        (0x03, 0x05, true, 0xfe, Flags::CLEAR),
+        (0x00, 0x01, true, 0xff, Flags::CLEAR),
+        (0x00, 0xff, true, 0x01, Flags::CLEAR),
+        (0x7f, 0x80, true, 0xff, Flags::CLEAR),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_alu_conformance() {
+        for (a, b, subtract, result, flags) in conformance_cases() {
+            assert_eq!(
+                alu(a, b, subtract),
+                (result, flags),
+                "a: {:#04x}, b: {:#04x}, subtract: {}",
+                a,
+                b,
+                subtract
+            );
+        }
+    }
+}
@@ -0,0 +1,815 @@
+//! An assembler for the `eater_8bit` instruction set.
+//!
+//! This understands the subset of customasm syntax used by the programs in this repository:
+//! labels, instructions with a 4-bit operand, and the `#include`, `#bits`, `#addr`, `#d8` and
+//! `#ruledef` directives. The rules in `#ruledef` blocks are skipped; the instruction set is
+//! built in.
+//!
+//! Programs that include the `eater_8bit_ram256` rules are assembled for the 256-byte RAM
+//! upgrade instead: instructions with an operand take two bytes, and the image is 256 bytes.
+
+use crate::cpu::Variant;
+use crate::isa::{self, Operand};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Guards against include cycles.
+const MAX_INCLUDE_DEPTH: usize = 16;
+
+/// An assembler diagnostic pointing at the offending source.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AsmError {
+    message: String,
+    file: String,
+    line: usize,
+    column: usize,
+    len: usize,
+    source_line: String,
+}
+
+impl AsmError {
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    pub fn file(&self) -> &str {
+        &self.file
+    }
+
+    /// One-based line number, or zero when the error is not tied to a line.
+    pub fn line(&self) -> usize {
+        self.line
+    }
+
+    /// One-based column number, or zero when the error is not tied to a line.
+    pub fn column(&self) -> usize {
+        self.column
+    }
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "error: {}", self.message)?;
+        if self.line == 0 {
+            return write!(f, " --> {}", self.file);
+        }
+
+        let gutter = self.line.to_string().len();
+        writeln!(
+            f,
+            "{:>w$}--> {}:{}:{}",
+            "",
+            self.file,
+            self.line,
+            self.column,
+            w = gutter
+        )?;
+        writeln!(f, "{:>w$} |", "", w = gutter)?;
+        writeln!(f, "{} | {}", self.line, self.source_line)?;
+        write!(
+            f,
+            "{:>w$} | {:>c$}{}",
+            "",
+            "",
+            "^".repeat(self.len.max(1)),
+            w = gutter,
+            c = self.column - 1
+        )
+    }
+}
+
+impl Error for AsmError {}
+
+/// An assembled memory image, and the build of the machine its `#ruledef` selected.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Program {
+    pub image: Vec<u8>,
+    pub variant: Variant,
+}
+
+/// Assemble source text into a memory image.
+///
+/// `name` is only used in diagnostics. `#include` paths are resolved relative to the current
+/// directory.
+pub fn assemble(name: &str, source: &str) -> Result<Program, AsmError> {
+    let mut asm = Assembler::default();
+    let index = asm.add_source(name.to_string(), None, source.to_string());
+    asm.parse(index, 0)?;
+    asm.emit()
+}
+
+/// Assemble a source file into a memory image.
+///
+/// `#include` paths are resolved relative to the directory of the including file.
+pub fn assemble_file(path: impl AsRef<Path>) -> Result<Program, AsmError> {
+    let path = path.as_ref();
+    let name = path.display().to_string();
+    let source = fs::read_to_string(path).map_err(|err| AsmError {
+        message: format!("cannot read source: {}", err),
+        file: name.clone(),
+        line: 0,
+        column: 0,
+        len: 0,
+        source_line: String::new(),
+    })?;
+
+    let mut asm = Assembler::default();
+    let index = asm.add_source(name, path.parent().map(Path::to_path_buf), source);
+    asm.parse(index, 0)?;
+    asm.emit()
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Span {
+    source: usize,
+    line: usize,
+    column: usize,
+    len: usize,
+}
+
+impl Span {
+    /// Extend this span to the end of `other`, which must be on the same line.
+    fn to(self, other: Span) -> Span {
+        Span {
+            len: other.column + other.len - self.column,
+            ..self
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Tok {
+    Ident(String),
+    Directive(String),
+    Number(i64),
+    Str(String),
+    Punct(char),
+    Newline,
+}
+
+#[derive(Clone, Debug)]
+struct Token {
+    tok: Tok,
+    span: Span,
+}
+
+#[derive(Debug)]
+enum ExprKind {
+    Number(i64),
+    Label(String),
+    Neg(Box<Expr>),
+    Add(Box<Expr>, Box<Expr>),
+    Sub(Box<Expr>, Box<Expr>),
+}
+
+#[derive(Debug)]
+struct Expr {
+    kind: ExprKind,
+    span: Span,
+}
+
+#[derive(Debug)]
+enum StmtKind {
+    Label(String),
+    Bits(Expr),
+    Addr(Expr),
+    D8(Vec<Expr>),
+    Inst(&'static isa::Instruction, Option<Expr>),
+}
+
+#[derive(Debug)]
+struct Stmt {
+    kind: StmtKind,
+    span: Span,
+}
+
+struct Source {
+    name: String,
+    dir: Option<PathBuf>,
+    text: String,
+}
+
+#[derive(Default)]
+struct Assembler {
+    sources: Vec<Source>,
+    stmts: Vec<Stmt>,
+    /// The build selected by the name of a `#ruledef` block, if any.
+    variant: Option<Variant>,
+}
+
+impl Assembler {
+    fn add_source(&mut self, name: String, dir: Option<PathBuf>, text: String) -> usize {
+        self.sources.push(Source { name, dir, text });
+        self.sources.len() - 1
+    }
+
+    fn error(&self, span: Span, message: impl Into<String>) -> AsmError {
+        let source = &self.sources[span.source];
+
+        AsmError {
+            message: message.into(),
+            file: source.name.clone(),
+            line: span.line,
+            column: span.column,
+            len: span.len,
+            source_line: source
+                .text
+                .lines()
+                .nth(span.line - 1)
+                .unwrap_or_default()
+                .to_string(),
+        }
+    }
+
+    fn tokenize(&self, index: usize) -> Result<Vec<Token>, AsmError> {
+        let mut tokens = Vec::new();
+
+        for (line_index, line) in self.sources[index].text.lines().enumerate() {
+            let chars: Vec<char> = line.chars().collect();
+            let mut i = 0;
+
+            while i < chars.len() {
+                let c = chars[i];
+                let start = i;
+                let span = |end: usize| Span {
+                    source: index,
+                    line: line_index + 1,
+                    column: start + 1,
+                    len: end - start,
+                };
+                let word_end = |from: usize| {
+                    (from..chars.len())
+                        .find(|&j| !is_ident_char(chars[j]))
+                        .unwrap_or(chars.len())
+                };
+
+                if c == ';' {
+                    break;
+                } else if c.is_whitespace() {
+                    i += 1;
+                    continue;
+                }
+
+                let tok = if c.is_ascii_digit() {
+                    i = word_end(i);
+                    let word: String = chars[start..i].iter().collect();
+                    let number = parse_number(&word)
+                        .ok_or_else(|| self.error(span(i), "invalid number literal"))?;
+                    Tok::Number(number)
+                } else if is_ident_start(c) {
+                    i = word_end(i);
+                    Tok::Ident(chars[start..i].iter().collect())
+                } else if c == '#' {
+                    i = word_end(i + 1);
+                    Tok::Directive(chars[start + 1..i].iter().collect())
+                } else if c == '"' {
+                    let end = (i + 1..chars.len())
+                        .find(|&j| chars[j] == '"')
+                        .ok_or_else(|| self.error(span(chars.len()), "unterminated string"))?;
+                    i = end + 1;
+                    Tok::Str(chars[start + 1..end].iter().collect())
+                } else {
+                    i += 1;
+                    Tok::Punct(c)
+                };
+
+                tokens.push(Token { tok, span: span(i) });
+            }
+
+            tokens.push(Token {
+                tok: Tok::Newline,
+                span: Span {
+                    source: index,
+                    line: line_index + 1,
+                    column: chars.len() + 1,
+                    len: 0,
+                },
+            });
+        }
+
+        Ok(tokens)
+    }
+
+    /// Parse a source into statements, expanding includes in place.
+    fn parse(&mut self, index: usize, depth: usize) -> Result<(), AsmError> {
+        let tokens = self.tokenize(index)?;
+        let mut parser = Parser { tokens, pos: 0 };
+
+        while let Some(token) = parser.next() {
+            let span = token.span;
+
+            match token.tok {
+                Tok::Newline => continue,
+                Tok::Ident(name) if parser.peek_punct(':') => {
+                    let colon = parser.next().unwrap();
+                    self.stmts.push(Stmt {
+                        kind: StmtKind::Label(name),
+                        span: span.to(colon.span),
+                    });
+                    // Labels may be followed by a statement on the same line
+                    continue;
+                }
+                Tok::Ident(mnemonic) => {
+                    let inst = isa::lookup(&mnemonic).ok_or_else(|| {
+                        self.error(span, format!("unknown instruction `{}`", mnemonic))
+                    })?;
+                    let operand = if parser.at_line_end() {
+                        None
+                    } else {
+                        Some(self.parse_expr(&mut parser)?)
+                    };
+                    let span = operand.as_ref().map_or(span, |expr| span.to(expr.span));
+
+                    self.stmts.push(Stmt {
+                        kind: StmtKind::Inst(inst, operand),
+                        span,
+                    });
+                }
+                Tok::Directive(directive) => match directive.as_str() {
+                    "include" => {
+                        let path = match parser.next() {
+                            Some(Token {
+                                tok: Tok::Str(path),
+                                ..
+                            }) => path,
+                            _ => return Err(self.error(span, "expected a path after `#include`")),
+                        };
+                        if depth >= MAX_INCLUDE_DEPTH {
+                            return Err(self.error(span, "includes are nested too deeply"));
+                        }
+
+                        let path = match &self.sources[index].dir {
+                            Some(dir) => dir.join(&path),
+                            None => PathBuf::from(&path),
+                        };
+                        let text = fs::read_to_string(&path).map_err(|err| {
+                            self.error(
+                                span,
+                                format!("cannot include `{}`: {}", path.display(), err),
+                            )
+                        })?;
+                        let dir = path.parent().map(Path::to_path_buf);
+                        let include = self.add_source(path.display().to_string(), dir, text);
+                        self.parse(include, depth + 1)?;
+                    }
+                    "bits" => {
+                        let expr = self.parse_expr(&mut parser)?;
+                        self.stmts.push(Stmt {
+                            span: span.to(expr.span),
+                            kind: StmtKind::Bits(expr),
+                        });
+                    }
+                    "addr" => {
+                        let expr = self.parse_expr(&mut parser)?;
+                        self.stmts.push(Stmt {
+                            span: span.to(expr.span),
+                            kind: StmtKind::Addr(expr),
+                        });
+                    }
+                    "d8" => {
+                        let mut values = vec![self.parse_expr(&mut parser)?];
+                        while parser.peek_punct(',') {
+                            parser.next();
+                            values.push(self.parse_expr(&mut parser)?);
+                        }
+                        self.stmts.push(Stmt {
+                            span: span.to(values.last().unwrap().span),
+                            kind: StmtKind::D8(values),
+                        });
+                    }
+                    "ruledef" => {
+                        // The instruction set is built in, so the name only picks the variant
+                        let name = parser.peek_ident();
+                        let variant = [Variant::Original, Variant::Ram256]
+                            .iter()
+                            .copied()
+                            .find(|&variant| name == Some(isa::ruledef_name(variant)));
+                        if let Some(variant) = variant {
+                            if self.variant.is_some_and(|v| v != variant) {
+                                let message = "`#ruledef` conflicts with an earlier `#ruledef`";
+                                return Err(self.error(span, message));
+                            }
+                            self.variant = Some(variant);
+                        }
+
+                        // Skip over the rules
+                        let mut nesting = 0;
+                        loop {
+                            match parser.next().map(|token| token.tok) {
+                                Some(Tok::Punct('{')) => nesting += 1,
+                                Some(Tok::Punct('}')) if nesting == 1 => break,
+                                Some(Tok::Punct('}')) => nesting -= 1,
+                                Some(_) => (),
+                                None => {
+                                    return Err(self.error(span, "unterminated `#ruledef` block"))
+                                }
+                            }
+                        }
+                    }
+                    _ => {
+                        return Err(self.error(span, format!("unknown directive `#{}`", directive)))
+                    }
+                },
+                _ => return Err(self.error(span, "expected a label, instruction or directive")),
+            }
+
+            match parser.next() {
+                None
+                | Some(Token {
+                    tok: Tok::Newline, ..
+                }) => (),
+                Some(token) => return Err(self.error(token.span, "expected end of line")),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// expr = term (('+' | '-') term)*
+    fn parse_expr(&self, parser: &mut Parser) -> Result<Expr, AsmError> {
+        let mut lhs = self.parse_term(parser)?;
+
+        loop {
+            let op = if parser.peek_punct('+') {
+                '+'
+            } else if parser.peek_punct('-') {
+                '-'
+            } else {
+                return Ok(lhs);
+            };
+            parser.next();
+
+            let rhs = self.parse_term(parser)?;
+            let span = lhs.span.to(rhs.span);
+            let kind = match op {
+                '+' => ExprKind::Add(Box::new(lhs), Box::new(rhs)),
+                _ => ExprKind::Sub(Box::new(lhs), Box::new(rhs)),
+            };
+            lhs = Expr { kind, span };
+        }
+    }
+
+    /// term = '-' term | '(' expr ')' | number | label
+    fn parse_term(&self, parser: &mut Parser) -> Result<Expr, AsmError> {
+        let token = match parser.next() {
+            Some(token) => token,
+            None => {
+                let span = parser.tokens.last().unwrap().span;
+                return Err(self.error(span, "expected an expression"));
+            }
+        };
+
+        match token.tok {
+            Tok::Number(number) => Ok(Expr {
+                kind: ExprKind::Number(number),
+                span: token.span,
+            }),
+            Tok::Ident(label) => Ok(Expr {
+                kind: ExprKind::Label(label),
+                span: token.span,
+            }),
+            Tok::Punct('-') => {
+                let inner = self.parse_term(parser)?;
+                Ok(Expr {
+                    span: token.span.to(inner.span),
+                    kind: ExprKind::Neg(Box::new(inner)),
+                })
+            }
+            Tok::Punct('(') => {
+                let inner = self.parse_expr(parser)?;
+                match parser.next() {
+                    Some(Token {
+                        tok: Tok::Punct(')'),
+                        span,
+                    }) => Ok(Expr {
+                        span: token.span.to(span),
+                        ..inner
+                    }),
+                    _ => Err(self.error(token.span.to(inner.span), "unclosed parenthesis")),
+                }
+            }
+            _ => Err(self.error(token.span, "expected an expression")),
+        }
+    }
+
+    fn eval(&self, expr: &Expr, labels: &HashMap<&str, i64>) -> Result<i64, AsmError> {
+        let overflow = || self.error(expr.span, "expression overflows");
+
+        match &expr.kind {
+            ExprKind::Number(number) => Ok(*number),
+            ExprKind::Label(label) => labels
+                .get(label.as_str())
+                .copied()
+                .ok_or_else(|| self.error(expr.span, format!("undefined label `{}`", label))),
+            ExprKind::Neg(inner) => self.eval(inner, labels)?.checked_neg().ok_or_else(overflow),
+            ExprKind::Add(lhs, rhs) => self
+                .eval(lhs, labels)?
+                .checked_add(self.eval(rhs, labels)?)
+                .ok_or_else(overflow),
+            ExprKind::Sub(lhs, rhs) => self
+                .eval(lhs, labels)?
+                .checked_sub(self.eval(rhs, labels)?)
+                .ok_or_else(overflow),
+        }
+    }
+
+    /// Lay out every statement, then encode them into the image.
+    fn emit(&self) -> Result<Program, AsmError> {
+        let variant = self.variant.unwrap_or_default();
+        let mem_size = variant.mem_size();
+        let mut labels = HashMap::new();
+        let mut addrs = Vec::with_capacity(self.stmts.len());
+        let mut addr = 0;
+
+        for stmt in &self.stmts {
+            addrs.push(addr);
+
+            match &stmt.kind {
+                StmtKind::Label(name) => {
+                    if labels.insert(name.as_str(), addr).is_some() {
+                        let message = format!("label `{}` is already defined", name);
+                        return Err(self.error(stmt.span, message));
+                    }
+                }
+                StmtKind::Bits(expr) => {
+                    if self.eval(expr, &labels)? != 8 {
+                        return Err(self.error(expr.span, "only `#bits 8` is supported"));
+                    }
+                }
+                StmtKind::Addr(expr) => {
+                    addr = self.eval(expr, &labels)?;
+                    if !(0..=mem_size as i64).contains(&addr) {
+                        let message = format!("address {} is outside of memory", addr);
+                        return Err(self.error(expr.span, message));
+                    }
+                }
+                StmtKind::D8(values) => addr += values.len() as i64,
+                StmtKind::Inst(inst, _) => {
+                    addr += variant.instruction_len(inst.opcode << 4) as i64;
+                }
+            }
+        }
+
+        let mut image = vec![0; mem_size];
+        let mut written = vec![false; mem_size];
+        let mut write = |addr: i64, value: u8, span: Span| {
+            if addr >= mem_size as i64 {
+                let message = format!("address {} is outside of memory", addr);
+                return Err(self.error(span, message));
+            }
+            if written[addr as usize] {
+                let message = format!("address {} is written more than once", addr);
+                return Err(self.error(span, message));
+            }
+
+            image[addr as usize] = value;
+            written[addr as usize] = true;
+
+            Ok(())
+        };
+
+        for (stmt, &addr) in self.stmts.iter().zip(addrs.iter()) {
+            match &stmt.kind {
+                StmtKind::D8(values) => {
+                    for (i, expr) in values.iter().enumerate() {
+                        let value = self.eval(expr, &labels)?;
+                        if !(-128..=255).contains(&value) {
+                            let message = format!("value {} does not fit in 8 bits", value);
+                            return Err(self.error(expr.span, message));
+                        }
+                        write(addr + i as i64, value as u8, expr.span)?;
+                    }
+                }
+                StmtKind::Inst(inst, operand) => {
+                    let operand = match (inst.operand, operand) {
+                        (Operand::None, None) => 0,
+                        (Operand::None, Some(expr)) => {
+                            let message = format!("`{}` does not take an operand", inst.mnemonic);
+                            return Err(self.error(expr.span, message));
+                        }
+                        (_, None) => {
+                            let message = format!("`{}` requires an operand", inst.mnemonic);
+                            return Err(self.error(stmt.span, message));
+                        }
+                        (_, Some(expr)) => {
+                            let value = self.eval(expr, &labels)?;
+                            let bits = match variant {
+                                Variant::Original => 4,
+                                Variant::Ram256 => 8,
+                            };
+                            if !(0..1 << bits).contains(&value) {
+                                let message =
+                                    format!("operand {} does not fit in {} bits", value, bits);
+                                return Err(self.error(expr.span, message));
+                            }
+                            value as u8
+                        }
+                    };
+
+                    if variant.instruction_len(inst.opcode << 4) == 2 {
+                        write(addr, inst.opcode << 4, stmt.span)?;
+                        write(addr + 1, operand, stmt.span)?;
+                    } else {
+                        write(addr, inst.opcode << 4 | operand, stmt.span)?;
+                    }
+                }
+                _ => (),
+            }
+        }
+
+        Ok(Program { image, variant })
+    }
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn peek_punct(&self, c: char) -> bool {
+        matches!(self.tokens.get(self.pos), Some(Token { tok: Tok::Punct(p), .. }) if *p == c)
+    }
+
+    fn peek_ident(&self) -> Option<&str> {
+        match self.tokens.get(self.pos) {
+            Some(Token {
+                tok: Tok::Ident(ident),
+                ..
+            }) => Some(ident),
+            _ => None,
+        }
+    }
+
+    fn at_line_end(&self) -> bool {
+        matches!(
+            self.tokens.get(self.pos),
+            None | Some(Token {
+                tok: Tok::Newline,
+                ..
+            })
+        )
+    }
+}
+
+fn is_ident_start(c: char) -> bool {
+    c.is_ascii_alphabetic() || c == '_' || c == '.'
+}
+
+fn is_ident_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == '.'
+}
+
+/// Parse a decimal, `0x` hexadecimal, `0o` octal or `0b` binary literal. Underscores may be
+/// used as separators.
+fn parse_number(word: &str) -> Option<i64> {
+    let word = word.replace('_', "");
+    let (digits, radix) = match word.get(..2) {
+        Some("0x") | Some("0X") => (&word[2..], 16),
+        Some("0o") | Some("0O") => (&word[2..], 8),
+        Some("0b") | Some("0B") => (&word[2..], 2),
+        _ => (&word[..], 10),
+    };
+
+    i64::from_str_radix(digits, radix).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_asm_example() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("src/example.asm");
+
+        assert_eq!(
+            assemble_file(path).unwrap().image,
+            include_bytes!("example.bin")
+        );
+    }
+
+    #[test]
+    fn test_asm_syntax() {
+        let source = "
+            #bits 8
+
+            start:  ldi 0b0101      ; binary
+                    STA data
+                    out
+            .loop:  add data + 1
+                    jc done
+                    jmp .loop
+            done:   hlt
+
+            #addr 0xd
+            data:
+            #d8 0o17, -1
+            #d8 1_0
+        ";
+
+        assert_eq!(
+            assemble("test.asm", source).unwrap().image,
+            [0x55, 0x4d, 0xe0, 0x2e, 0x76, 0x63, 0xf0, 0, 0, 0, 0, 0, 0, 0x0f, 0xff, 10]
+        );
+    }
+
+    #[test]
+    fn test_asm_errors() {
+        let error = |source| assemble("test.asm", source).unwrap_err();
+
+        let err = error("nop\n  foo 1\n");
+        assert_eq!(err.message(), "unknown instruction `foo`");
+        assert_eq!((err.line(), err.column()), (2, 3));
+
+        let err = error("lda 16");
+        assert_eq!(err.message(), "operand 16 does not fit in 4 bits");
+        assert_eq!((err.line(), err.column()), (1, 5));
+
+        let err = error("jmp nowhere");
+        assert_eq!(err.message(), "undefined label `nowhere`");
+        assert_eq!((err.line(), err.column()), (1, 5));
+
+        let err = error("a:\na:");
+        assert_eq!(err.message(), "label `a` is already defined");
+        assert_eq!(err.line(), 2);
+
+        let err = error("out\n#addr 0\nhlt");
+        assert_eq!(err.message(), "address 0 is written more than once");
+        assert_eq!(err.line(), 3);
+
+        let err = error("#addr 15\nnop\nnop");
+        assert_eq!(err.message(), "address 16 is outside of memory");
+        assert_eq!(err.line(), 3);
+
+        assert_eq!(error("out 1").message(), "`out` does not take an operand");
+        assert_eq!(error("lda").message(), "`lda` requires an operand");
+        assert_eq!(
+            error("#d8 256").message(),
+            "value 256 does not fit in 8 bits"
+        );
+        assert_eq!(error("#bits 16").message(), "only `#bits 8` is supported");
+        assert_eq!(error("#d8 0x").message(), "invalid number literal");
+        assert_eq!(error("jmp 1 2").message(), "expected end of line");
+        assert_eq!(
+            error("#ruledef x {").message(),
+            "unterminated `#ruledef` block"
+        );
+    }
+
+    #[test]
+    fn test_asm_ram256() {
+        let source = "
+            #ruledef eater_8bit_ram256 {
+                lda {mem: u8} => 0x10 @ mem
+            }
+
+            start:  ldi 200
+                    sta data
+                    out
+                    jz start
+                    hlt
+
+            #addr 0xff
+            data:
+        ";
+
+        let program = assemble("test.asm", source).unwrap();
+        assert_eq!(program.variant, Variant::Ram256);
+        let image = program.image;
+        assert_eq!(image.len(), 256);
+        assert_eq!(image[..8], [0x50, 200, 0x40, 0xff, 0xe0, 0x80, 0, 0xf0]);
+
+        let error = |source| assemble("test.asm", source).unwrap_err();
+        assert_eq!(
+            error("#ruledef eater_8bit_ram256 {}\nlda 256").message(),
+            "operand 256 does not fit in 8 bits"
+        );
+        assert_eq!(
+            error("#ruledef eater_8bit {}\n#ruledef eater_8bit_ram256 {}").message(),
+            "`#ruledef` conflicts with an earlier `#ruledef`"
+        );
+    }
+
+    #[test]
+    fn test_asm_error_display() {
+        let err = assemble("test.asm", "lda 14\nadd 99 ; oops\n").unwrap_err();
+
+        assert_eq!(
+            err.to_string(),
+            "error: operand 99 does not fit in 4 bits\n \
+             --> test.asm:2:5\n  \
+             |\n\
+             2 | add 99 ; oops\n  \
+             |     ^^"
+        );
+    }
+}
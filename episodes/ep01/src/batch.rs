@@ -0,0 +1,463 @@
+//! Runs many independent machines at once.
+//!
+//! Machines are stored as a structure of arrays, in chunks of `LANES` machines each. Every step
+//! executes one instruction on every lane of a chunk with the same straight-line code: memory is
+//! read and written by comparing each address against the PC or operand instead of indexing, and
+//! the effect of each opcode is selected rather than branched to. That leaves nothing in the inner
+//! loops that depends on the lane, so the compiler can vectorize them. With the `rayon` feature,
+//! chunks are also spread across threads.
+//!
+//! What each opcode does is looked up in `OPCODES`, a table of opcode sets built from
+//! `isa::INSTRUCTIONS` at compile time, so new instructions only need an entry there.
+
+use crate::cpu::{Flags, OpcodePolicy};
+use crate::error::EaterError;
+use crate::isa::{self, Condition, Op, Operand};
+#[cfg(feature = "rayon")]
+use rayon::prelude::*;
+
+/// The number of machines in a chunk.
+const LANES: usize = 32;
+
+/// Turn a condition into an all-ones or all-zeros byte mask.
+fn mask(cond: bool) -> u8 {
+    0u8.wrapping_sub(cond as u8)
+}
+
+/// The opcodes with each effect, one bit per opcode, so that each lane can test its opcode with a
+/// shift. Indexing a table of masks by opcode instead would be a gather, which keeps the lanes
+/// from vectorizing.
+struct Opcodes {
+    /// The value is the operand itself rather than the memory it addresses.
+    immediate: u16,
+    load: u16,
+    alu: u16,
+    sub: u16,
+    store: u16,
+    jump: u16,
+    jump_carry: u16,
+    jump_zero: u16,
+    jump_not_carry: u16,
+    jump_not_zero: u16,
+    out: u16,
+    halt: u16,
+    undefined: u16,
+}
+
+/// The opcodes with each effect, from `isa::INSTRUCTIONS`.
+const OPCODES: Opcodes = {
+    let mut opcodes = Opcodes {
+        immediate: 0,
+        load: 0,
+        alu: 0,
+        sub: 0,
+        store: 0,
+        jump: 0,
+        jump_carry: 0,
+        jump_zero: 0,
+        jump_not_carry: 0,
+        jump_not_zero: 0,
+        out: 0,
+        halt: 0,
+        undefined: 0xffff,
+    };
+    let mut i = 0;
+    while i < isa::INSTRUCTIONS.len() {
+        let inst = &isa::INSTRUCTIONS[i];
+        let bit = 1 << inst.opcode;
+        opcodes.undefined &= !bit;
+        if let Operand::Immediate = inst.operand {
+            opcodes.immediate |= bit;
+        }
+        match inst.op {
+            Op::Nop => {}
+            Op::Load => opcodes.load |= bit,
+            Op::Add => opcodes.alu |= bit,
+            Op::Sub => {
+                opcodes.alu |= bit;
+                opcodes.sub |= bit;
+            }
+            Op::Store => opcodes.store |= bit,
+            Op::Jump(Condition::Always) => opcodes.jump |= bit,
+            Op::Jump(Condition::Carry) => opcodes.jump_carry |= bit,
+            Op::Jump(Condition::Zero) => opcodes.jump_zero |= bit,
+            Op::Jump(Condition::NotCarry) => opcodes.jump_not_carry |= bit,
+            Op::Jump(Condition::NotZero) => opcodes.jump_not_zero |= bit,
+            Op::Out => opcodes.out |= bit,
+            Op::Hlt => opcodes.halt |= bit,
+        }
+        i += 1;
+    }
+    opcodes
+};
+
+/// Whether `opcode` is in the set, as a mask.
+fn has(opcodes: u16, opcode: u8) -> u8 {
+    ((opcodes >> opcode) as u8 & 1).wrapping_neg()
+}
+
+/// Compare two addresses, giving a mask like `mask(a == b)`.
+///
+/// LLVM sees through `mask` in the memory loops and turns them back into per-lane indexing, so
+/// this computes the mask with arithmetic instead. The sign of `(a ^ b) - 1` is only set for
+/// equal addresses, as long as both fit in four bits.
+fn addr_mask(a: u8, b: u8) -> u8 {
+    (((a ^ b).wrapping_sub(1) as i8) >> 7) as u8
+}
+
+/// Pick `a` where the mask is set, and `b` elsewhere.
+fn select(mask: u8, a: u8, b: u8) -> u8 {
+    (a & mask) | (b & !mask)
+}
+
+#[derive(Clone, Debug)]
+struct Chunk {
+    /// `mem[addr][lane]`
+    mem: [[u8; LANES]; 16],
+    pc: [u8; LANES],
+    a: [u8; LANES],
+    ir: [u8; LANES],
+    flags: [u8; LANES],
+    /// Masks for halted lanes, and lanes that halted on a trapped undefined opcode.
+    halt: [u8; LANES],
+    trap: [u8; LANES],
+    /// Every lane starts at reset and steps together, so lanes that are still running have
+    /// executed `steps` instructions. The count is kept separately for each lane once it halts.
+    steps: u64,
+    halted_at: [u64; LANES],
+    outputs: Vec<Vec<u8>>,
+}
+
+impl Chunk {
+    /// A chunk where every lane is halted.
+    fn new() -> Self {
+        Self {
+            mem: [[0; LANES]; 16],
+            pc: [0; LANES],
+            a: [0; LANES],
+            ir: [0; LANES],
+            flags: [0; LANES],
+            halt: [0xff; LANES],
+            trap: [0; LANES],
+            steps: 0,
+            halted_at: [0; LANES],
+            outputs: vec![Vec::new(); LANES],
+        }
+    }
+
+    fn running(&self) -> bool {
+        self.halt.contains(&0)
+    }
+
+    fn cycles(&self, lane: usize) -> u64 {
+        if self.halt[lane] != 0 {
+            self.halted_at[lane]
+        } else {
+            self.steps
+        }
+    }
+
+    /// Run for at most `limit` instructions, or until every lane has halted.
+    fn run(&mut self, policy: OpcodePolicy, limit: u64) {
+        for _ in 0..limit {
+            if !self.running() {
+                break;
+            }
+            self.step(policy);
+        }
+    }
+
+    /// Execute one instruction on every lane that hasn't halted.
+    fn step(&mut self, policy: OpcodePolicy) {
+        let mut inst = [0; LANES];
+        let mut operand = [0; LANES];
+
+        // Fetch
+        for (addr, row) in self.mem.iter().enumerate() {
+            for lane in 0..LANES {
+                inst[lane] |= row[lane] & addr_mask(self.pc[lane], addr as u8);
+            }
+        }
+        for (addr, row) in self.mem.iter().enumerate() {
+            for lane in 0..LANES {
+                operand[lane] |= row[lane] & addr_mask(inst[lane] & 0xf, addr as u8);
+            }
+        }
+
+        let halt_undefined = mask(policy != OpcodePolicy::Nop);
+        let trap_undefined = mask(policy == OpcodePolicy::Trap);
+        let mut store = [0; LANES];
+        let mut out = [0; LANES];
+        let mut halted = [0; LANES];
+        for lane in 0..LANES {
+            let run = !self.halt[lane];
+            let inst = inst[lane];
+            let opcode = inst >> 4;
+            let x = inst & 0xf;
+            let value = select(has(OPCODES.immediate, opcode), x, operand[lane]);
+            let alu = has(OPCODES.alu, opcode);
+            let sub = has(OPCODES.sub, opcode);
+            let undefined = has(OPCODES.undefined, opcode);
+            let a = self.a[lane];
+            let flags = self.flags[lane];
+
+            // Addition and subtraction share the adder, as in `alu`
+            let b = value ^ sub;
+            let partial = a.wrapping_add(b);
+            let result = partial.wrapping_add(sub & 1);
+            let carry = mask(partial < a) | mask(result < partial);
+            let alu_flags = (mask(result == 0) & Flags::Z.bits()) | (carry & Flags::C.bits());
+
+            let c = mask(flags & Flags::C.bits() != 0);
+            let z = mask(flags & Flags::Z.bits() != 0);
+            let jump = has(OPCODES.jump, opcode)
+                | (has(OPCODES.jump_carry, opcode) & c)
+                | (has(OPCODES.jump_zero, opcode) & z)
+                | (has(OPCODES.jump_not_carry, opcode) & !c)
+                | (has(OPCODES.jump_not_zero, opcode) & !z);
+
+            let mut next_a = select(has(OPCODES.load, opcode), value, a);
+            next_a = select(alu, result, next_a);
+            let next_pc = select(jump, x, (self.pc[lane] + 1) & 0xf);
+            let halt = has(OPCODES.halt, opcode) | (undefined & halt_undefined);
+
+            self.a[lane] = select(run, next_a, a);
+            self.flags[lane] = select(run & alu, alu_flags, flags);
+            self.pc[lane] = select(run, next_pc, self.pc[lane]);
+            self.ir[lane] = select(run, inst, self.ir[lane]);
+            self.trap[lane] |= run & undefined & trap_undefined;
+            self.halt[lane] |= run & halt;
+            halted[lane] = run & halt;
+            store[lane] = run & has(OPCODES.store, opcode);
+            out[lane] = run & has(OPCODES.out, opcode);
+        }
+        self.steps += 1;
+
+        // STA writes the accumulator, which is unchanged by the store itself
+        for (addr, row) in self.mem.iter_mut().enumerate() {
+            for lane in 0..LANES {
+                let hit = store[lane] & addr_mask(inst[lane] & 0xf, addr as u8);
+                row[lane] = select(hit, self.a[lane], row[lane]);
+            }
+        }
+
+        // Outputs and halts are rare, so check for any before going through the lanes
+        if out.iter().any(|&out| out != 0) {
+            for (lane, outputs) in self.outputs.iter_mut().enumerate() {
+                if out[lane] != 0 {
+                    outputs.push(self.a[lane]);
+                }
+            }
+        }
+        if halted.iter().any(|&halted| halted != 0) {
+            for (halted_at, &halted) in self.halted_at.iter_mut().zip(&halted) {
+                if halted != 0 {
+                    *halted_at = self.steps;
+                }
+            }
+        }
+    }
+}
+
+/// Runs a batch of programs side by side, each on its own machine with the behavior of `EaterVm`.
+///
+/// This is meant for searching and fuzzing, where millions of programs need to be run: stepping a
+/// batch is much cheaper than stepping each machine separately. Every machine starts from reset
+/// with its own program, and collects its own output values. Lanes are numbered in the order the
+/// programs were given.
+///
+/// A chunk of 32 machines keeps stepping until all of them have halted, so batches are fastest
+/// when their programs run for similar lengths of time.
+#[derive(Clone, Debug)]
+pub struct EaterBatch {
+    chunks: Vec<Chunk>,
+    len: usize,
+    policy: OpcodePolicy,
+}
+
+impl EaterBatch {
+    pub fn new(programs: &[[u8; 16]]) -> Self {
+        let mut chunks = vec![Chunk::new(); programs.len().div_ceil(LANES)];
+        for (i, program) in programs.iter().enumerate() {
+            let chunk = &mut chunks[i / LANES];
+            let lane = i % LANES;
+            for (addr, &value) in program.iter().enumerate() {
+                chunk.mem[addr][lane] = value;
+            }
+            chunk.halt[lane] = 0;
+        }
+
+        Self {
+            chunks,
+            len: programs.len(),
+            policy: OpcodePolicy::Nop,
+        }
+    }
+
+    /// The number of machines in the batch.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Select how every machine handles undefined opcodes.
+    pub fn set_opcode_policy(&mut self, policy: OpcodePolicy) {
+        self.policy = policy;
+    }
+
+    /// Execute one instruction on every machine that hasn't halted.
+    pub fn step(&mut self) {
+        self.run(1);
+    }
+
+    /// Run every machine for at most `limit` more instructions, or until it halts.
+    pub fn run(&mut self, limit: u64) {
+        let policy = self.policy;
+
+        #[cfg(feature = "rayon")]
+        self.chunks
+            .par_iter_mut()
+            .for_each(|chunk| chunk.run(policy, limit));
+
+        #[cfg(not(feature = "rayon"))]
+        self.chunks
+            .iter_mut()
+            .for_each(|chunk| chunk.run(policy, limit));
+    }
+
+    /// Find a lane's chunk and its index within the chunk.
+    fn lane(&self, lane: usize) -> (&Chunk, usize) {
+        assert!(lane < self.len, "Lane {} is out of range", lane);
+
+        (&self.chunks[lane / LANES], lane % LANES)
+    }
+
+    pub fn halted(&self, lane: usize) -> bool {
+        let (chunk, i) = self.lane(lane);
+        chunk.halt[i] != 0
+    }
+
+    /// The undefined opcode a machine trapped on, if the policy is `OpcodePolicy::Trap`.
+    pub fn fault(&self, lane: usize) -> Option<EaterError> {
+        let (chunk, i) = self.lane(lane);
+        if chunk.trap[i] == 0 {
+            return None;
+        }
+
+        Some(EaterError::UndefinedOpcode {
+            addr: chunk.pc[i].wrapping_sub(1) & 0xf,
+            inst: chunk.ir[i],
+        })
+    }
+
+    /// The number of instructions a machine has executed.
+    pub fn cycles(&self, lane: usize) -> u64 {
+        let (chunk, i) = self.lane(lane);
+        chunk.cycles(i)
+    }
+
+    /// Every value a machine has output.
+    pub fn outputs(&self, lane: usize) -> &[u8] {
+        let (chunk, i) = self.lane(lane);
+        &chunk.outputs[i]
+    }
+
+    pub fn pc(&self, lane: usize) -> u8 {
+        let (chunk, i) = self.lane(lane);
+        chunk.pc[i]
+    }
+
+    pub fn a(&self, lane: usize) -> u8 {
+        let (chunk, i) = self.lane(lane);
+        chunk.a[i]
+    }
+
+    pub fn ir(&self, lane: usize) -> u8 {
+        let (chunk, i) = self.lane(lane);
+        chunk.ir[i]
+    }
+
+    pub fn flags(&self, lane: usize) -> Flags {
+        let (chunk, i) = self.lane(lane);
+        Flags::from_bits_truncate(chunk.flags[i])
+    }
+
+    pub fn mem(&self, lane: usize) -> [u8; 16] {
+        let (chunk, i) = self.lane(lane);
+        let mut mem = [0; 16];
+        for (addr, row) in chunk.mem.iter().enumerate() {
+            mem[addr] = row[i];
+        }
+
+        mem
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::Cpu;
+    use crate::fixtures::{random_programs, COUNTER, EXAMPLE, SELF_MODIFYING};
+    use crate::interp::EaterVm;
+
+    /// Run every program on the batch and on its own `EaterVm`, and compare the results.
+    fn check(programs: &[[u8; 16]], policy: OpcodePolicy, limit: u64) {
+        let mut batch = EaterBatch::new(programs);
+        batch.set_opcode_policy(policy);
+        batch.run(limit);
+
+        for (lane, program) in programs.iter().enumerate() {
+            let mut vm = EaterVm::with_output(Vec::new());
+            vm.load(program).unwrap();
+            vm.set_opcode_policy(policy);
+            let mut fault = None;
+            while vm.cycles() < limit {
+                match vm.step_instruction() {
+                    Ok(false) => (),
+                    Ok(true) => break,
+                    Err(err) => {
+                        fault = Some(err);
+                        break;
+                    }
+                }
+            }
+
+            assert_eq!(batch.halted(lane), vm.halted(), "lane {}", lane);
+            assert_eq!(batch.fault(lane), fault, "lane {}", lane);
+            assert_eq!(batch.cycles(lane), vm.cycles(), "lane {}", lane);
+            assert_eq!(batch.outputs(lane), vm.output().as_slice(), "lane {}", lane);
+            assert_eq!(batch.pc(lane), vm.pc(), "lane {}", lane);
+            assert_eq!(batch.a(lane), vm.a(), "lane {}", lane);
+            assert_eq!(batch.ir(lane), vm.ir(), "lane {}", lane);
+            assert_eq!(batch.flags(lane), vm.flags(), "lane {}", lane);
+            assert_eq!(&batch.mem(lane)[..], vm.mem(), "lane {}", lane);
+        }
+    }
+
+    #[test]
+    fn test_batch_matches_interpreter() {
+        let mut programs = vec![*EXAMPLE, COUNTER, SELF_MODIFYING];
+        programs.extend(random_programs(200));
+
+        for &policy in &[OpcodePolicy::Nop, OpcodePolicy::Trap, OpcodePolicy::Halt] {
+            check(&programs, policy, 500);
+        }
+        assert!(EaterBatch::new(&programs[..0]).is_empty());
+    }
+
+    #[test]
+    fn test_batch_step() {
+        let mut batch = EaterBatch::new(&[*EXAMPLE; 3]);
+        batch.step();
+        batch.step();
+        batch.step();
+
+        for lane in 0..batch.len() {
+            assert_eq!(batch.cycles(lane), 3);
+            assert_eq!(batch.outputs(lane), [3]);
+        }
+    }
+}
@@ -0,0 +1,494 @@
+//! Lockstep co-simulation of two backends on the same program.
+
+use crate::cpu::{Cpu, Flags, OpcodePolicy, Phase};
+use crate::diff::{compare, Divergence, Mismatch};
+use crate::error::EaterError;
+use crate::interp::EaterVm;
+use crate::output::{Output, Stdout};
+use crate::sim::EaterSim;
+use std::sync::mpsc::{self, Receiver, Sender};
+
+/// The output device attached to both backends of a co-simulation.
+pub type Probe = Sender<(u8, u64)>;
+
+/// Runs a reference backend and a candidate backend in lockstep, one instruction at a time, and
+/// stops as soon as they disagree.
+///
+/// After every instruction the registers, flags, memory, halt status, faults and output values
+/// of both backends are compared. The first difference is kept as a `Divergence`, and every step
+/// from then on fails with `EaterError::Diverged`. Loading a program or resetting starts over.
+///
+/// The wrapper behaves like the reference backend: its registers and cycle count are the ones
+/// reported, and its output values are forwarded to the output device. Every step executes a
+/// whole instruction, so the simulator retires its instruction over all of its T-states while
+/// the interpreter retires it at once.
+#[derive(Debug)]
+pub struct CoSim<R, C, O = Stdout> {
+    reference: R,
+    candidate: C,
+    reference_out: Receiver<(u8, u64)>,
+    candidate_out: Receiver<(u8, u64)>,
+    instructions: u64,
+    divergence: Option<Divergence>,
+    output: O,
+}
+
+impl CoSim<EaterVm<Probe>, EaterSim<Probe>> {
+    /// Check the simulator against the interpreter.
+    pub fn new() -> Self {
+        Self::with_output(Stdout)
+    }
+}
+
+impl Default for CoSim<EaterVm<Probe>, EaterSim<Probe>> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<O: Output> CoSim<EaterVm<Probe>, EaterSim<Probe>, O> {
+    pub fn with_output(output: O) -> Self {
+        Self::with_backends(EaterVm::with_output, EaterSim::with_output, output)
+    }
+}
+
+impl<R: Cpu, C: Cpu, O: Output> CoSim<R, C, O> {
+    /// Check any two backends against each other. Each constructor is given the probe the
+    /// backend must use as its output device.
+    pub fn with_backends<F, G>(reference: F, candidate: G, output: O) -> Self
+    where
+        F: FnOnce(Probe) -> R,
+        G: FnOnce(Probe) -> C,
+    {
+        let (reference_tx, reference_out) = mpsc::channel();
+        let (candidate_tx, candidate_out) = mpsc::channel();
+
+        Self {
+            reference: reference(reference_tx),
+            candidate: candidate(candidate_tx),
+            reference_out,
+            candidate_out,
+            instructions: 0,
+            divergence: None,
+            output,
+        }
+    }
+
+    pub fn reference(&self) -> &R {
+        &self.reference
+    }
+
+    /// Access the reference backend directly. Changes that aren't mirrored on the candidate show
+    /// up as a divergence after the next instruction.
+    pub fn reference_mut(&mut self) -> &mut R {
+        &mut self.reference
+    }
+
+    pub fn candidate(&self) -> &C {
+        &self.candidate
+    }
+
+    pub fn candidate_mut(&mut self) -> &mut C {
+        &mut self.candidate
+    }
+
+    /// The first difference between the backends, if they have diverged.
+    pub fn divergence(&self) -> Option<&Divergence> {
+        self.divergence.as_ref()
+    }
+
+    pub fn output(&self) -> &O {
+        &self.output
+    }
+
+    pub fn output_mut(&mut self) -> &mut O {
+        &mut self.output
+    }
+
+    pub fn into_output(self) -> O {
+        self.output
+    }
+
+    /// Forget any divergence, and any output values left over from the previous run.
+    fn restart(&mut self) {
+        self.reference_out.try_iter().for_each(drop);
+        self.candidate_out.try_iter().for_each(drop);
+        self.instructions = 0;
+        self.divergence = None;
+    }
+
+    fn diverged(&self) -> EaterError {
+        EaterError::Diverged {
+            instruction: self.instructions,
+        }
+    }
+}
+
+impl<R: Cpu, C: Cpu, O: Output> Cpu for CoSim<R, C, O> {
+    fn load(&mut self, mem: &[u8]) -> Result<(), EaterError> {
+        self.reference.load(mem)?;
+        self.candidate.load(mem)?;
+        self.restart();
+
+        Ok(())
+    }
+
+    fn reset(&mut self) {
+        self.reference.reset();
+        self.candidate.reset();
+        self.restart();
+    }
+
+    fn set_opcode_policy(&mut self, policy: OpcodePolicy) {
+        self.reference.set_opcode_policy(policy);
+        self.candidate.set_opcode_policy(policy);
+    }
+
+    fn poke(&mut self, addr: u8, value: u8) {
+        self.reference.poke(addr, value);
+        self.candidate.poke(addr, value);
+    }
+
+    fn step_instruction(&mut self) -> Result<bool, EaterError> {
+        if self.divergence.is_some() {
+            return Err(self.diverged());
+        }
+        if self.reference.halted() && self.candidate.halted() {
+            return self.reference.step_instruction();
+        }
+
+        let reference = self.reference.step_instruction();
+        let candidate = self.candidate.step_instruction();
+        let reference_out: Vec<_> = self.reference_out.try_iter().collect();
+        let candidate_out: Vec<_> = self.candidate_out.try_iter().collect();
+
+        let mut mismatches = Vec::new();
+        if reference.as_ref().err() != candidate.as_ref().err() {
+            mismatches.push(Mismatch::Fault {
+                left: reference.clone().err(),
+                right: candidate.err(),
+            });
+        }
+        let values = |out: &[(u8, u64)]| out.iter().map(|&(value, _)| value).collect::<Vec<_>>();
+        if values(&reference_out) != values(&candidate_out) {
+            mismatches.push(Mismatch::Output {
+                left: values(&reference_out),
+                right: values(&candidate_out),
+            });
+        }
+        mismatches.extend(compare(&self.reference, &self.candidate));
+
+        if !mismatches.is_empty() {
+            self.divergence = Some(Divergence {
+                instruction: self.instructions,
+                mismatches,
+            });
+            return Err(self.diverged());
+        }
+
+        for (value, time) in reference_out {
+            self.output.out(value, time);
+        }
+        self.instructions += 1;
+
+        reference
+    }
+
+    /// Executes a whole instruction; the backends can only be compared between instructions.
+    fn step_clock(&mut self) -> Result<bool, EaterError> {
+        self.step_instruction()
+    }
+
+    fn pc(&self) -> u8 {
+        self.reference.pc()
+    }
+
+    fn a(&self) -> u8 {
+        self.reference.a()
+    }
+
+    fn ir(&self) -> u8 {
+        self.reference.ir()
+    }
+
+    fn phase(&self) -> Phase {
+        Phase::Instruction
+    }
+
+    fn flags(&self) -> Flags {
+        self.reference.flags()
+    }
+
+    fn mem(&self) -> &[u8] {
+        self.reference.mem()
+    }
+
+    fn halted(&self) -> bool {
+        self.reference.halted()
+    }
+
+    fn cycles(&self) -> u64 {
+        self.reference.cycles()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::Variant;
+    use crate::fixtures::{random_programs, COUNTER, EXAMPLE, SELF_MODIFYING};
+    use crate::image::format_hex;
+    use crate::isa::{self, Condition, Instruction, Isa, Op, Operand};
+    use crate::microcode::{Control, Microcode};
+    use crate::{EaterMemo, EaterThreaded};
+
+    /// Run a program on a backend alongside `EaterVm`, failing on any divergence.
+    fn lockstep<C, F>(candidate: &F, program: &[u8; 16], policy: OpcodePolicy) -> Vec<u8>
+    where
+        C: Cpu,
+        F: Fn(Probe) -> C,
+    {
+        let mut cosim = CoSim::with_backends(EaterVm::with_output, candidate, Vec::new());
+        cosim.load(program).unwrap();
+        cosim.set_opcode_policy(policy);
+        for _ in 0..1000 {
+            match cosim.step_instruction() {
+                Ok(false) => (),
+                Err(EaterError::Diverged { .. }) => {
+                    panic!("{}\n{}", cosim.divergence().unwrap(), format_hex(program))
+                }
+                Ok(true) | Err(_) => break,
+            }
+        }
+
+        cosim.into_output()
+    }
+
+    /// Check a backend against `EaterVm` on the fixtures and random programs, under every opcode
+    /// policy.
+    fn conformance<C, F>(candidate: F)
+    where
+        C: Cpu,
+        F: Fn(Probe) -> C,
+    {
+        let nop = OpcodePolicy::Nop;
+        assert_eq!(lockstep(&candidate, EXAMPLE, nop).len(), 86);
+        assert_eq!(lockstep(&candidate, &COUNTER, nop)[..4], [1, 2, 3, 4]);
+        assert_eq!(
+            lockstep(&candidate, &SELF_MODIFYING, nop),
+            [0xe0, 0xe0, 0xf0]
+        );
+
+        for program in random_programs(200) {
+            for &policy in &[OpcodePolicy::Nop, OpcodePolicy::Trap, OpcodePolicy::Halt] {
+                lockstep(&candidate, &program, policy);
+            }
+        }
+
+        let mut cpu = candidate(mpsc::channel().0);
+        cpu.set_opcode_policy(OpcodePolicy::Trap);
+        cpu.poke(1, 0x9a);
+        assert_eq!(
+            cpu.run(),
+            Err(EaterError::UndefinedOpcode {
+                addr: 1,
+                inst: 0x9a
+            })
+        );
+        assert_eq!(cpu.pc(), 2);
+    }
+
+    #[test]
+    fn test_cosim_backends() {
+        // The microcode mode ignores the opcode policy; `diff` checks it instead
+        conformance(EaterSim::with_output);
+        conformance(EaterThreaded::with_output);
+        conformance(EaterMemo::with_output);
+    }
+
+    /// Run a program on a backend alongside `EaterVm`, both decoding with `isa`.
+    fn lockstep_isa<C: Cpu>(
+        isa: &'static Isa,
+        variant: Variant,
+        program: &[u8],
+        candidate: impl FnOnce(Probe) -> C,
+    ) -> Vec<u8> {
+        let reference = |probe| {
+            let mut vm = EaterVm::with_output(probe);
+            vm.set_variant(variant);
+            vm.set_isa(isa);
+            vm
+        };
+        let mut cosim = CoSim::with_backends(reference, candidate, Vec::new());
+        let mut image = vec![0; variant.mem_size()];
+        image[..program.len()].copy_from_slice(program);
+        cosim.load(&image).unwrap();
+        cosim
+            .run()
+            .unwrap_or_else(|error| match cosim.divergence() {
+                Some(divergence) => panic!("{}", divergence),
+                None => panic!("{}", error),
+            });
+
+        cosim.into_output()
+    }
+
+    #[test]
+    fn test_cosim_isa() {
+        // The immediate ALU operations and inverted jumps that `eater_8bit` leaves out, with OUT
+        // moved to another opcode
+        let alu = |mnemonic, opcode, op, su| Instruction {
+            mnemonic,
+            opcode,
+            operand: Operand::Immediate,
+            op,
+            microcode: [
+                Control::IO | Control::BI,
+                Control::EO | Control::AI | Control::FI | su,
+                Control::empty(),
+            ],
+            microcode_ram256: [
+                Control::CO | Control::MI,
+                Control::RO | Control::BI | Control::CE,
+                Control::EO | Control::AI | Control::FI | su,
+                Control::empty(),
+            ],
+        };
+        let jump = |mnemonic, opcode, condition| Instruction {
+            mnemonic,
+            opcode,
+            operand: Operand::Address,
+            op: Op::Jump(condition),
+            microcode: [Control::IO | Control::J, Control::empty(), Control::empty()],
+            microcode_ram256: [
+                Control::CO | Control::MI,
+                Control::RO | Control::J,
+                Control::empty(),
+                Control::empty(),
+            ],
+        };
+        let mut instructions = isa::INSTRUCTIONS.to_vec();
+        for inst in &mut instructions {
+            if inst.op == Op::Out {
+                inst.opcode = 0xd;
+            }
+        }
+        instructions.extend_from_slice(&[
+            jump("jnc", 0x9, Condition::NotCarry),
+            alu("adi", 0xa, Op::Add, Control::empty()),
+            alu("sui", 0xb, Op::Sub, Control::SU),
+            jump("jnz", 0xc, Condition::NotZero),
+        ]);
+        let isa: &'static Isa = Box::leak(Box::new(Isa::new(Box::leak(
+            instructions.into_boxed_slice(),
+        ))));
+
+        // Counts down from 3 with SUI and JNZ, then up in fives with ADI and JNC until it carries
+        let program = [0x53, 0xb1, 0xd0, 0xc1, 0xa5, 0xd0, 0x94, 0xf0];
+        let program_ram256 = [
+            0x50, 3, 0xb0, 1, 0xd0, 0xc0, 2, 0xa0, 5, 0xd0, 0x90, 7, 0xf0,
+        ];
+        let expected: Vec<u8> = [2, 1, 0]
+            .iter()
+            .copied()
+            .chain((5..=255).step_by(5))
+            .chain(Some(4))
+            .collect();
+
+        let sim = |variant, microcode| {
+            move |probe| {
+                let mut sim = EaterSim::with_output(probe);
+                sim.set_variant(variant);
+                sim.set_isa(isa);
+                sim.set_microcode(microcode);
+                sim
+            }
+        };
+        let threaded = |probe| {
+            let mut threaded = EaterThreaded::with_output(probe);
+            threaded.set_isa(isa);
+            threaded
+        };
+        for (variant, program) in [
+            (Variant::Original, &program[..]),
+            (Variant::Ram256, &program_ram256[..]),
+        ] {
+            let microcode = Microcode::for_isa(variant, isa);
+            assert_eq!(
+                lockstep_isa(isa, variant, program, sim(variant, None)),
+                expected
+            );
+            assert_eq!(
+                lockstep_isa(isa, variant, program, sim(variant, Some(microcode))),
+                expected
+            );
+        }
+        assert_eq!(
+            lockstep_isa(isa, Variant::Original, &program, threaded),
+            expected
+        );
+
+        // Loop detection collects the outputs by decoding too
+        let mut vm = EaterVm::with_output(Vec::new());
+        vm.set_isa(isa);
+        for (addr, &inst) in program.iter().enumerate() {
+            vm.poke(addr as u8, inst);
+        }
+        assert_eq!(vm.run_checked(1000).unwrap().outputs, expected);
+    }
+
+    #[test]
+    fn test_cosim_agree() {
+        let mut cosim = CoSim::with_output(Vec::new());
+        cosim.load(include_bytes!("example.bin")).unwrap();
+        cosim.run().unwrap();
+
+        assert_eq!(cosim.output().len(), 86);
+        assert_eq!(cosim.candidate().cycles(), 5 * cosim.cycles() - 2);
+        assert_eq!(cosim.divergence(), None);
+    }
+
+    #[test]
+    fn test_cosim_diverged() {
+        // A candidate whose ADD forgets to latch the flags
+        let mut microcode = Microcode::default();
+        for flags in [Flags::CLEAR, Flags::C, Flags::Z, Flags::C | Flags::Z] {
+            let control = microcode.control(flags, 0x2, 4);
+            microcode.set_control(flags, 0x2, 4, control - Control::FI);
+        }
+        let candidate = |probe| {
+            let mut sim = EaterSim::with_output(probe);
+            sim.set_microcode(Some(microcode));
+            sim
+        };
+
+        let mut cosim = CoSim::with_backends(EaterVm::with_output, candidate, Vec::new());
+        cosim
+            .load(&[0x1e, 0x2f, 0xe0, 0xf0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0])
+            .unwrap();
+
+        assert_eq!(cosim.step_instruction(), Ok(false));
+        assert_eq!(
+            cosim.step_instruction(),
+            Err(EaterError::Diverged { instruction: 1 })
+        );
+        let divergence = cosim.divergence().unwrap();
+        assert_eq!(
+            divergence.mismatches,
+            [Mismatch::Flags {
+                left: Flags::Z,
+                right: Flags::CLEAR
+            }]
+        );
+        assert_eq!(
+            divergence.to_string(),
+            "Backends diverged at instruction 1:\n  flags: -Z != --"
+        );
+
+        // The divergence sticks until the program is reloaded
+        assert!(cosim.run().is_err());
+        assert!(cosim.output().is_empty());
+        cosim.reset();
+        assert_eq!(cosim.divergence(), None);
+    }
+}
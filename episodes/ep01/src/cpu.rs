@@ -0,0 +1,246 @@
+use crate::error::EaterError;
+use crate::isa;
+use bitflags::bitflags;
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use std::fmt;
+
+bitflags! {
+    #[derive(Default, Serialize, Deserialize)]
+    #[serde(try_from = "u8", into = "u8")]
+    pub struct Flags: u8 {
+        const CLEAR = 0;
+        const Z = 0b01;
+        const C = 0b10;
+    }
+}
+
+impl From<Flags> for u8 {
+    fn from(flags: Flags) -> Self {
+        flags.bits()
+    }
+}
+
+impl TryFrom<u8> for Flags {
+    type Error = &'static str;
+
+    fn try_from(bits: u8) -> Result<Self, Self::Error> {
+        Flags::from_bits(bits).ok_or("Undefined flag bits")
+    }
+}
+
+/// Shows the carry and zero flags as `CZ`, with `-` for a clear flag.
+impl fmt::Display for Flags {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let c = if self.contains(Flags::C) { 'C' } else { '-' };
+        let z = if self.contains(Flags::Z) { 'Z' } else { '-' };
+
+        write!(f, "{}{}", c, z)
+    }
+}
+
+/// Where a backend is in the instruction cycle, which is what the next `Cpu::step_clock` executes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Phase {
+    /// A whole instruction, for backends without T-states.
+    Instruction,
+    /// The T-state with this step counter value. Step 0 latches the PC, step 1 fetches the
+    /// instruction, and the remaining steps execute it.
+    Step(u8),
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Phase::Instruction => write!(f, "instruction"),
+            Phase::Step(step) => write!(f, "t{}", step),
+        }
+    }
+}
+
+/// What to do when the CPU fetches an opcode that has no instruction assigned (0x9 - 0xd).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum OpcodePolicy {
+    /// Execute the fetch cycle only, which is what the microcode ROM on the real hardware does.
+    #[default]
+    Nop,
+    /// Halt the CPU and report `EaterError::UndefinedOpcode`.
+    Trap,
+    /// Halt the CPU as if it executed HLT.
+    Halt,
+}
+
+/// Which build of the machine a backend models.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Variant {
+    /// 16 bytes of RAM and a 4-bit PC. Every instruction is one byte, with the operand in its low
+    /// nibble.
+    #[default]
+    Original,
+    /// The popular RAM upgrade: 256 bytes of RAM, with an 8-bit PC and memory address register.
+    /// Instructions that take an operand are followed by an operand byte, which the CPU fetches
+    /// in extra T-states. The low nibble of the opcode byte is ignored.
+    Ram256,
+}
+
+impl Variant {
+    pub fn mem_size(self) -> usize {
+        match self {
+            Variant::Original => 16,
+            Variant::Ram256 => 256,
+        }
+    }
+
+    /// The number of T-states per instruction before the step counter resets.
+    pub fn steps(self) -> u8 {
+        match self {
+            Variant::Original => 5,
+            Variant::Ram256 => 6,
+        }
+    }
+
+    /// The bits of the PC and memory address register.
+    pub(crate) fn addr_mask(self) -> u8 {
+        match self {
+            Variant::Original => 0xf,
+            Variant::Ram256 => 0xff,
+        }
+    }
+
+    /// The number of bytes taken by the instruction that starts with this byte.
+    pub fn instruction_len(self, inst: u8) -> usize {
+        isa::ISA.instruction_len(self, inst)
+    }
+}
+
+/// Common interface for all Eater CPU backends.
+///
+/// Tooling (the CLI, benchmarks, test harnesses) should be written against this trait so it can
+/// drive either the interpreter or the cycle simulator.
+pub trait Cpu {
+    /// Copy a program image into memory. The image must be exactly the size of memory.
+    fn load(&mut self, mem: &[u8]) -> Result<(), EaterError>;
+
+    /// Copy a program image into memory, filling the remainder of memory with zeros.
+    fn load_padded(&mut self, mem: &[u8]) -> Result<(), EaterError> {
+        let size = self.mem().len();
+        if mem.len() > size {
+            return Err(EaterError::ImageTooLarge {
+                len: mem.len(),
+                size,
+            });
+        }
+
+        let mut image = vec![0; size];
+        image[..mem.len()].copy_from_slice(mem);
+        self.load(&image)
+    }
+
+    /// Clear all registers and flags, like pressing the reset button. Memory is retained.
+    fn reset(&mut self);
+
+    fn set_opcode_policy(&mut self, policy: OpcodePolicy);
+
+    /// Write a byte to memory, like the programming switches on the RAM module. The address wraps
+    /// at the end of memory.
+    fn poke(&mut self, addr: u8, value: u8);
+
+    /// Execute one full instruction. Returns `true` when the CPU is halted.
+    ///
+    /// Fails when an undefined opcode was trapped; the CPU stays halted until it is reset.
+    fn step_instruction(&mut self) -> Result<bool, EaterError>;
+
+    /// Advance by the smallest unit of time the backend models. Returns `true` when the CPU is
+    /// halted.
+    ///
+    /// For the simulator this is one T-state; for the interpreter it is one instruction.
+    fn step_clock(&mut self) -> Result<bool, EaterError>;
+
+    /// Run until the CPU halts.
+    fn run(&mut self) -> Result<(), EaterError> {
+        while !self.step_instruction()? {}
+
+        Ok(())
+    }
+
+    fn pc(&self) -> u8;
+    fn a(&self) -> u8;
+
+    /// The instruction register, holding the instruction most recently fetched.
+    fn ir(&self) -> u8;
+
+    fn phase(&self) -> Phase;
+    fn flags(&self) -> Flags;
+    fn mem(&self) -> &[u8];
+    fn halted(&self) -> bool;
+
+    /// Number of clock ticks executed since reset. This is the timestamp passed to the output
+    /// device.
+    fn cycles(&self) -> u64;
+}
+
+impl<C: Cpu + ?Sized> Cpu for &mut C {
+    fn load(&mut self, mem: &[u8]) -> Result<(), EaterError> {
+        (**self).load(mem)
+    }
+
+    fn load_padded(&mut self, mem: &[u8]) -> Result<(), EaterError> {
+        (**self).load_padded(mem)
+    }
+
+    fn reset(&mut self) {
+        (**self).reset();
+    }
+
+    fn set_opcode_policy(&mut self, policy: OpcodePolicy) {
+        (**self).set_opcode_policy(policy);
+    }
+
+    fn poke(&mut self, addr: u8, value: u8) {
+        (**self).poke(addr, value);
+    }
+
+    fn step_instruction(&mut self) -> Result<bool, EaterError> {
+        (**self).step_instruction()
+    }
+
+    fn step_clock(&mut self) -> Result<bool, EaterError> {
+        (**self).step_clock()
+    }
+
+    fn run(&mut self) -> Result<(), EaterError> {
+        (**self).run()
+    }
+
+    fn pc(&self) -> u8 {
+        (**self).pc()
+    }
+
+    fn a(&self) -> u8 {
+        (**self).a()
+    }
+
+    fn ir(&self) -> u8 {
+        (**self).ir()
+    }
+
+    fn phase(&self) -> Phase {
+        (**self).phase()
+    }
+
+    fn flags(&self) -> Flags {
+        (**self).flags()
+    }
+
+    fn mem(&self) -> &[u8] {
+        (**self).mem()
+    }
+
+    fn halted(&self) -> bool {
+        (**self).halted()
+    }
+
+    fn cycles(&self) -> u64 {
+        (**self).cycles()
+    }
+}
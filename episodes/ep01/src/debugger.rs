@@ -0,0 +1,446 @@
+//! An interactive step debugger core that drives any `Cpu` backend.
+
+use crate::cpu::{Cpu, Phase};
+use crate::error::EaterError;
+use crate::history::Rewind;
+use std::collections::BTreeSet;
+use std::convert::TryFrom;
+use std::fmt;
+use std::str::FromStr;
+
+/// Help text for the commands accepted by `Command::from_str`.
+pub const HELP: &str = "\
+s, step [N]          Execute N instructions (default 1)
+t, tick [N]          Execute N clock ticks; T-states on the simulator (default 1)
+c, continue [N]      Run until a breakpoint, watchpoint or halt, or for N instructions
+bk, back [N]         Undo N instructions (default 1)
+rc, rcontinue [N]    Run backwards until a breakpoint or watchpoint, or for N instructions
+b, break <ADDR>      Stop before executing the instruction at ADDR
+d, delete <ADDR>     Remove a breakpoint
+w, watch <a|ADDR>    Stop when the A register or a memory byte changes
+u, unwatch <a|ADDR>  Remove a watchpoint
+i, info              List breakpoints and watchpoints
+r, regs              Show the registers
+m, mem               Show memory
+p, poke <ADDR> <N>   Write a byte to memory
+reset                Press the reset button; memory is retained
+reload               Reload the program and reset
+h, help              Show this help
+q, quit              Exit the debugger
+An empty line repeats the previous command.";
+
+/// A value a watchpoint observes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Watch {
+    /// The A register.
+    A,
+    /// A memory byte.
+    Mem(u8),
+}
+
+impl fmt::Display for Watch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Watch::A => write!(f, "a"),
+            Watch::Mem(addr) => write!(f, "mem[{:#x}]", addr),
+        }
+    }
+}
+
+/// How far a single step advances the CPU.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Unit {
+    Instruction,
+    /// The smallest unit of time the backend models; see `Cpu::step_clock`.
+    Clock,
+}
+
+/// Why the debugger stopped running the CPU.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Event {
+    /// The requested number of steps were executed.
+    Done,
+    /// The CPU is halted.
+    Halted,
+    /// The program counter reached a breakpoint after an instruction.
+    Breakpoint(u8),
+    /// A watched value changed.
+    Watch { watch: Watch, old: u8, new: u8 },
+    /// Running backwards reached the oldest recorded state.
+    Start,
+}
+
+/// A command typed at the debugger prompt.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Command {
+    Step(u64),
+    Tick(u64),
+    /// Run without a limit, or for at most the given number of instructions.
+    Continue(Option<u64>),
+    Back(u64),
+    /// Run backwards without a limit, or for at most the given number of instructions.
+    ReverseContinue(Option<u64>),
+    Break(u8),
+    Delete(u8),
+    Watch(Watch),
+    Unwatch(Watch),
+    Info,
+    Regs,
+    Mem,
+    Poke(u8, u8),
+    Reset,
+    Reload,
+    Help,
+    Quit,
+}
+
+/// Parse a number in decimal, or hex and binary with `0x` and `0b` prefixes.
+fn parse_number<T: TryFrom<u64>>(arg: Option<&str>) -> Result<T, String> {
+    let arg = arg.ok_or("Missing argument")?;
+    let value = match arg.get(..2) {
+        Some("0x") | Some("0X") => u64::from_str_radix(&arg[2..], 16),
+        Some("0b") | Some("0B") => u64::from_str_radix(&arg[2..], 2),
+        _ => arg.parse(),
+    };
+
+    value
+        .ok()
+        .and_then(|value| T::try_from(value).ok())
+        .ok_or_else(|| format!("Invalid number `{}`", arg))
+}
+
+fn parse_watch(arg: Option<&str>) -> Result<Watch, String> {
+    match arg {
+        Some(a) if a.eq_ignore_ascii_case("a") => Ok(Watch::A),
+        _ => parse_number(arg).map(Watch::Mem),
+    }
+}
+
+impl FromStr for Command {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut args = s.split_whitespace();
+        let name = args.next().ok_or("Empty command")?;
+        let mut arg = || args.next();
+
+        let command = match name {
+            "s" | "step" => Command::Step(arg().map_or(Ok(1), |n| parse_number(Some(n)))?),
+            "t" | "tick" => Command::Tick(arg().map_or(Ok(1), |n| parse_number(Some(n)))?),
+            "c" | "continue" => {
+                Command::Continue(arg().map(|n| parse_number(Some(n))).transpose()?)
+            }
+            "bk" | "back" => Command::Back(arg().map_or(Ok(1), |n| parse_number(Some(n)))?),
+            "rc" | "rcontinue" => {
+                Command::ReverseContinue(arg().map(|n| parse_number(Some(n))).transpose()?)
+            }
+            "b" | "break" => Command::Break(parse_number(arg())?),
+            "d" | "delete" => Command::Delete(parse_number(arg())?),
+            "w" | "watch" => Command::Watch(parse_watch(arg())?),
+            "u" | "unwatch" => Command::Unwatch(parse_watch(arg())?),
+            "i" | "info" => Command::Info,
+            "r" | "regs" => Command::Regs,
+            "m" | "mem" => Command::Mem,
+            "p" | "poke" => Command::Poke(parse_number(arg())?, parse_number(arg())?),
+            "reset" => Command::Reset,
+            "reload" => Command::Reload,
+            "h" | "help" => Command::Help,
+            "q" | "quit" => Command::Quit,
+            _ => return Err(format!("Unknown command `{}`", name)),
+        };
+
+        match args.next() {
+            Some(extra) => Err(format!("Unexpected argument `{}`", extra)),
+            None => Ok(command),
+        }
+    }
+}
+
+/// Runs a CPU with breakpoints and watchpoints.
+#[derive(Debug)]
+pub struct Debugger<C> {
+    cpu: C,
+    breakpoints: BTreeSet<u8>,
+    watches: BTreeSet<Watch>,
+}
+
+impl<C: Cpu> Debugger<C> {
+    pub fn new(cpu: C) -> Self {
+        Self {
+            cpu,
+            breakpoints: BTreeSet::new(),
+            watches: BTreeSet::new(),
+        }
+    }
+
+    pub fn cpu(&self) -> &C {
+        &self.cpu
+    }
+
+    pub fn cpu_mut(&mut self) -> &mut C {
+        &mut self.cpu
+    }
+
+    pub fn into_inner(self) -> C {
+        self.cpu
+    }
+
+    /// Add a breakpoint. Returns `false` if it was already set.
+    pub fn add_breakpoint(&mut self, addr: u8) -> bool {
+        self.breakpoints.insert(addr)
+    }
+
+    /// Remove a breakpoint. Returns `false` if it was not set.
+    pub fn remove_breakpoint(&mut self, addr: u8) -> bool {
+        self.breakpoints.remove(&addr)
+    }
+
+    pub fn breakpoints(&self) -> impl Iterator<Item = u8> + '_ {
+        self.breakpoints.iter().copied()
+    }
+
+    /// Add a watchpoint. Returns `false` if it was already set.
+    pub fn add_watch(&mut self, watch: Watch) -> bool {
+        self.watches.insert(watch)
+    }
+
+    /// Remove a watchpoint. Returns `false` if it was not set.
+    pub fn remove_watch(&mut self, watch: Watch) -> bool {
+        self.watches.remove(&watch)
+    }
+
+    pub fn watches(&self) -> impl Iterator<Item = Watch> + '_ {
+        self.watches.iter().copied()
+    }
+
+    /// Read the value a watchpoint observes. Addresses outside of memory read as zero.
+    pub fn read(&self, watch: Watch) -> u8 {
+        match watch {
+            Watch::A => self.cpu.a(),
+            Watch::Mem(addr) => self.cpu.mem().get(addr as usize).copied().unwrap_or(0),
+        }
+    }
+
+    /// Execute up to `count` steps, or without limit when `count` is `None`.
+    ///
+    /// Stops early when the CPU halts or a watched value changes. Breakpoints are checked after
+    /// each instruction, so the instruction at the current program counter always executes; they
+    /// are ignored when stepping clock ticks, where the program counter moves mid-instruction.
+    pub fn run(&mut self, unit: Unit, count: Option<u64>) -> Result<Event, EaterError> {
+        let mut steps = 0;
+
+        loop {
+            if self.cpu.halted() {
+                return Ok(Event::Halted);
+            }
+            if count.is_some_and(|count| steps >= count) {
+                return Ok(Event::Done);
+            }
+
+            let before: Vec<_> = self
+                .watches
+                .iter()
+                .map(|&watch| (watch, self.read(watch)))
+                .collect();
+
+            match unit {
+                Unit::Instruction => self.cpu.step_instruction()?,
+                Unit::Clock => self.cpu.step_clock()?,
+            };
+            steps += 1;
+
+            for (watch, old) in before {
+                let new = self.read(watch);
+                if new != old {
+                    return Ok(Event::Watch { watch, old, new });
+                }
+            }
+
+            let pc = self.cpu.pc();
+            if unit == Unit::Instruction && self.breakpoints.contains(&pc) && !self.cpu.halted() {
+                return Ok(Event::Breakpoint(pc));
+            }
+        }
+    }
+}
+
+impl<C: Rewind> Debugger<C> {
+    /// Undo up to `count` instructions, or without limit when `count` is `None`.
+    ///
+    /// The mirror image of `run`: stops early when a watched value changes, or when the program
+    /// counter reaches a breakpoint, which leaves the instruction at the breakpoint about to
+    /// execute again.
+    pub fn run_back(&mut self, count: Option<u64>) -> Event {
+        let mut steps = 0;
+
+        loop {
+            if count.is_some_and(|count| steps >= count) {
+                return Event::Done;
+            }
+
+            let before: Vec<_> = self
+                .watches
+                .iter()
+                .map(|&watch| (watch, self.read(watch)))
+                .collect();
+
+            if !self
+                .cpu
+                .run_back_to(|cpu| matches!(cpu.phase(), Phase::Instruction | Phase::Step(0)))
+            {
+                return Event::Start;
+            }
+            steps += 1;
+
+            for (watch, old) in before {
+                let new = self.read(watch);
+                if new != old {
+                    return Event::Watch { watch, old, new };
+                }
+            }
+
+            let pc = self.cpu.pc();
+            if self.breakpoints.contains(&pc) {
+                return Event::Breakpoint(pc);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::EXAMPLE;
+    use crate::{EaterSim, EaterVm};
+
+    fn debugger<C: Cpu>(cpu: C) -> Debugger<C> {
+        let mut debugger = Debugger::new(cpu);
+        debugger.cpu_mut().load(EXAMPLE).unwrap();
+        debugger
+    }
+
+    #[test]
+    fn test_debugger_breakpoint() {
+        let mut vm = debugger(EaterVm::with_output(Vec::new()));
+        assert!(vm.add_breakpoint(2));
+        assert!(!vm.add_breakpoint(2));
+
+        // Every iteration of the loop stops at OUT
+        for a in [3, 6, 9] {
+            assert_eq!(vm.run(Unit::Instruction, None), Ok(Event::Breakpoint(2)));
+            assert_eq!(vm.cpu().a(), a);
+        }
+
+        assert!(vm.remove_breakpoint(2));
+        assert_eq!(vm.run(Unit::Instruction, None), Ok(Event::Halted));
+        assert_eq!(vm.cpu().output().last(), Some(&2));
+    }
+
+    #[test]
+    fn test_debugger_watch() {
+        let mut sim = debugger(EaterSim::with_output(Vec::new()));
+        sim.add_watch(Watch::A);
+        sim.add_watch(Watch::Mem(14));
+
+        let event = sim.run(Unit::Instruction, None);
+        assert_eq!(
+            event,
+            Ok(Event::Watch {
+                watch: Watch::A,
+                old: 0,
+                new: 3
+            })
+        );
+        assert_eq!(sim.cpu().pc(), 2);
+
+        sim.remove_watch(Watch::A);
+        sim.cpu_mut().poke(1, 0x4e); // STA 14
+        assert_eq!(
+            sim.run(Unit::Instruction, Some(10)),
+            Ok(Event::Watch {
+                watch: Watch::Mem(14),
+                old: 0,
+                new: 3
+            })
+        );
+    }
+
+    #[test]
+    fn test_debugger_step_clock() {
+        let mut sim = debugger(EaterSim::with_output(Vec::new()));
+        sim.add_breakpoint(1);
+
+        // Clock ticks ignore breakpoints
+        assert_eq!(sim.run(Unit::Clock, Some(7)), Ok(Event::Done));
+        assert_eq!(sim.cpu().cycles(), 7);
+        assert_eq!(sim.cpu().step_counter(), 2);
+
+        assert_eq!(sim.run(Unit::Instruction, Some(1)), Ok(Event::Done));
+        assert_eq!(sim.cpu().step_counter(), 0);
+    }
+
+    #[test]
+    fn test_debugger_run_back() {
+        let mut sim = debugger(EaterSim::with_output(Vec::new()));
+        sim.cpu_mut().set_history_limit(1000);
+        assert_eq!(sim.run(Unit::Clock, Some(37)), Ok(Event::Done));
+
+        // A partial instruction is undone as a whole
+        assert_eq!(sim.run_back(Some(1)), Event::Done);
+        assert_eq!((sim.cpu().cycles(), sim.cpu().step_counter()), (35, 0));
+
+        sim.add_breakpoint(2);
+        assert_eq!(sim.run_back(None), Event::Breakpoint(2));
+        assert_eq!((sim.cpu().a(), sim.cpu().cycles()), (6, 30));
+
+        sim.add_watch(Watch::A);
+        assert_eq!(
+            sim.run_back(None),
+            Event::Watch {
+                watch: Watch::A,
+                old: 6,
+                new: 3
+            }
+        );
+        assert_eq!(sim.run_back(Some(100)), Event::Breakpoint(2));
+        sim.remove_watch(Watch::A);
+        assert_eq!(sim.run_back(None), Event::Start);
+        assert_eq!(sim.cpu().cycles(), 0);
+    }
+
+    #[test]
+    fn test_debugger_trap() {
+        let mut vm = debugger(EaterVm::with_output(Vec::new()));
+        vm.cpu_mut().set_opcode_policy(crate::OpcodePolicy::Trap);
+        vm.cpu_mut().poke(0, 0x90);
+
+        assert_eq!(
+            vm.run(Unit::Instruction, None),
+            Err(EaterError::UndefinedOpcode {
+                addr: 0,
+                inst: 0x90
+            })
+        );
+    }
+
+    #[test]
+    fn test_debugger_command() {
+        assert_eq!("s".parse(), Ok(Command::Step(1)));
+        assert_eq!("step 0x10".parse(), Ok(Command::Step(16)));
+        assert_eq!("tick 3".parse(), Ok(Command::Tick(3)));
+        assert_eq!("  c ".parse(), Ok(Command::Continue(None)));
+        assert_eq!("continue 100".parse(), Ok(Command::Continue(Some(100))));
+        assert_eq!("bk 4".parse(), Ok(Command::Back(4)));
+        assert_eq!("rc".parse(), Ok(Command::ReverseContinue(None)));
+        assert_eq!("b 0b101".parse(), Ok(Command::Break(5)));
+        assert_eq!("w A".parse(), Ok(Command::Watch(Watch::A)));
+        assert_eq!("unwatch 14".parse(), Ok(Command::Unwatch(Watch::Mem(14))));
+        assert_eq!("poke 15 0xff".parse(), Ok(Command::Poke(15, 0xff)));
+
+        assert!("".parse::<Command>().is_err());
+        assert!("break".parse::<Command>().is_err());
+        assert!("poke 1 256".parse::<Command>().is_err());
+        assert!("regs 1".parse::<Command>().is_err());
+        assert!("frobnicate".parse::<Command>().is_err());
+    }
+}
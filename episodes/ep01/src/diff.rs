@@ -0,0 +1,331 @@
+//! Differential testing of the interpreter against the cycle simulator.
+//!
+//! Both backends run the same program from the same initial state in lockstep with `CoSim`, one
+//! instruction at a time, and must agree on the architectural state after every instruction.
+//! Cycle counts are not compared, since the interpreter counts instructions and the simulator
+//! counts T-states.
+
+use crate::cosim::CoSim;
+use crate::cpu::{Cpu, Flags, OpcodePolicy};
+use crate::error::EaterError;
+use crate::image::format_hex;
+use crate::interp::EaterVm;
+use crate::microcode::Microcode;
+use crate::sim::EaterSim;
+use std::fmt;
+
+/// An architectural difference between two backends at an instruction boundary.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Mismatch {
+    Register {
+        name: &'static str,
+        left: u8,
+        right: u8,
+    },
+    Flags {
+        left: Flags,
+        right: Flags,
+    },
+    Mem {
+        addr: u8,
+        left: u8,
+        right: u8,
+    },
+    Halted {
+        left: bool,
+        right: bool,
+    },
+    /// The values output by the last instruction.
+    Output {
+        left: Vec<u8>,
+        right: Vec<u8>,
+    },
+    Fault {
+        left: Option<EaterError>,
+        right: Option<EaterError>,
+    },
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Mismatch::Register { name, left, right } => {
+                write!(f, "{}: {:#04x} != {:#04x}", name, left, right)
+            }
+            Mismatch::Flags { left, right } => write!(f, "flags: {} != {}", left, right),
+            Mismatch::Mem { addr, left, right } => {
+                write!(f, "mem[{:#x}]: {:#04x} != {:#04x}", addr, left, right)
+            }
+            Mismatch::Halted { left, right } => write!(f, "halted: {} != {}", left, right),
+            Mismatch::Output { left, right } => write!(f, "output: {:?} != {:?}", left, right),
+            Mismatch::Fault { left, right } => {
+                let show = |fault: &Option<EaterError>| match fault {
+                    Some(err) => err.to_string(),
+                    None => "none".to_string(),
+                };
+                write!(f, "fault: {} != {}", show(left), show(right))
+            }
+        }
+    }
+}
+
+/// Compare the architectural state of two CPUs: registers, flags, memory and the halt status.
+pub fn compare<L, R>(left: &L, right: &R) -> Vec<Mismatch>
+where
+    L: Cpu + ?Sized,
+    R: Cpu + ?Sized,
+{
+    let mut mismatches = Vec::new();
+
+    let registers = [
+        ("pc", left.pc(), right.pc()),
+        ("a", left.a(), right.a()),
+        ("ir", left.ir(), right.ir()),
+    ];
+    for &(name, left, right) in registers.iter() {
+        if left != right {
+            mismatches.push(Mismatch::Register { name, left, right });
+        }
+    }
+    if left.flags() != right.flags() {
+        mismatches.push(Mismatch::Flags {
+            left: left.flags(),
+            right: right.flags(),
+        });
+    }
+    for (addr, (&l, &r)) in left.mem().iter().zip(right.mem()).enumerate() {
+        if l != r {
+            mismatches.push(Mismatch::Mem {
+                addr: addr as u8,
+                left: l,
+                right: r,
+            });
+        }
+    }
+    if left.halted() != right.halted() {
+        mismatches.push(Mismatch::Halted {
+            left: left.halted(),
+            right: right.halted(),
+        });
+    }
+
+    mismatches
+}
+
+/// A program and the state both backends start from.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Case {
+    pub mem: [u8; 16],
+    pub pc: u8,
+    pub a: u8,
+    pub flags: Flags,
+    pub policy: OpcodePolicy,
+    /// Run the simulator from the default microcode instead of its built-in behavior. The
+    /// microcode mode always executes undefined opcodes as NOP, so the policy must be `Nop`.
+    pub microcode: bool,
+}
+
+impl Case {
+    /// The number of bytes `from_bytes` consumes.
+    pub const LEN: usize = 20;
+
+    /// Build a case from raw fuzzer input: 16 bytes of memory, then the PC, A, flags, and a byte
+    /// that selects the opcode policy or the microcode mode. Returns `None` for short input.
+    pub fn from_bytes(data: &[u8]) -> Option<Self> {
+        let data = data.get(..Self::LEN)?;
+        let mut mem = [0; 16];
+        mem.copy_from_slice(&data[..16]);
+
+        let (policy, microcode) = match data[19] % 4 {
+            0 => (OpcodePolicy::Nop, false),
+            1 => (OpcodePolicy::Trap, false),
+            2 => (OpcodePolicy::Halt, false),
+            _ => (OpcodePolicy::Nop, true),
+        };
+
+        Some(Self {
+            mem,
+            pc: data[16] & 0xf,
+            a: data[17],
+            flags: Flags::from_bits_truncate(data[18]),
+            policy,
+            microcode,
+        })
+    }
+
+    /// Run both backends in lockstep for up to `limit` instructions, returning the first
+    /// divergence.
+    pub fn check(&self, limit: u64) -> Result<(), Divergence> {
+        let microcode = self.microcode;
+        let mut cosim = CoSim::with_backends(
+            EaterVm::with_output,
+            |probe| {
+                let mut sim = EaterSim::with_output(probe);
+                if microcode {
+                    sim.set_microcode(Some(Microcode::default()));
+                }
+                sim
+            },
+            Vec::new(),
+        );
+
+        cosim.load(&self.mem).expect("Cases fill memory");
+        cosim.set_opcode_policy(self.policy);
+        cosim
+            .reference_mut()
+            .set_registers(self.pc, self.a, self.flags);
+        cosim
+            .candidate_mut()
+            .set_registers(self.pc, self.a, self.flags);
+
+        for _ in 0..limit {
+            match cosim.step_instruction() {
+                Ok(false) => (),
+                Err(EaterError::Diverged { .. }) => break,
+                Ok(true) | Err(_) => return Ok(()),
+            }
+        }
+
+        match cosim.divergence() {
+            Some(divergence) => Err(divergence.clone()),
+            None => Ok(()),
+        }
+    }
+
+    /// Shrink a diverging case by dropping the microcode mode, then zeroing memory bytes and
+    /// registers one at a time, as long as the backends still diverge. The result is usually much
+    /// easier to read than fuzzer output.
+    pub fn minimize(&self, limit: u64) -> Self {
+        let mut case = self.clone();
+        if case.check(limit).is_ok() {
+            return case;
+        }
+
+        loop {
+            let mut candidates = Vec::new();
+            let mut zeroed = |zero: &dyn Fn(&mut Case)| {
+                let mut candidate = case.clone();
+                zero(&mut candidate);
+                if candidate != case {
+                    candidates.push(candidate);
+                }
+            };
+
+            // The behavioral simulator is the simpler reproducer
+            zeroed(&|c| c.microcode = false);
+            for addr in 0..case.mem.len() {
+                zeroed(&|c| c.mem[addr] = 0);
+            }
+            zeroed(&|c| c.pc = 0);
+            zeroed(&|c| c.a = 0);
+            zeroed(&|c| c.flags = Flags::CLEAR);
+            zeroed(&|c| c.policy = OpcodePolicy::Nop);
+
+            match candidates
+                .into_iter()
+                .find(|candidate| candidate.check(limit).is_err())
+            {
+                Some(smaller) => case = smaller,
+                None => return case,
+            }
+        }
+    }
+}
+
+/// Shows the case as a reproducer: hex memory that `eater` can load, then the initial state.
+impl fmt::Display for Case {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", format_hex(&self.mem))?;
+        write!(
+            f,
+            "; pc={:#x} a={:#04x} flags={} policy={:?}",
+            self.pc, self.a, self.flags, self.policy
+        )?;
+        if self.microcode {
+            write!(f, " microcode")?;
+        }
+
+        Ok(())
+    }
+}
+
+/// The first instruction after which two backends disagreed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Divergence {
+    /// Index of the instruction, counting from zero.
+    pub instruction: u64,
+    /// Differences, with the reference backend on the left.
+    pub mismatches: Vec<Mismatch>,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Backends diverged at instruction {}:", self.instruction)?;
+        for mismatch in &self.mismatches {
+            write!(f, "\n  {}", mismatch)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl std::error::Error for Divergence {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    proptest! {
+        #[test]
+        fn test_diff_lockstep(data in proptest::array::uniform20(any::<u8>())) {
+            let case = Case::from_bytes(&data).unwrap();
+            if case.check(256).is_err() {
+                let case = case.minimize(256);
+                panic!("{}\n{}", case.check(256).unwrap_err(), case);
+            }
+        }
+    }
+
+    #[test]
+    fn test_diff_compare() {
+        let mut left = EaterVm::with_output(Vec::new());
+        let mut right = EaterSim::with_output(Vec::new());
+        left.poke(0, 0x57); // LDI 7
+        right.poke(0, 0x4f); // STA 15
+        left.step_instruction().unwrap();
+        right.step_instruction().unwrap();
+
+        assert_eq!(
+            compare(&left, &right),
+            [
+                Mismatch::Register {
+                    name: "a",
+                    left: 7,
+                    right: 0
+                },
+                Mismatch::Register {
+                    name: "ir",
+                    left: 0x57,
+                    right: 0x4f
+                },
+                Mismatch::Mem {
+                    addr: 0,
+                    left: 0x57,
+                    right: 0x4f
+                },
+            ]
+        );
+        assert_eq!(compare(&left, &left), []);
+    }
+
+    #[test]
+    fn test_diff_example() {
+        let mut data = include_bytes!("example.bin").to_vec();
+        data.extend([0, 0, 0, 3]);
+
+        let case = Case::from_bytes(&data).unwrap();
+        assert!(case.microcode);
+        assert_eq!(case.check(1000), Ok(()));
+    }
+}
@@ -0,0 +1,188 @@
+//! A disassembler producing source that re-assembles to the identical image.
+
+use crate::isa::{self, Condition, Instruction, Op, Operand};
+use std::collections::BTreeSet;
+use std::fmt::Write;
+
+/// Width of the code column, which is followed by an address comment.
+const CODE_WIDTH: usize = 23;
+
+/// Most data bytes emitted on one `#d8` line.
+const DATA_PER_LINE: usize = 8;
+
+/// Decode a byte into an instruction, if it has an exact assembly representation.
+///
+/// Undefined opcodes, and instructions without an operand that have low bits set, return
+/// `None`; they can only be represented with `#d8`.
+pub fn decode(byte: u8) -> Option<&'static Instruction> {
+    let inst = isa::decode(byte >> 4)?;
+
+    match inst.operand {
+        Operand::None if byte & 0xf != 0 => None,
+        _ => Some(inst),
+    }
+}
+
+/// Format a single byte as an instruction with a numeric operand, or as `#d8` data.
+pub fn format_byte(byte: u8) -> String {
+    match decode(byte) {
+        Some(inst) if inst.operand == Operand::None => inst.mnemonic.to_string(),
+        Some(inst) => format!("{} {}", inst.mnemonic, byte & 0xf),
+        None => format!("#d8 {:#04x}", byte),
+    }
+}
+
+/// Find every address that can execute when starting from address 0.
+///
+/// Undefined opcodes are followed as NOPs, like the hardware does. Conditional jumps are assumed
+/// to go both ways.
+fn reachable(image: &[u8]) -> Vec<bool> {
+    let len = image.len();
+    let mut code = vec![false; len];
+    let mut pending = vec![0];
+
+    while let Some(addr) = pending.pop() {
+        if addr >= len || code[addr] {
+            continue;
+        }
+        code[addr] = true;
+
+        let byte = image[addr];
+        let next = (addr + 1) % len;
+        let target = (byte & 0xf) as usize;
+
+        match isa::decode(byte >> 4).map(|inst| inst.op) {
+            Some(Op::Hlt) => (),
+            Some(Op::Jump(Condition::Always)) => pending.push(target),
+            Some(Op::Jump(_)) => pending.extend([target, next]),
+            _ => pending.push(next),
+        }
+    }
+
+    code
+}
+
+/// Disassemble a memory image.
+///
+/// Bytes reachable from address 0 are decoded as instructions and everything else is emitted as
+/// `#d8` data. Jump targets get synthesized labels. Assembling the output produces the same
+/// image.
+pub fn disassemble(image: &[u8]) -> String {
+    let code = reachable(image);
+
+    // Only code that decodes cleanly can jump, so every label lands on a reachable address
+    let labels: BTreeSet<usize> = image
+        .iter()
+        .zip(code.iter())
+        .filter(|&(_, &code)| code)
+        .filter_map(|(&byte, _)| match decode(byte).map(|inst| inst.op) {
+            Some(Op::Jump(_)) => Some((byte & 0xf) as usize),
+            _ => None,
+        })
+        .filter(|&target| target < image.len())
+        .collect();
+    let label = |addr: usize| format!("l{}", addr);
+
+    let mut out = String::new();
+    let mut addr = 0;
+    while addr < image.len() {
+        if labels.contains(&addr) {
+            writeln!(out, "{}:", label(addr)).unwrap();
+        }
+
+        let start = addr;
+        let byte = image[addr];
+        let line = match decode(byte).filter(|_| code[addr]) {
+            Some(inst) => {
+                let line = match inst.op {
+                    Op::Jump(_) if labels.contains(&((byte & 0xf) as usize)) => {
+                        format!("{} {}", inst.mnemonic, label((byte & 0xf) as usize))
+                    }
+                    _ => format_byte(byte),
+                };
+                addr += 1;
+                line
+            }
+            None => {
+                // Group data bytes until the next instruction or label
+                addr += 1;
+                while addr < image.len()
+                    && addr - start < DATA_PER_LINE
+                    && !labels.contains(&addr)
+                    && !(code[addr] && decode(image[addr]).is_some())
+                {
+                    addr += 1;
+                }
+
+                let bytes: Vec<_> = image[start..addr]
+                    .iter()
+                    .map(|byte| format!("{:#04x}", byte))
+                    .collect();
+                format!("#d8 {}", bytes.join(", "))
+            }
+        };
+
+        writeln!(out, "{:<w$} ; {:#03x}", line, start, w = CODE_WIDTH).unwrap();
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+    use crate::fixtures::random_programs;
+
+    #[test]
+    fn test_disasm_example() {
+        let image = include_bytes!("example.bin");
+
+        assert_eq!(
+            disassemble(image),
+            "\
+lda 14                  ; 0x0
+l1:
+add 15                  ; 0x1
+out                     ; 0x2
+jc l5                   ; 0x3
+jmp l1                  ; 0x4
+l5:
+hlt                     ; 0x5
+#d8 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00 ; 0x6
+#d8 0x00, 0x03          ; 0xe
+"
+        );
+    }
+
+    #[test]
+    fn test_disasm_format_byte() {
+        assert_eq!(format_byte(0x00), "nop");
+        assert_eq!(format_byte(0x1e), "lda 14");
+        assert_eq!(format_byte(0x5f), "ldi 15");
+        assert_eq!(format_byte(0xe0), "out");
+        assert_eq!(format_byte(0xe1), "#d8 0xe1");
+        assert_eq!(format_byte(0x9a), "#d8 0x9a");
+    }
+
+    #[test]
+    fn test_disasm_undefined_opcodes_are_followed() {
+        // Undefined opcode, then JMP back to it
+        let mut image = [0; 16];
+        image[0] = 0xa5;
+        image[1] = 0x60;
+
+        let text = disassemble(&image);
+        assert!(text.starts_with("l0:\n#d8 0xa5"), "{}", text);
+        assert!(text.contains("\njmp l0 "), "{}", text);
+        assert_eq!(assemble("disasm", &text).unwrap().image, image);
+    }
+
+    #[test]
+    fn test_disasm_round_trip() {
+        for image in random_programs(1000) {
+            let text = disassemble(&image);
+            assert_eq!(assemble("disasm", &text).unwrap().image, image, "{}", text);
+        }
+    }
+}
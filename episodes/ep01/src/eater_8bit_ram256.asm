@@ -0,0 +1,17 @@
+; Eater 8-bit CPU with the 256-byte RAM upgrade
+
+#bits 8
+
+#ruledef eater_8bit_ram256 {
+    nop => 0x00
+    lda {mem: u8} => 0x10 @ mem
+    add {mem: u8} => 0x20 @ mem
+    sub {mem: u8} => 0x30 @ mem
+    sta {mem: u8} => 0x40 @ mem
+    ldi {mem: u8} => 0x50 @ mem
+    jmp {mem: u8} => 0x60 @ mem
+    jc {mem: u8} => 0x70 @ mem
+    jz {mem: u8} => 0x80 @ mem
+    out => 0xe0
+    hlt => 0xf0
+}
@@ -0,0 +1,262 @@
+//! ROM images for the control logic EEPROMs.
+//!
+//! The control logic is two 28C16 EEPROMs, each driving eight control lines. Both are programmed
+//! with the same image and share their address lines, except for A7, which is tied low on the
+//! left EEPROM and high on the right one:
+//!
+//! | Address | Signal                           |
+//! |---------|----------------------------------|
+//! | A0 - A2 | Step counter                     |
+//! | A3 - A6 | Opcode (the high nibble of IR)   |
+//! | A7      | Byte select: 0 = left, 1 = right |
+//! | A8      | Carry flag                       |
+//! | A9      | Zero flag                        |
+//! | A10     | Tied low                         |
+//!
+//! The left EEPROM outputs the high byte of the control word (HLT - AO) and the right one the low
+//! byte (EO - FI).
+//!
+//! `microcode` reads dumps of the two EEPROMs back into a table, so `EaterSim` can run a physical
+//! machine's ROM contents and be compared against the reference microcode.
+
+use crate::cpu::Flags;
+use crate::error::EaterError;
+use crate::image::{self, IhexError};
+use crate::microcode::{Control, Microcode, FLAG_STATES, OPCODES, STEPS};
+use std::path::Path;
+
+/// Size of a 28C16.
+pub const ROM_SIZE: usize = 2048;
+
+/// The part of each EEPROM the control logic addresses, since A10 is tied low.
+pub const ADDRESSED_SIZE: usize = ROM_SIZE / 2;
+
+/// Which of the two EEPROMs a byte is read from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Chip {
+    /// Drives HLT - AO, the high byte of the control word.
+    Left,
+    /// Drives EO - FI, the low byte of the control word.
+    Right,
+}
+
+/// The EEPROM address the control logic reads for the given chip, flags, opcode and step.
+pub fn address(chip: Chip, flags: Flags, opcode: u8, step: u8) -> usize {
+    let chip = match chip {
+        Chip::Left => 0,
+        Chip::Right => 1,
+    };
+    let carry = flags.contains(Flags::C) as usize;
+    let zero = flags.contains(Flags::Z) as usize;
+
+    zero << 9 | carry << 8 | chip << 7 | (opcode as usize & 0xf) << 3 | (step as usize & 0x7)
+}
+
+/// Build the ROM image programmed into both EEPROMs.
+///
+/// The upper kilobyte is never addressed since A10 is tied low, and is left erased (`0xff`).
+pub fn image(microcode: &Microcode) -> Vec<u8> {
+    let mut image = vec![0xff; ROM_SIZE];
+
+    for flags in 0..FLAG_STATES as u8 {
+        let flags = Flags::from_bits_truncate(flags);
+
+        for opcode in 0..OPCODES as u8 {
+            for step in 0..STEPS as u8 {
+                let [high, low] = microcode.control(flags, opcode, step).bits().to_be_bytes();
+                image[address(Chip::Left, flags, opcode, step)] = high;
+                image[address(Chip::Right, flags, opcode, step)] = low;
+            }
+        }
+    }
+
+    image
+}
+
+/// Whether an image file is Intel HEX, going by its extension, rather than raw bytes.
+pub fn is_ihex(path: &Path) -> bool {
+    matches!(
+        path.extension().and_then(|ext| ext.to_str()),
+        Some("hex") | Some("ihex")
+    )
+}
+
+/// Read the contents of a dump file: Intel HEX for `.hex` and `.ihex` files, and raw bytes
+/// otherwise. Intel HEX records past the end of a 28C16 are errors.
+pub fn parse_dump(path: &Path, data: &[u8]) -> Result<Vec<u8>, IhexError> {
+    if is_ihex(path) {
+        image::parse_ihex(&String::from_utf8_lossy(data), ROM_SIZE)
+    } else {
+        Ok(data.to_vec())
+    }
+}
+
+/// Rebuild a microcode table from dumps of the left and right EEPROMs.
+///
+/// Each dump is either the whole EEPROM, whose upper kilobyte is ignored, or only the kilobyte
+/// the control logic addresses. Dumps are only read at the addresses their chip sees, so they
+/// don't need to hold the same image. The step counter resets after `steps` T-states in hardware
+/// rather than in the ROMs, so it has to be given (see `Variant::steps`).
+pub fn microcode(left: &[u8], right: &[u8], steps: u8) -> Result<Microcode, EaterError> {
+    for dump in [left, right].iter() {
+        match dump.len() {
+            ADDRESSED_SIZE | ROM_SIZE => (),
+            len if len < ADDRESSED_SIZE => {
+                return Err(EaterError::RomTooSmall {
+                    len,
+                    size: ADDRESSED_SIZE,
+                })
+            }
+            len => return Err(EaterError::RomWrongSize { len }),
+        }
+    }
+
+    let mut microcode = Microcode::empty(steps);
+
+    for flags in 0..FLAG_STATES as u8 {
+        let flags = Flags::from_bits_truncate(flags);
+
+        for opcode in 0..OPCODES as u8 {
+            for step in 0..STEPS as u8 {
+                let high = left[address(Chip::Left, flags, opcode, step)];
+                let low = right[address(Chip::Right, flags, opcode, step)];
+                let control = Control::from_bits_truncate(u16::from_be_bytes([high, low]));
+                microcode.set_control(flags, opcode, step, control);
+            }
+        }
+    }
+
+    Ok(microcode)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::{Cpu, Variant};
+    use crate::sim::EaterSim;
+
+    #[test]
+    #[allow(clippy::unusual_byte_groupings)] // Addresses are grouped as Z C / chip / opcode / step
+    fn test_eeprom_layout() {
+        let image = image(&Microcode::default());
+        assert_eq!(image.len(), ROM_SIZE);
+
+        // Fetch: MI | CO, then RO | II | CE
+        assert_eq!(image[0b00_0_0000_000], 0x40);
+        assert_eq!(image[0b00_1_0000_000], 0x04);
+        assert_eq!(image[0b00_0_0000_001], 0x14);
+        assert_eq!(image[0b00_1_0000_001], 0x08);
+
+        // SUB 15: EO | AI | SU | FI in T4
+        assert_eq!(image[0b00_0_0011_100], 0x02);
+        assert_eq!(image[0b00_1_0011_100], 0xc1);
+
+        // JC only jumps with the carry flag (A8) set, and JZ with the zero flag (A9)
+        assert_eq!(image[0b00_0_0111_010], 0x00);
+        assert_eq!(image[0b01_0_0111_010], 0x08);
+        assert_eq!(image[0b01_1_0111_010], 0x02);
+        assert_eq!(image[0b01_0_1000_010], 0x00);
+        assert_eq!(image[0b10_0_1000_010], 0x08);
+
+        assert!(image[1024..].iter().all(|&byte| byte == 0xff));
+    }
+
+    #[test]
+    fn test_eeprom_matches_microcode() {
+        let microcode = Microcode::ram256();
+        let image = image(&microcode);
+
+        for flags in 0..FLAG_STATES as u8 {
+            let flags = Flags::from_bits_truncate(flags);
+
+            for opcode in 0..OPCODES as u8 {
+                for step in 0..STEPS as u8 {
+                    let high = image[address(Chip::Left, flags, opcode, step)];
+                    let low = image[address(Chip::Right, flags, opcode, step)];
+                    let control = Control::from_bits_truncate(u16::from_be_bytes([high, low]));
+                    assert_eq!(control, microcode.control(flags, opcode, step));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_eeprom_read_dumps() {
+        for variant in [Variant::Original, Variant::Ram256].iter().copied() {
+            let reference = Microcode::for_variant(variant);
+            let image = image(&reference);
+            let dumped = microcode(&image, &image[..ADDRESSED_SIZE], variant.steps());
+            assert_eq!(dumped, Ok(reference));
+        }
+
+        // Each chip is only read at its own byte select
+        let reference = Microcode::default();
+        let mut left = image(&reference);
+        let mut right = left.clone();
+        for byte in 0..ADDRESSED_SIZE {
+            if byte & 0x80 == 0 {
+                right[byte] = 0;
+            } else {
+                left[byte] = 0;
+            }
+        }
+        assert_eq!(microcode(&left, &right, 5), Ok(reference));
+
+        assert_eq!(
+            microcode(&left, &right[..1000], 5),
+            Err(EaterError::RomTooSmall {
+                len: 1000,
+                size: ADDRESSED_SIZE,
+            })
+        );
+        for &len in &[1500, 4096] {
+            assert_eq!(
+                microcode(&left, &vec![0; len], 5),
+                Err(EaterError::RomWrongSize { len })
+            );
+        }
+    }
+
+    #[test]
+    fn test_eeprom_ihex_dumps() {
+        for variant in [Variant::Original, Variant::Ram256].iter().copied() {
+            let reference = Microcode::for_variant(variant);
+            let image = image(&reference);
+            let ihex = image::format_ihex(&image);
+
+            let left = parse_dump(Path::new("left.hex"), ihex.as_bytes()).unwrap();
+            let right = parse_dump(Path::new("right.bin"), &image).unwrap();
+            assert_eq!(left, image);
+            assert_eq!(microcode(&left, &right, variant.steps()), Ok(reference));
+        }
+
+        assert!(parse_dump(Path::new("left.ihex"), &[0x1e, 0x2f]).is_err());
+        let high = b":02000004FFFFFC\n:0100000042BD\n:00000001FF\n";
+        assert!(parse_dump(Path::new("left.hex"), high).is_err());
+    }
+
+    #[test]
+    fn test_eeprom_run_dumps() {
+        // Overflow to set the carry flag, then JC over the OUT
+        let program = [
+            0x1e, 0x2f, 0x74, 0xe0, 0xf0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0x01,
+        ];
+        let run = |microcode| {
+            let mut sim = EaterSim::with_output(Vec::new());
+            sim.set_microcode(Some(microcode));
+            sim.load(&program).unwrap();
+            sim.run().unwrap();
+            sim.into_output()
+        };
+
+        let left = image(&Microcode::default());
+        let mut right = left.clone();
+        assert_eq!(run(microcode(&left, &right, 5).unwrap()), Vec::<u8>::new());
+
+        // A right EEPROM that lost the J bit in JC's carry-set rows never jumps
+        for flags in [Flags::C, Flags::C | Flags::Z].iter().copied() {
+            right[address(Chip::Right, flags, 0x7, 2)] &= !0x02;
+        }
+        assert_eq!(run(microcode(&left, &right, 5).unwrap()), [0]);
+    }
+}
@@ -0,0 +1,69 @@
+use crate::eeprom::{ADDRESSED_SIZE, ROM_SIZE};
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fmt;
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum EaterError {
+    /// The program image is larger than memory.
+    ImageTooLarge { len: usize, size: usize },
+    /// The program image is smaller than memory and was not loaded with padding.
+    ImageTooSmall { len: usize, size: usize },
+    /// A control logic EEPROM dump is smaller than the range the control logic addresses.
+    RomTooSmall { len: usize, size: usize },
+    /// A control logic EEPROM dump is neither the whole EEPROM nor the range the control logic
+    /// addresses.
+    RomWrongSize { len: usize },
+    /// An undefined opcode was fetched while trapping on undefined opcodes.
+    UndefinedOpcode { addr: u8, inst: u8 },
+    /// The backends of a co-simulation disagreed at this instruction, counting from zero. The
+    /// differences are available from `CoSim::divergence`.
+    Diverged { instruction: u64 },
+}
+
+impl EaterError {
+    /// Ensure a program image is exactly the size of memory.
+    pub(crate) fn check_image(len: usize, size: usize) -> Result<(), Self> {
+        match len {
+            len if len > size => Err(EaterError::ImageTooLarge { len, size }),
+            len if len < size => Err(EaterError::ImageTooSmall { len, size }),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl fmt::Display for EaterError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EaterError::ImageTooLarge { len, size } => write!(
+                f,
+                "Program image is {} bytes, but memory is only {} bytes",
+                len, size
+            ),
+            EaterError::ImageTooSmall { len, size } => write!(
+                f,
+                "Program image is {} bytes, but memory is {} bytes",
+                len, size
+            ),
+            EaterError::RomTooSmall { len, size } => write!(
+                f,
+                "ROM dump is {} bytes, but the control logic addresses {} bytes",
+                len, size
+            ),
+            EaterError::RomWrongSize { len } => write!(
+                f,
+                "ROM dump is {} bytes, but should be {} bytes, or the {} bytes the control logic \
+                 addresses",
+                len, ROM_SIZE, ADDRESSED_SIZE
+            ),
+            EaterError::UndefinedOpcode { addr, inst } => {
+                write!(f, "Undefined opcode {:#04x} at address {}", inst, addr)
+            }
+            EaterError::Diverged { instruction } => {
+                write!(f, "Backends diverged at instruction {}", instruction)
+            }
+        }
+    }
+}
+
+impl Error for EaterError {}
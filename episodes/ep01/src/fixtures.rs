@@ -0,0 +1,33 @@
+//! Programs shared by the backend tests and the benchmarks, which include this file directly.
+
+/// Prints multiples of three until the carry flag is set.
+pub(crate) const EXAMPLE: &[u8; 16] = include_bytes!("example.bin");
+
+/// Prints multiples of three like `EXAMPLE`, storing the running sum in mem[14] every iteration.
+pub(crate) const STORE_THREES: [u8; 16] = [
+    0x1e, 0x2f, 0x4e, 0xe0, 0x76, 0x61, 0xf0, 0, 0, 0, 0, 0, 0, 0, 0, 0x03,
+];
+
+/// Counts up in mem[15], in steps of one stored in mem[14].
+pub(crate) const COUNTER: [u8; 16] = [
+    0x51, 0x4e, 0x1f, 0x2e, 0x4f, 0xe0, 0x62, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+];
+
+/// Replaces the HLT at 3 with OUT and runs it, then puts the HLT back and jumps to it.
+pub(crate) const SELF_MODIFYING: [u8; 16] = [
+    0x1e, 0x43, 0xe0, 0xf0, 0x1f, 0x43, 0x62, 0, 0, 0, 0, 0, 0, 0, 0xe0, 0xf0,
+];
+
+/// Random programs from a xorshift generator, the same ones on every call.
+pub(crate) fn random_programs(count: usize) -> Vec<[u8; 16]> {
+    let mut seed = 0x2545_f491_4f6c_dd1du64;
+    let mut programs = vec![[0; 16]; count];
+    for byte in programs.iter_mut().flatten() {
+        seed ^= seed << 13;
+        seed ^= seed >> 7;
+        seed ^= seed << 17;
+        *byte = seed as u8;
+    }
+
+    programs
+}
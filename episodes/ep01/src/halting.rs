@@ -0,0 +1,171 @@
+//! Exact halting and infinite loop detection.
+//!
+//! Memory and registers fit in about 20 bytes, and the machine has no inputs, so its state at
+//! each instruction boundary determines everything that follows. A program that hasn't halted by
+//! the time a state repeats never will.
+
+use crate::error::EaterError;
+use std::collections::HashMap;
+use std::hash::Hash;
+
+/// How a checked run ended.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RunOutcome {
+    Halted,
+    /// The machine returned to an earlier state. The loop was entered after `entry` instructions,
+    /// and repeats every `period` instructions.
+    InfiniteLoop {
+        period: u64,
+        entry: u64,
+    },
+    /// The instruction limit was reached before the machine halted or repeated a state.
+    LimitReached,
+}
+
+/// The result of `run_checked` on either backend.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RunReport {
+    pub outcome: RunOutcome,
+    /// Every value output during the run. For an infinite loop this covers the instructions
+    /// before the loop and one iteration of it.
+    pub outputs: Vec<u8>,
+}
+
+/// A backend that can be run with loop detection.
+pub(crate) trait Checked {
+    /// Everything that can differ between two instruction boundaries, apart from the cycle count.
+    type State: Hash + Eq;
+
+    fn state(&self) -> Self::State;
+
+    /// Execute one instruction, collecting the values it outputs.
+    fn step_checked(&mut self, outputs: &mut Vec<u8>) -> Result<bool, EaterError>;
+}
+
+/// Run for at most `limit` instructions, remembering every state seen along the way.
+pub(crate) fn run_checked<M: Checked>(
+    machine: &mut M,
+    limit: u64,
+) -> Result<RunReport, EaterError> {
+    let mut seen = HashMap::new();
+    let mut outputs = Vec::new();
+    let mut count = 0;
+
+    let outcome = loop {
+        if let Some(entry) = seen.insert(machine.state(), count) {
+            break RunOutcome::InfiniteLoop {
+                period: count - entry,
+                entry,
+            };
+        }
+        if count == limit {
+            break RunOutcome::LimitReached;
+        }
+        if machine.step_checked(&mut outputs)? {
+            break RunOutcome::Halted;
+        }
+        count += 1;
+    };
+
+    Ok(RunReport { outcome, outputs })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::COUNTER;
+    use crate::{Cpu, EaterSim, EaterVm, Microcode, OpcodePolicy};
+
+    /// Run a program on every backend.
+    fn run(program: &[u8; 16], limit: u64) -> Vec<RunReport> {
+        let mut vm = EaterVm::with_output(Vec::new());
+        vm.load(program).unwrap();
+        let mut reports = vec![vm.run_checked(limit).unwrap()];
+        assert_eq!(vm.output(), &reports[0].outputs);
+
+        for microcode in [None, Some(Microcode::default())] {
+            let mut sim = EaterSim::with_output(Vec::new());
+            sim.set_microcode(microcode);
+            sim.load(program).unwrap();
+            reports.push(sim.run_checked(limit).unwrap());
+            assert_eq!(sim.output(), &reports.last().unwrap().outputs);
+        }
+
+        reports
+    }
+
+    #[test]
+    fn test_halting_halted() {
+        for report in run(include_bytes!("example.bin"), 1000) {
+            assert_eq!(report.outcome, RunOutcome::Halted);
+            assert_eq!(report.outputs.len(), 86);
+            assert_eq!(report.outputs.last(), Some(&2));
+        }
+    }
+
+    #[test]
+    fn test_halting_infinite_loop() {
+        // Adding zero never carries
+        let mut program = *include_bytes!("example.bin");
+        program[15] = 0;
+
+        for report in run(&program, 1000) {
+            assert_eq!(
+                report.outcome,
+                RunOutcome::InfiniteLoop {
+                    period: 4,
+                    entry: 2
+                }
+            );
+            assert_eq!(report.outputs, [0]);
+        }
+
+        for report in run(&program, 3) {
+            assert_eq!(report.outcome, RunOutcome::LimitReached);
+            assert_eq!(report.outputs, [0]);
+        }
+
+        // Counting in mem[15] only repeats once the byte wraps around
+        for report in run(&COUNTER, 10_000) {
+            assert_eq!(
+                report.outcome,
+                RunOutcome::InfiniteLoop {
+                    period: 1280,
+                    entry: 4
+                }
+            );
+            assert_eq!(report.outputs.len(), 256);
+        }
+    }
+
+    #[test]
+    fn test_halting_loop_entry() {
+        // JMP 0 loops from the first instruction. The simulator also remembers IR, which selects
+        // the microcode for the next fetch, so only the interpreter can tell.
+        let mut vm = EaterVm::with_output(Vec::new());
+        vm.load(&[0x60; 16]).unwrap();
+
+        assert_eq!(
+            vm.run_checked(10).unwrap().outcome,
+            RunOutcome::InfiniteLoop {
+                period: 1,
+                entry: 0
+            }
+        );
+    }
+
+    #[test]
+    fn test_halting_trap() {
+        let mut vm = EaterVm::with_output(Vec::new());
+        vm.set_opcode_policy(OpcodePolicy::Trap);
+        vm.poke(0, 0x90);
+
+        assert_eq!(
+            vm.run_checked(10),
+            Err(EaterError::UndefinedOpcode {
+                addr: 0,
+                inst: 0x90
+            })
+        );
+    }
+}
@@ -0,0 +1,109 @@
+//! Undo logs that let the backends step backwards in time.
+//!
+//! The machine state is so small that recording every step is cheap: each entry holds the
+//! registers from before the step, and the old value of the memory byte it overwrote, if any.
+
+use crate::cpu::Cpu;
+use std::collections::VecDeque;
+
+/// Step a backend backwards through the steps it recorded.
+///
+/// Recording is off until `set_history_limit` is called. Loading, poking, resetting or restoring
+/// a snapshot rewrites the state outside of any step, so it clears the log. Values already sent
+/// to the output device can't be taken back.
+pub trait Rewind: Cpu {
+    /// Keep at most `limit` steps, dropping the oldest ones first. A limit of zero stops recording
+    /// and clears the log.
+    fn set_history_limit(&mut self, limit: usize);
+
+    /// The number of steps that can be undone.
+    fn history_len(&self) -> usize;
+
+    /// Undo the most recent step, which is an instruction on the interpreter and a T-state on the
+    /// simulator. Returns `false` when there is nothing left to undo.
+    fn step_back(&mut self) -> bool;
+
+    /// Undo steps until the predicate accepts the state, returning `false` if the log runs out
+    /// first. At least one step is undone, so repeated calls find earlier and earlier matches.
+    fn run_back_to<P>(&mut self, mut predicate: P) -> bool
+    where
+        P: FnMut(&Self) -> bool,
+        Self: Sized,
+    {
+        while self.step_back() {
+            if predicate(self) {
+                return true;
+            }
+        }
+
+        false
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct Delta<R> {
+    pub(crate) registers: R,
+    /// Address and old value of the memory byte written by the step.
+    pub(crate) write: Option<(usize, u8)>,
+}
+
+/// A bounded undo log of register sets `R`.
+#[derive(Debug)]
+pub(crate) struct History<R> {
+    log: VecDeque<Delta<R>>,
+    limit: usize,
+}
+
+impl<R> Default for History<R> {
+    fn default() -> Self {
+        Self {
+            log: VecDeque::new(),
+            limit: 0,
+        }
+    }
+}
+
+impl<R> History<R> {
+    pub(crate) fn set_limit(&mut self, limit: usize) {
+        self.limit = limit;
+        while self.log.len() > limit {
+            self.log.pop_front();
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.log.len()
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.log.clear();
+    }
+
+    /// Whether steps should be recorded, so backends can skip gathering their registers.
+    pub(crate) fn is_recording(&self) -> bool {
+        self.limit > 0
+    }
+
+    /// Start a new entry with the registers from before a step.
+    pub(crate) fn record(&mut self, registers: R) {
+        if self.log.len() == self.limit {
+            self.log.pop_front();
+        }
+
+        self.log.push_back(Delta {
+            registers,
+            write: None,
+        });
+    }
+
+    /// Note the old value of a memory byte the current step is about to overwrite.
+    pub(crate) fn store(&mut self, addr: usize, old: u8) {
+        if let Some(delta) = self.log.back_mut() {
+            delta.write = Some((addr, old));
+        }
+    }
+
+    pub(crate) fn pop(&mut self) -> Option<Delta<R>> {
+        self.log.pop_back()
+    }
+}
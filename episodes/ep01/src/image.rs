@@ -0,0 +1,369 @@
+//! Program image formats accepted by the command line tools.
+
+use std::error::Error;
+use std::fmt::{self, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+/// Encoding of a program image on disk.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    /// Raw memory bytes.
+    Binary,
+    /// Hex text: bytes separated by whitespace or commas, with `;` comments.
+    Hex,
+    /// Assembly source for the `eater_8bit` instruction set.
+    Asm,
+}
+
+impl Format {
+    /// Guess the format of an image from its file extension, falling back to its contents.
+    ///
+    /// Content that isn't UTF-8 is binary. Text that parses as hex is hex, and any other text is
+    /// assumed to be assembly.
+    pub fn detect(path: Option<&Path>, data: &[u8]) -> Self {
+        let ext = path
+            .and_then(|path| path.extension())
+            .and_then(|ext| ext.to_str())
+            .map(|ext| ext.to_ascii_lowercase());
+
+        match ext.as_deref() {
+            Some("asm") | Some("s") => return Format::Asm,
+            Some("hex") | Some("txt") => return Format::Hex,
+            Some("bin") | Some("rom") => return Format::Binary,
+            _ => (),
+        }
+
+        match std::str::from_utf8(data) {
+            Ok(text) if parse_hex(text).is_ok() => Format::Hex,
+            Ok(text) if !text.chars().any(|c| c.is_control() && !c.is_whitespace()) => Format::Asm,
+            _ => Format::Binary,
+        }
+    }
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "bin" | "binary" => Ok(Format::Binary),
+            "hex" => Ok(Format::Hex),
+            "asm" => Ok(Format::Asm),
+            _ => Err(format!("Unknown image format `{}`", s)),
+        }
+    }
+}
+
+/// A token in hex text that isn't a byte.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HexError {
+    line: usize,
+    token: String,
+}
+
+impl HexError {
+    /// The offending token.
+    pub fn token(&self) -> &str {
+        &self.token
+    }
+
+    /// Line number of the token, starting at 1.
+    pub fn line(&self) -> usize {
+        self.line
+    }
+}
+
+impl fmt::Display for HexError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid hex byte `{}` on line {}", self.token, self.line)
+    }
+}
+
+impl Error for HexError {}
+
+/// A malformed Intel HEX record.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct IhexError {
+    line: usize,
+    reason: &'static str,
+}
+
+impl IhexError {
+    /// What is wrong with the record.
+    pub fn reason(&self) -> &str {
+        self.reason
+    }
+
+    /// Line number of the record, starting at 1.
+    pub fn line(&self) -> usize {
+        self.line
+    }
+}
+
+impl fmt::Display for IhexError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Invalid Intel HEX record on line {}: {}",
+            self.line, self.reason
+        )
+    }
+}
+
+impl Error for IhexError {}
+
+/// Parse hex text into bytes.
+///
+/// Bytes are one or two hex digits with an optional `0x` prefix, separated by whitespace or
+/// commas. Longer runs of digits without a prefix are split into pairs, so `xxd -p` output can be
+/// read directly. Everything after a `;` is a comment.
+pub fn parse_hex(text: &str) -> Result<Vec<u8>, HexError> {
+    let mut bytes = Vec::new();
+
+    for (line, src) in text.lines().enumerate() {
+        let code = src.split(';').next().unwrap_or_default();
+
+        for token in code.split(|c: char| c.is_whitespace() || c == ',') {
+            if token.is_empty() {
+                continue;
+            }
+
+            let error = || HexError {
+                line: line + 1,
+                token: token.to_string(),
+            };
+            let (digits, prefixed) = match token.strip_prefix("0x").or(token.strip_prefix("0X")) {
+                Some(digits) => (digits, true),
+                None => (token, false),
+            };
+            if digits.is_empty() || !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+                return Err(error());
+            }
+
+            match digits.len() {
+                1 | 2 => bytes.push(u8::from_str_radix(digits, 16).map_err(|_| error())?),
+                len if !prefixed && len % 2 == 0 => {
+                    for pair in digits.as_bytes().chunks(2) {
+                        let pair = std::str::from_utf8(pair).map_err(|_| error())?;
+                        bytes.push(u8::from_str_radix(pair, 16).map_err(|_| error())?);
+                    }
+                }
+                _ => return Err(error()),
+            }
+        }
+    }
+
+    Ok(bytes)
+}
+
+/// Format bytes as hex text that `parse_hex` reads back, eight bytes per line.
+pub fn format_hex(image: &[u8]) -> String {
+    let mut out = String::new();
+
+    for chunk in image.chunks(8) {
+        let line: Vec<_> = chunk.iter().map(|byte| format!("{:02x}", byte)).collect();
+        writeln!(out, "{}", line.join(" ")).unwrap();
+    }
+
+    out
+}
+
+/// Format bytes as Intel HEX, the format EEPROM programmers read, sixteen bytes per record.
+///
+/// Images larger than 64 KiB get extended linear address records.
+pub fn format_ihex(image: &[u8]) -> String {
+    let mut out = String::new();
+    let mut record = |kind: u8, addr: u16, data: &[u8]| {
+        let [hi, lo] = addr.to_be_bytes();
+        let mut sum = (data.len() as u8)
+            .wrapping_add(hi)
+            .wrapping_add(lo)
+            .wrapping_add(kind);
+
+        write!(out, ":{:02X}{:04X}{:02X}", data.len(), addr, kind).unwrap();
+        for &byte in data {
+            write!(out, "{:02X}", byte).unwrap();
+            sum = sum.wrapping_add(byte);
+        }
+        writeln!(out, "{:02X}", sum.wrapping_neg()).unwrap();
+    };
+
+    for (index, chunk) in image.chunks(16).enumerate() {
+        let addr = index * 16;
+        if addr > 0 && addr % 0x10000 == 0 {
+            record(0x04, 0, &((addr >> 16) as u16).to_be_bytes());
+        }
+        record(0x00, addr as u16, chunk);
+    }
+    record(0x01, 0, &[]);
+
+    out
+}
+
+/// Parse Intel HEX, as written by `format_ihex` or an EEPROM programmer, into bytes.
+///
+/// Data records are placed at their address, following extended segment and linear address
+/// records, and bytes that no record covers are left erased (`0xff`). Records with data past
+/// `max_len` bytes are errors, so a stray address record can't allocate gigabytes. Blank lines
+/// are skipped, and everything after the end of file record is ignored.
+pub fn parse_ihex(text: &str, max_len: usize) -> Result<Vec<u8>, IhexError> {
+    let mut image = Vec::new();
+    let mut base = 0;
+
+    for (line, src) in text.lines().enumerate() {
+        let src = src.trim();
+        if src.is_empty() {
+            continue;
+        }
+
+        let error = |reason| IhexError {
+            line: line + 1,
+            reason,
+        };
+        let digits = src
+            .strip_prefix(':')
+            .ok_or_else(|| error("missing start code `:`"))?;
+        if digits.len() % 2 != 0 || !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(error("expected pairs of hex digits"));
+        }
+        let bytes = (0..digits.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&digits[i..i + 2], 16))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| error("expected pairs of hex digits"))?;
+
+        if bytes.len() < 5 || bytes.len() != bytes[0] as usize + 5 {
+            return Err(error("length doesn't match the byte count"));
+        }
+        if bytes.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)) != 0 {
+            return Err(error("checksum mismatch"));
+        }
+
+        let addr = u16::from_be_bytes([bytes[1], bytes[2]]) as usize;
+        let data = &bytes[4..bytes.len() - 1];
+        match (bytes[3], data) {
+            (0x00, _) => {
+                let start = base + addr;
+                let end = start + data.len();
+                if end > max_len {
+                    return Err(error("data past the end of the image"));
+                }
+                if image.len() < end {
+                    image.resize(end, 0xff);
+                }
+                image[start..end].copy_from_slice(data);
+            }
+            (0x01, _) => return Ok(image),
+            (0x02, &[hi, lo]) => base = (u16::from_be_bytes([hi, lo]) as usize) << 4,
+            (0x04, &[hi, lo]) => base = (u16::from_be_bytes([hi, lo]) as usize) << 16,
+            (0x02, _) | (0x04, _) => return Err(error("address records take two bytes")),
+            // Start addresses don't matter to a memory image
+            (0x03, _) | (0x05, _) => (),
+            _ => return Err(error("unknown record type")),
+        }
+    }
+
+    Err(IhexError {
+        line: text.lines().count(),
+        reason: "missing end of file record",
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_image_parse_hex() {
+        let text = "1e 2f,0xe0 ; comment\n75\n\n  6 f0 0003\n";
+
+        assert_eq!(
+            parse_hex(text).unwrap(),
+            [0x1e, 0x2f, 0xe0, 0x75, 0x06, 0xf0, 0x00, 0x03]
+        );
+        assert_eq!(parse_hex(&format_hex(&[0xab; 20])).unwrap(), [0xab; 20]);
+        assert!(parse_hex("").unwrap().is_empty());
+
+        let err = parse_hex("00\n1e lda").unwrap_err();
+        assert_eq!(err.line(), 2);
+        assert_eq!(err.token(), "lda");
+        assert!(parse_hex("123").is_err());
+        assert!(parse_hex("0x100").is_err());
+    }
+
+    #[test]
+    fn test_image_format_ihex() {
+        let image: Vec<u8> = (0..20).collect();
+
+        assert_eq!(
+            format_ihex(&image),
+            ":10000000000102030405060708090A0B0C0D0E0F78\n\
+             :0400100010111213A6\n\
+             :00000001FF\n"
+        );
+
+        let ihex = format_ihex(&[0; 0x10010]);
+        assert!(ihex.contains(":10FFF0000000000000000000000000000000000001\n:020000040001F9\n"));
+        assert!(ihex.ends_with(":1000000000000000000000000000000000000000F0\n:00000001FF\n"));
+    }
+
+    #[test]
+    fn test_image_parse_ihex() {
+        let image: Vec<u8> = (0..=255).cycle().take(0x10020).collect();
+        assert_eq!(
+            parse_ihex(&format_ihex(&image), image.len()).unwrap(),
+            image
+        );
+
+        // Gaps are erased, and segment addresses are in units of 16 bytes
+        let ihex = ":0100020042BB\n\n:020000020001FB\n:0100000043BC\n:00000001FF\nnot hex";
+        let mut expected = vec![0xff; 17];
+        expected[2] = 0x42;
+        expected[16] = 0x43;
+        assert_eq!(parse_ihex(ihex, 256).unwrap(), expected);
+
+        let err = parse_ihex(":0100020042BC\n", 256).unwrap_err();
+        assert_eq!(err.line(), 1);
+        assert_eq!(err.reason(), "checksum mismatch");
+        assert_eq!(
+            parse_ihex(":00000001FF\n0100020042BB", 256).map(|image| image.len()),
+            Ok(0)
+        );
+        assert_eq!(
+            parse_ihex("\n:0200020042BB\n", 256)
+                .unwrap_err()
+                .to_string(),
+            "Invalid Intel HEX record on line 2: length doesn't match the byte count"
+        );
+        assert_eq!(
+            parse_ihex(":0100020042BB\n", 256).unwrap_err().reason(),
+            "missing end of file record"
+        );
+        assert!(parse_ihex(":01000200+2BB\n:00000001FF", 256).is_err());
+
+        // Data is checked against the limit before anything is allocated for it
+        let high = ":02000004FFFFFC\n:0100000042BD\n:00000001FF\n";
+        let err = parse_ihex(high, 256).unwrap_err();
+        assert_eq!(
+            (err.line(), err.reason()),
+            (2, "data past the end of the image")
+        );
+        assert!(parse_ihex(&format_ihex(&[0; 257]), 256).is_err());
+    }
+
+    #[test]
+    fn test_image_detect() {
+        let asm = Path::new("prog.asm");
+        let unknown = Path::new("prog");
+
+        assert_eq!(Format::detect(Some(asm), b"1e 2f"), Format::Asm);
+        assert_eq!(Format::detect(Some(unknown), b"1e 2f\n"), Format::Hex);
+        assert_eq!(Format::detect(None, b"lda 14\nout\n"), Format::Asm);
+        assert_eq!(
+            Format::detect(None, include_bytes!("example.bin")),
+            Format::Binary
+        );
+        assert_eq!(Format::detect(None, &[0x1e, 0x00, 0x03]), Format::Binary);
+    }
+}
@@ -1,20 +1,71 @@
-use bitflags::bitflags;
+use crate::alu::alu;
+use crate::cpu::{Cpu, Flags, OpcodePolicy, Phase, Variant};
+use crate::error::EaterError;
+use crate::halting::{self, Checked, RunReport};
+use crate::history::{History, Rewind};
+use crate::isa::{Isa, Op, ISA};
+use crate::output::{Output, Stdout};
+use crate::snapshot::{Snapshot, SnapshotError};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug)]
+pub struct EaterVm<O = Stdout> {
+    mem: Vec<u8>,
+    variant: Variant,
+    isa: &'static Isa,
+    pc: u8,
+    a: u8,
+    ir: u8,
+    flags: Flags,
+    halt: bool,
+    fault: Option<EaterError>,
+    policy: OpcodePolicy,
+    cycles: u64,
+    history: History<Registers>,
+    output: O,
+}
+
+/// Everything a step can change apart from memory, for the undo log.
+#[derive(Debug)]
+struct Registers {
+    pc: u8,
+    a: u8,
+    ir: u8,
+    flags: Flags,
+    halt: bool,
+    fault: Option<EaterError>,
+    cycles: u64,
+}
 
-#[derive(Debug, Default)]
-pub struct EaterVm {
-    mem: [u8; 16],
+/// The complete state of an `EaterVm`, apart from its output device.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VmSnapshot {
+    mem: Vec<u8>,
+    variant: Variant,
     pc: u8,
     a: u8,
+    ir: u8,
     flags: Flags,
     halt: bool,
+    fault: Option<EaterError>,
+    policy: OpcodePolicy,
+    cycles: u64,
 }
 
-bitflags! {
-    #[derive(Default)]
-    struct Flags: u8 {
-        const CLEAR = 0;
-        const Z = 0b01;
-        const C = 0b10;
+impl Snapshot for VmSnapshot {
+    const BACKEND: &'static str = "interp";
+
+    fn validate(&self) -> Result<(), SnapshotError> {
+        if self.mem.len() != self.variant.mem_size() {
+            return Err(SnapshotError::Invalid(
+                "Memory size doesn't match the variant",
+            ));
+        }
+        if self.pc as usize >= self.mem.len() {
+            return Err(SnapshotError::Invalid("PC is outside of memory"));
+        }
+
+        Ok(())
     }
 }
 
@@ -22,110 +73,342 @@ impl EaterVm {
     pub fn new() -> Self {
         Self::default()
     }
+}
 
-    pub fn load(&mut self, mem: &[u8]) {
-        // TODO: Return Result when mem slice length is not equal to 16.
-        self.mem.copy_from_slice(mem);
+impl Default for EaterVm {
+    fn default() -> Self {
+        Self::with_output(Stdout)
+    }
+}
+
+impl<O: Output> EaterVm<O> {
+    pub fn with_output(output: O) -> Self {
+        Self {
+            mem: vec![0; Variant::Original.mem_size()],
+            variant: Variant::Original,
+            isa: &ISA,
+            pc: 0,
+            a: 0,
+            ir: 0,
+            flags: Flags::CLEAR,
+            halt: false,
+            fault: None,
+            policy: OpcodePolicy::Nop,
+            cycles: 0,
+            history: History::default(),
+            output,
+        }
+    }
+
+    pub fn output(&self) -> &O {
+        &self.output
+    }
+
+    pub fn output_mut(&mut self) -> &mut O {
+        &mut self.output
+    }
+
+    pub fn into_output(self) -> O {
+        self.output
+    }
+
+    /// Switch to another build of the machine. Memory is cleared and the CPU is reset.
+    pub fn set_variant(&mut self, variant: Variant) {
+        self.variant = variant;
+        self.mem = vec![0; variant.mem_size()];
+        self.reset();
+    }
+
+    /// Decode with another instruction table.
+    #[cfg(test)]
+    pub(crate) fn set_isa(&mut self, isa: &'static Isa) {
+        self.isa = isa;
+    }
+
+    pub fn variant(&self) -> Variant {
+        self.variant
+    }
+
+    /// Capture the complete machine state, apart from the output device.
+    pub fn snapshot(&self) -> VmSnapshot {
+        VmSnapshot {
+            mem: self.mem.clone(),
+            variant: self.variant,
+            pc: self.pc,
+            a: self.a,
+            ir: self.ir,
+            flags: self.flags,
+            halt: self.halt,
+            fault: self.fault.clone(),
+            policy: self.policy,
+            cycles: self.cycles,
+        }
+    }
+
+    /// Return to a captured state. The output device is left alone.
+    pub fn restore(&mut self, snapshot: &VmSnapshot) {
+        self.mem = snapshot.mem.clone();
+        self.variant = snapshot.variant;
+        self.pc = snapshot.pc;
+        self.a = snapshot.a;
+        self.ir = snapshot.ir;
+        self.flags = snapshot.flags;
+        self.halt = snapshot.halt;
+        self.fault = snapshot.fault.clone();
+        self.policy = snapshot.policy;
+        self.cycles = snapshot.cycles;
+        self.history.clear();
+    }
+
+    /// Set the architectural registers, for starting differential tests from arbitrary states.
+    pub(crate) fn set_registers(&mut self, pc: u8, a: u8, flags: Flags) {
+        self.pc = pc & self.variant.addr_mask();
+        self.a = a;
+        self.flags = flags;
+        self.history.clear();
+    }
+
+    /// Run until the CPU halts, returns to a state it was in before, or executes `limit`
+    /// instructions. A trapped fault is returned as an error.
+    pub fn run_checked(&mut self, limit: u64) -> Result<RunReport, EaterError> {
+        halting::run_checked(self, limit)
+    }
+
+    /// Surface a trapped fault as an error, otherwise report the halt status.
+    fn status(&self) -> Result<bool, EaterError> {
+        match &self.fault {
+            Some(fault) => Err(fault.clone()),
+            None => Ok(self.halt),
+        }
     }
 
     fn step(&mut self) -> bool {
         if self.halt {
             return self.halt;
         }
+        if self.history.is_recording() {
+            self.history.record(Registers {
+                pc: self.pc,
+                a: self.a,
+                ir: self.ir,
+                flags: self.flags,
+                halt: self.halt,
+                fault: self.fault.clone(),
+                cycles: self.cycles,
+            });
+        }
+        let time = self.cycles;
+        self.cycles += 1;
 
-        let inst = self.mem[(self.pc & 0xf) as usize];
-        let x = inst & 0xf;
+        let mask = self.variant.addr_mask();
+        let addr = self.pc;
+        let inst = self.mem[addr as usize];
+        self.ir = inst;
         let opcode = inst >> 4;
-
-        self.pc += 1;
-        self.pc &= 0xf;
-
-        match opcode {
-            0x0 => {
-                // NOP
+        self.pc = self.pc.wrapping_add(1) & mask;
+
+        // The RAM upgrade takes the operand from the byte after the opcode
+        let x = match self.isa.instruction_len(self.variant, inst) {
+            2 => {
+                let x = self.mem[self.pc as usize];
+                self.pc = self.pc.wrapping_add(1) & mask;
+                x
             }
-            0x1 => {
-                // LDA X
-                self.a = self.mem[x as usize];
+            _ => inst & 0xf,
+        };
+
+        let inst = match self.isa.decode(opcode) {
+            Some(inst) => inst,
+            None => {
+                match self.policy {
+                    OpcodePolicy::Nop => (),
+                    OpcodePolicy::Trap => {
+                        self.fault = Some(EaterError::UndefinedOpcode { addr, inst });
+                        self.halt = true;
+                    }
+                    OpcodePolicy::Halt => {
+                        self.halt = true;
+                    }
+                }
+
+                return self.halt;
             }
-            0x2 => {
-                // ADD X
-                let carry = (self.a as u16).wrapping_add(self.mem[x as usize] as u16);
-
-                self.a = carry as u8;
-                self.flags = if self.a == 0 {
-                    Flags::Z
-                } else if carry >= 0x100 {
-                    Flags::C
-                } else {
-                    Flags::CLEAR
-                };
+        };
+        let value = if inst.uses_memory() {
+            self.mem[x as usize]
+        } else {
+            x
+        };
+
+        match inst.op {
+            Op::Nop => (),
+            Op::Load => {
+                self.a = value;
             }
-            0x3 => {
-                // SUB X
-                let carry = (self.a as u16).wrapping_sub(self.mem[x as usize] as u16);
-
-                self.a = carry as u8;
-                self.flags = if self.a == 0 {
-                    Flags::Z
-                } else if carry >= 0x100 {
-                    Flags::C
-                } else {
-                    Flags::CLEAR
-                };
+            Op::Add | Op::Sub => {
+                let (result, flags) = alu(self.a, value, inst.op == Op::Sub);
+
+                self.a = result;
+                self.flags = flags;
             }
-            0x4 => {
-                // STA X
+            Op::Store => {
+                self.history.store(x as usize, self.mem[x as usize]);
                 self.mem[x as usize] = self.a;
             }
-            0x5 => {
-                // LDI X
-                self.a = x;
-            }
-            0x6 => {
-                // JMP X
-                self.pc = x;
-            }
-            0x7 => {
-                // JC X
-                if self.flags & Flags::C == Flags::C {
-                    self.pc = x;
-                }
-            }
-            0x8 => {
-                // JZ X
-                if self.flags & Flags::Z == Flags::Z {
+            Op::Jump(condition) => {
+                if condition.holds(self.flags) {
                     self.pc = x;
                 }
             }
-            0xe => {
-                // OUT
-                println!("{}", self.a);
+            Op::Out => {
+                self.output.out(self.a, time);
             }
-            0xf => {
-                // HLT
+            Op::Hlt => {
                 self.halt = true;
             }
-            _ => {
-                panic!("Unknown opcode: {:x?}", opcode);
-            }
         }
 
         self.halt
     }
+}
 
-    pub fn run(&mut self) {
-        loop {
-            if self.step() {
-                break;
-            }
+impl<O: Output> Cpu for EaterVm<O> {
+    fn load(&mut self, mem: &[u8]) -> Result<(), EaterError> {
+        EaterError::check_image(mem.len(), self.mem.len())?;
+        self.mem.copy_from_slice(mem);
+        self.history.clear();
+
+        Ok(())
+    }
+
+    fn reset(&mut self) {
+        self.pc = 0;
+        self.a = 0;
+        self.ir = 0;
+        self.flags = Flags::CLEAR;
+        self.halt = false;
+        self.fault = None;
+        self.cycles = 0;
+        self.history.clear();
+    }
+
+    fn set_opcode_policy(&mut self, policy: OpcodePolicy) {
+        self.policy = policy;
+    }
+
+    fn poke(&mut self, addr: u8, value: u8) {
+        let len = self.mem.len();
+        self.mem[addr as usize % len] = value;
+        self.history.clear();
+    }
+
+    fn step_instruction(&mut self) -> Result<bool, EaterError> {
+        self.step();
+        self.status()
+    }
+
+    fn step_clock(&mut self) -> Result<bool, EaterError> {
+        self.step();
+        self.status()
+    }
+
+    fn pc(&self) -> u8 {
+        self.pc
+    }
+
+    fn a(&self) -> u8 {
+        self.a
+    }
+
+    fn ir(&self) -> u8 {
+        self.ir
+    }
+
+    fn phase(&self) -> Phase {
+        Phase::Instruction
+    }
+
+    fn flags(&self) -> Flags {
+        self.flags
+    }
+
+    fn mem(&self) -> &[u8] {
+        &self.mem
+    }
+
+    fn halted(&self) -> bool {
+        self.halt
+    }
+
+    fn cycles(&self) -> u64 {
+        self.cycles
+    }
+}
+
+impl<O: Output> Checked for EaterVm<O> {
+    // IR is only written before it is read, so it doesn't affect what follows
+    type State = (Vec<u8>, u8, u8, Flags);
+
+    fn state(&self) -> Self::State {
+        (self.mem.clone(), self.pc, self.a, self.flags)
+    }
+
+    fn step_checked(&mut self, outputs: &mut Vec<u8>) -> Result<bool, EaterError> {
+        let opcode = self.mem[self.pc as usize] >> 4;
+        let out = !self.halt && matches!(self.isa.decode(opcode), Some(inst) if inst.op == Op::Out);
+        let halted = self.step();
+        if out {
+            outputs.push(self.a);
         }
+
+        self.status().map(|_| halted)
+    }
+}
+
+impl<O: Output> Rewind for EaterVm<O> {
+    fn set_history_limit(&mut self, limit: usize) {
+        self.history.set_limit(limit);
+    }
+
+    fn history_len(&self) -> usize {
+        self.history.len()
+    }
+
+    fn step_back(&mut self) -> bool {
+        let delta = match self.history.pop() {
+            Some(delta) => delta,
+            None => return false,
+        };
+
+        let Registers {
+            pc,
+            a,
+            ir,
+            flags,
+            halt,
+            fault,
+            cycles,
+        } = delta.registers;
+        self.pc = pc;
+        self.a = a;
+        self.ir = ir;
+        self.flags = flags;
+        self.halt = halt;
+        self.fault = fault;
+        self.cycles = cycles;
+        if let Some((addr, old)) = delta.write {
+            self.mem[addr] = old;
+        }
+
+        true
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::alu::conformance_cases;
+    use crate::fixtures::COUNTER;
 
     #[test]
     fn test_vm_nop() {
@@ -219,20 +502,20 @@ mod tests {
     }
 
     #[test]
-    fn test_vm_sub_carry() {
+    fn test_vm_sub_borrow() {
         let mut vm = EaterVm::new();
 
         vm.mem[0] = 0x3f; // SUB 15
         vm.mem[1] = 0x3f; // SUB 15
         vm.mem[15] = 0xff; // This is the value added
 
-        // Step the interpreter twice to check the flags
+        // Step the interpreter twice to check the flags; borrowing clears the carry
         vm.step();
         vm.step();
 
         assert_eq!(vm.pc, 2);
         assert_eq!(vm.a, 2);
-        assert_eq!(vm.flags, Flags::C);
+        assert_eq!(vm.flags, Flags::CLEAR);
         assert_eq!(
             vm.mem,
             [0x3f, 0x3f, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff]
@@ -253,7 +536,7 @@ mod tests {
 
         assert_eq!(vm.pc, 2);
         assert_eq!(vm.a, 0);
-        assert_eq!(vm.flags, Flags::Z);
+        assert_eq!(vm.flags, Flags::Z | Flags::C);
         assert_eq!(
             vm.mem,
             [0x3f, 0x3f, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]
@@ -274,7 +557,7 @@ mod tests {
 
         assert_eq!(vm.pc, 2);
         assert_eq!(vm.a, 0xfe);
-        assert_eq!(vm.flags, Flags::CLEAR);
+        assert_eq!(vm.flags, Flags::C);
         assert_eq!(
             vm.mem,
             [0x3f, 0x3f, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]
@@ -385,4 +668,218 @@ mod tests {
         assert_eq!(vm.flags, Flags::CLEAR);
         assert_eq!(vm.mem, [0xf0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
     }
+
+    #[test]
+    fn test_vm_out() {
+        let mut vm = EaterVm::with_output(Vec::new());
+
+        vm.mem[0] = 0x5a; // LDI 10
+        vm.mem[1] = 0xe0; // OUT
+        vm.mem[2] = 0x53; // LDI 3
+        vm.mem[3] = 0xe0; // OUT
+        vm.mem[4] = 0xf0; // HLT
+
+        vm.run().unwrap();
+        assert_eq!(vm.output(), &[10, 3]);
+        assert_eq!(vm.cycles(), 5);
+    }
+
+    #[test]
+    fn test_vm_out_channel() {
+        let (tx, rx) = std::sync::mpsc::channel();
+        let mut vm = EaterVm::with_output(tx);
+
+        vm.mem[0] = 0x00; // NOP
+        vm.mem[1] = 0xe0; // OUT
+        vm.mem[2] = 0xf0; // HLT
+
+        vm.run().unwrap();
+        drop(vm);
+        assert_eq!(rx.iter().collect::<Vec<_>>(), [(0, 1)]);
+    }
+
+    #[test]
+    fn test_vm_load() {
+        let mut vm = EaterVm::new();
+
+        assert_eq!(
+            vm.load(&[0x5a; 17]),
+            Err(EaterError::ImageTooLarge { len: 17, size: 16 })
+        );
+        assert_eq!(
+            vm.load(&[0x5a; 3]),
+            Err(EaterError::ImageTooSmall { len: 3, size: 16 })
+        );
+        assert_eq!(vm.mem, [0; 16]);
+
+        vm.load_padded(&[0x5a, 0xe0, 0xf0]).unwrap();
+        assert_eq!(
+            vm.mem,
+            [0x5a, 0xe0, 0xf0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]
+        );
+
+        vm.load(&[0x11; 16]).unwrap();
+        assert_eq!(vm.mem, [0x11; 16]);
+    }
+
+    #[test]
+    fn test_vm_undefined_nop() {
+        let mut vm = EaterVm::new();
+
+        vm.mem[0] = 0x9a; // Undefined
+        vm.mem[1] = 0x5a; // LDI 10
+
+        assert_eq!(vm.step_instruction(), Ok(false));
+        assert_eq!(vm.pc, 1);
+        assert_eq!(vm.step_instruction(), Ok(false));
+        assert_eq!(vm.a, 10);
+    }
+
+    #[test]
+    fn test_vm_undefined_trap() {
+        let mut vm = EaterVm::new();
+        vm.set_opcode_policy(OpcodePolicy::Trap);
+
+        vm.mem[0] = 0x00; // NOP
+        vm.mem[1] = 0xd3; // Undefined
+
+        let err = EaterError::UndefinedOpcode {
+            addr: 1,
+            inst: 0xd3,
+        };
+        assert_eq!(vm.run(), Err(err.clone()));
+        assert!(vm.halt);
+        assert_eq!(vm.pc, 2);
+
+        // The fault sticks until reset
+        assert_eq!(vm.step_instruction(), Err(err));
+        vm.reset();
+        assert_eq!(vm.step_instruction(), Ok(false));
+    }
+
+    #[test]
+    fn test_vm_undefined_halt() {
+        let mut vm = EaterVm::new();
+        vm.set_opcode_policy(OpcodePolicy::Halt);
+
+        vm.mem[0] = 0xa0; // Undefined
+
+        assert_eq!(vm.step_instruction(), Ok(true));
+        assert_eq!(vm.pc, 1);
+    }
+
+    #[test]
+    fn test_vm_snapshot() {
+        let mut vm = EaterVm::with_output(Vec::new());
+        vm.load(include_bytes!("example.bin")).unwrap();
+        for _ in 0..40 {
+            vm.step_instruction().unwrap();
+        }
+
+        let snapshot = vm.snapshot();
+        let start = vm.output().len();
+        vm.run().unwrap();
+        let (output, cycles) = (vm.output()[start..].to_vec(), vm.cycles());
+
+        vm.reset();
+        vm.poke(0, 0xf0);
+        vm.restore(&snapshot);
+        vm.output_mut().truncate(start);
+        vm.run().unwrap();
+        assert_eq!(vm.output()[start..], output[..]);
+        assert_eq!(vm.cycles(), cycles);
+    }
+
+    #[test]
+    fn test_vm_ram256() {
+        let mut vm = EaterVm::with_output(Vec::new());
+        vm.set_variant(Variant::Ram256);
+        assert_eq!(vm.mem.len(), 256);
+
+        let program = [
+            0x50, 200, // LDI 200
+            0x20, 0x80, // ADD 0x80
+            0x70, 0x08, // JC 8
+            0xf0, // HLT
+            0x00, // NOP
+            0xe0, // OUT
+            0x40, 0xff, // STA 0xff
+            0xf0, // HLT
+        ];
+        vm.load_padded(&program).unwrap();
+        vm.poke(0x80, 100);
+
+        for _ in 0..3 {
+            vm.step_instruction().unwrap();
+        }
+        let snapshot = vm.snapshot();
+
+        vm.run().unwrap();
+        assert_eq!(vm.output(), &[44]);
+        assert_eq!((vm.pc, vm.a, vm.cycles), (12, 44, 6));
+        assert_eq!(vm.mem[0xff], 44);
+
+        // Snapshots carry the variant along with the larger memory
+        let mut vm = EaterVm::with_output(Vec::new());
+        vm.restore(&snapshot);
+        assert_eq!(vm.variant(), Variant::Ram256);
+        assert_eq!(vm.pc, 8);
+        vm.run().unwrap();
+        assert_eq!(vm.mem[0xff], 44);
+    }
+
+    #[test]
+    fn test_vm_step_back() {
+        let mut vm = EaterVm::with_output(Vec::new());
+
+        vm.load(&COUNTER).unwrap();
+        vm.set_history_limit(100);
+
+        let mut snapshots = vec![vm.snapshot()];
+        for _ in 0..40 {
+            vm.step_instruction().unwrap();
+            snapshots.push(vm.snapshot());
+        }
+        assert_eq!(vm.history_len(), 40);
+        assert_eq!(vm.output().len(), 7);
+
+        // Find the OUT that showed 3
+        assert!(vm.run_back_to(|vm| vm.mem()[vm.pc() as usize] == 0xe0 && vm.a() == 3));
+        assert_eq!(vm.snapshot(), snapshots[15]);
+        assert_eq!(vm.mem()[15], 3);
+
+        while vm.step_back() {}
+        assert_eq!(vm.snapshot(), snapshots[0]);
+        assert!(!vm.run_back_to(|_| true));
+
+        // The oldest steps are dropped beyond the limit, and poking clears the log
+        vm.set_history_limit(4);
+        vm.run_back_to(|_| false);
+        for _ in 0..10 {
+            vm.step_instruction().unwrap();
+        }
+        assert_eq!(vm.history_len(), 4);
+        vm.poke(15, 0);
+        assert!(!vm.step_back());
+    }
+
+    #[test]
+    fn test_vm_alu_conformance() {
+        for (a, b, subtract, result, flags) in conformance_cases() {
+            let op = if subtract { 0x3f } else { 0x2f }; // SUB 15 or ADD 15
+
+            // LDA 14, ADD/SUB 15, STA 13, LDI 0, HLT
+            let program = [0x1e, op, 0x4d, 0x50, 0xf0, 0, 0, 0, 0, 0, 0, 0, 0, 0, a, b];
+
+            let mut vm = EaterVm::new();
+            vm.load(&program).unwrap();
+            vm.run().unwrap();
+
+            // Flags are only latched by ADD and SUB, so loading zero leaves them untouched
+            let case = (a, b, subtract);
+            assert_eq!(vm.mem[13], result, "{:x?}", case);
+            assert_eq!(vm.flags, flags, "{:x?}", case);
+            assert_eq!(vm.a, 0);
+        }
+    }
 }
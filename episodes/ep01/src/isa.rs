@@ -0,0 +1,314 @@
+//! The instruction set, described once.
+//!
+//! Every backend decodes through `INSTRUCTIONS`: the interpreters dispatch on `Op`, the microcode
+//! tables are built from each instruction's control words, and the assembler and disassembler
+//! look up mnemonics and operands here. `ruledef` renders the matching customasm rules. Adding
+//! an instruction like `JNC`, `ADI` or `SUI` only takes a new entry in the table.
+
+use crate::cpu::{Flags, Variant};
+use crate::microcode::{Control, AI, AO, BI, CE, CO, EO, FI, HLT, IO, J, MI, OI, RI, RO, SU};
+use serde::{Deserialize, Serialize};
+use std::fmt::Write;
+
+/// The kind of operand encoded in an instruction's low nibble.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Operand {
+    /// The low nibble is ignored and should be zero.
+    None,
+    /// A memory address.
+    Address,
+    /// An immediate value.
+    Immediate,
+}
+
+/// The flags a jump depends on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Condition {
+    Always,
+    Carry,
+    Zero,
+    NotCarry,
+    NotZero,
+}
+
+impl Condition {
+    /// Whether the jump is taken with these flags.
+    pub fn holds(self, flags: Flags) -> bool {
+        match self {
+            Condition::Always => true,
+            Condition::Carry => flags.contains(Flags::C),
+            Condition::Zero => flags.contains(Flags::Z),
+            Condition::NotCarry => !flags.contains(Flags::C),
+            Condition::NotZero => !flags.contains(Flags::Z),
+        }
+    }
+}
+
+/// What an instruction does when it executes. Operations that read a value take it from memory
+/// for `Operand::Address`, or from the operand itself for `Operand::Immediate`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Op {
+    Nop,
+    /// Load the value into A.
+    Load,
+    /// Add the value to A, setting the flags.
+    Add,
+    /// Subtract the value from A, setting the flags.
+    Sub,
+    /// Store A at the operand address.
+    Store,
+    /// Jump to the operand address when the condition holds.
+    Jump(Condition),
+    Out,
+    Hlt,
+}
+
+impl Op {
+    /// Whether an operand of this kind is the address of the value to read or write.
+    pub fn uses_memory(self, operand: Operand) -> bool {
+        operand == Operand::Address && !matches!(self, Op::Jump(_))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Instruction {
+    pub mnemonic: &'static str,
+    pub opcode: u8,
+    pub operand: Operand,
+    pub op: Op,
+    /// Control words for T2 - T4 on the original build. Jumps only execute these when their
+    /// condition holds.
+    pub microcode: [Control; 3],
+    /// Control words for T2 - T5 on the RAM upgrade, which fetches the operand byte first.
+    /// Jumps that are not taken skip the operand byte instead.
+    pub microcode_ram256: [Control; 4],
+}
+
+impl Instruction {
+    /// Whether the operand is the address of the value to read or write, rather than the value
+    /// itself or a jump target.
+    pub fn uses_memory(&self) -> bool {
+        self.op.uses_memory(self.operand)
+    }
+}
+
+const fn steps<const N: usize>(bits: [u16; N]) -> [Control; N] {
+    let mut steps = [Control::empty(); N];
+    let mut i = 0;
+    while i < N {
+        steps[i] = Control::from_bits_truncate(bits[i]);
+        i += 1;
+    }
+    steps
+}
+
+/// The `eater_8bit` instruction set. The `.asm` rule definitions are checked against this.
+pub const INSTRUCTIONS: &[Instruction] = &[
+    Instruction {
+        mnemonic: "nop",
+        opcode: 0x0,
+        operand: Operand::None,
+        op: Op::Nop,
+        microcode: steps([0, 0, 0]),
+        microcode_ram256: steps([0, 0, 0, 0]),
+    },
+    Instruction {
+        mnemonic: "lda",
+        opcode: 0x1,
+        operand: Operand::Address,
+        op: Op::Load,
+        microcode: steps([IO | MI, RO | AI, 0]),
+        microcode_ram256: steps([CO | MI, RO | MI | CE, RO | AI, 0]),
+    },
+    Instruction {
+        mnemonic: "add",
+        opcode: 0x2,
+        operand: Operand::Address,
+        op: Op::Add,
+        microcode: steps([IO | MI, RO | BI, EO | AI | FI]),
+        microcode_ram256: steps([CO | MI, RO | MI | CE, RO | BI, EO | AI | FI]),
+    },
+    Instruction {
+        mnemonic: "sub",
+        opcode: 0x3,
+        operand: Operand::Address,
+        op: Op::Sub,
+        microcode: steps([IO | MI, RO | BI, EO | AI | SU | FI]),
+        microcode_ram256: steps([CO | MI, RO | MI | CE, RO | BI, EO | AI | SU | FI]),
+    },
+    Instruction {
+        mnemonic: "sta",
+        opcode: 0x4,
+        operand: Operand::Address,
+        op: Op::Store,
+        microcode: steps([IO | MI, AO | RI, 0]),
+        microcode_ram256: steps([CO | MI, RO | MI | CE, AO | RI, 0]),
+    },
+    Instruction {
+        mnemonic: "ldi",
+        opcode: 0x5,
+        operand: Operand::Immediate,
+        op: Op::Load,
+        microcode: steps([IO | AI, 0, 0]),
+        microcode_ram256: steps([CO | MI, RO | AI | CE, 0, 0]),
+    },
+    Instruction {
+        mnemonic: "jmp",
+        opcode: 0x6,
+        operand: Operand::Address,
+        op: Op::Jump(Condition::Always),
+        microcode: steps([IO | J, 0, 0]),
+        microcode_ram256: steps([CO | MI, RO | J, 0, 0]),
+    },
+    Instruction {
+        mnemonic: "jc",
+        opcode: 0x7,
+        operand: Operand::Address,
+        op: Op::Jump(Condition::Carry),
+        microcode: steps([IO | J, 0, 0]),
+        microcode_ram256: steps([CO | MI, RO | J, 0, 0]),
+    },
+    Instruction {
+        mnemonic: "jz",
+        opcode: 0x8,
+        operand: Operand::Address,
+        op: Op::Jump(Condition::Zero),
+        microcode: steps([IO | J, 0, 0]),
+        microcode_ram256: steps([CO | MI, RO | J, 0, 0]),
+    },
+    Instruction {
+        mnemonic: "out",
+        opcode: 0xe,
+        operand: Operand::None,
+        op: Op::Out,
+        microcode: steps([AO | OI, 0, 0]),
+        microcode_ram256: steps([AO | OI, 0, 0, 0]),
+    },
+    Instruction {
+        mnemonic: "hlt",
+        opcode: 0xf,
+        operand: Operand::None,
+        op: Op::Hlt,
+        microcode: steps([HLT, 0, 0]),
+        microcode_ram256: steps([HLT, 0, 0, 0]),
+    },
+];
+
+/// An instruction table, indexed by opcode. The backends decode through `ISA`, but tests can
+/// give them a table with instructions that `eater_8bit` doesn't have.
+#[derive(Debug)]
+pub(crate) struct Isa {
+    instructions: &'static [Instruction],
+    /// Index into `instructions` for each opcode, so decoding doesn't search the table.
+    decode: [Option<usize>; 16],
+}
+
+impl Isa {
+    pub(crate) const fn new(instructions: &'static [Instruction]) -> Self {
+        let mut decode = [None; 16];
+        let mut i = 0;
+        while i < instructions.len() {
+            let opcode = instructions[i].opcode as usize;
+            assert!(decode[opcode].is_none(), "Opcode assigned twice");
+            decode[opcode] = Some(i);
+            i += 1;
+        }
+
+        Self {
+            instructions,
+            decode,
+        }
+    }
+
+    pub(crate) fn instructions(&self) -> &'static [Instruction] {
+        self.instructions
+    }
+
+    /// Find the instruction assigned to an opcode (0 - 15).
+    pub(crate) fn decode(&self, opcode: u8) -> Option<&'static Instruction> {
+        let instructions = self.instructions;
+        self.decode[opcode as usize & 0xf].map(|index| &instructions[index])
+    }
+
+    /// The number of bytes taken by the instruction that starts with this byte.
+    pub(crate) fn instruction_len(&self, variant: Variant, inst: u8) -> usize {
+        let operand = self
+            .decode(inst >> 4)
+            .map_or(Operand::None, |inst| inst.operand);
+        match (variant, operand) {
+            (Variant::Ram256, Operand::Address) | (Variant::Ram256, Operand::Immediate) => 2,
+            _ => 1,
+        }
+    }
+}
+
+/// `INSTRUCTIONS`, indexed by opcode.
+pub(crate) static ISA: Isa = Isa::new(INSTRUCTIONS);
+
+/// Find an instruction by its mnemonic, ignoring case.
+pub fn lookup(mnemonic: &str) -> Option<&'static Instruction> {
+    INSTRUCTIONS
+        .iter()
+        .find(|inst| inst.mnemonic.eq_ignore_ascii_case(mnemonic))
+}
+
+/// Find the instruction assigned to an opcode (0 - 15).
+pub fn decode(opcode: u8) -> Option<&'static Instruction> {
+    ISA.decode(opcode)
+}
+
+/// The name of the customasm rule definitions for a variant.
+pub fn ruledef_name(variant: Variant) -> &'static str {
+    match variant {
+        Variant::Original => "eater_8bit",
+        Variant::Ram256 => "eater_8bit_ram256",
+    }
+}
+
+/// Render the customasm `#ruledef` block for a variant.
+pub fn ruledef(variant: Variant) -> String {
+    let mut rules = format!("#ruledef {} {{\n", ruledef_name(variant));
+
+    for inst in INSTRUCTIONS {
+        let (mnemonic, opcode) = (inst.mnemonic, inst.opcode);
+        match (inst.operand, variant) {
+            (Operand::None, _) => writeln!(rules, "    {} => {:#04x}", mnemonic, opcode << 4),
+            (_, Variant::Original) => {
+                writeln!(rules, "    {} {{mem: u4}} => {:#x} @ mem", mnemonic, opcode)
+            }
+            (_, Variant::Ram256) => {
+                writeln!(
+                    rules,
+                    "    {} {{mem: u8}} => {:#04x} @ mem",
+                    mnemonic,
+                    opcode << 4
+                )
+            }
+        }
+        .unwrap();
+    }
+
+    rules.push_str("}\n");
+    rules
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_isa_ruledef() {
+        assert!(include_str!("eater_8bit.asm").ends_with(&ruledef(Variant::Original)));
+        assert!(include_str!("eater_8bit_ram256.asm").ends_with(&ruledef(Variant::Ram256)));
+    }
+
+    #[test]
+    fn test_isa_decode() {
+        for inst in INSTRUCTIONS {
+            assert_eq!(decode(inst.opcode), Some(inst));
+            assert_eq!(lookup(&inst.mnemonic.to_uppercase()), Some(inst));
+        }
+        assert_eq!(decode(0x9), None);
+        assert_eq!(lookup("jnc"), None);
+    }
+}
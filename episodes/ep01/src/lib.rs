@@ -1,5 +1,38 @@
-pub use interp::EaterVm;
-pub use sim::EaterSim;
+pub use batch::EaterBatch;
+pub use cpu::{Cpu, Flags, OpcodePolicy, Phase, Variant};
+pub use error::EaterError;
+pub use halting::{RunOutcome, RunReport};
+pub use history::Rewind;
+pub use interp::{EaterVm, VmSnapshot};
+pub use memo::EaterMemo;
+pub use microcode::{Control, Microcode};
+pub use output::{Output, Stdout};
+pub use sim::{EaterSim, SimSnapshot};
+pub use threaded::EaterThreaded;
 
+mod alu;
+pub mod asm;
+mod batch;
+pub mod cosim;
+mod cpu;
+pub mod debugger;
+pub mod diff;
+pub mod disasm;
+pub mod eeprom;
+mod error;
+#[cfg(test)]
+mod fixtures;
+mod halting;
+mod history;
+pub mod image;
 mod interp;
+pub mod isa;
+mod memo;
+pub mod microcode;
+mod output;
 mod sim;
+pub mod snapshot;
+mod threaded;
+pub mod trace;
+pub mod validate;
+pub mod vcd;
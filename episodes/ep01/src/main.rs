@@ -1,17 +1,930 @@
-use eater::EaterSim;
+use eater::asm::{self, AsmError};
+use eater::cosim::CoSim;
+use eater::debugger::{self, Debugger, Event, Unit, Watch};
+use eater::diff::Divergence;
+use eater::eeprom;
+use eater::image::{self, Format};
+use eater::microcode::Microcode;
+use eater::trace::{self, BinaryWriter, JsonWriter, Recorder, Sink};
+use eater::validate;
+use eater::vcd::VcdWriter;
+use eater::{
+    disasm, Cpu, EaterError, EaterSim, EaterThreaded, EaterVm, OpcodePolicy, Output, Rewind,
+    Variant,
+};
+use rustyline::error::ReadlineError;
+use rustyline::DefaultEditor;
 use std::env;
-use std::fs;
+use std::error::Error;
+use std::ffi::OsString;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::str::FromStr;
+use std::time::Instant;
 
-fn main() -> Result<(), std::io::Error> {
-    let path = env::args_os().nth(1);
-    if path.is_none() {
-        todo!("Error handling for cli args");
+const USAGE: &str = "\
+Usage: eater [COMMAND] [OPTIONS] <FILE>
+
+Commands:
+  run       Run the program until it halts (default)
+  step      Print the CPU state after every instruction
+  trace     Print the CPU state after every clock tick
+  debug     Debug the program interactively
+  replay    Print a trace recorded with --record
+  asm       Convert a program to a binary image (hex text on stdout without -o)
+  disasm    Disassemble a program
+  eeprom    Write the microcode EEPROM image to FILE, as Intel HEX for .hex files
+  validate  Check the microcode, or the EEPROM dumps given with --rom, for mistakes
+  bench     Time repeated runs of the program
+
+Options:
+  -b, --backend <BACKEND>             CPU backend: interp, sim, threaded (a faster interp),
+                                      or cosim, which checks sim against interp after every
+                                      instruction [default: sim]
+  -f, --format <bin|hex|asm>          Input format [default: detected]
+  -l, --limit <N>                     Stop after N clock ticks (instructions for interp
+                                      and threaded)
+  -d, --display <unsigned|signed|hex> How to print output values [default: unsigned]
+  -u, --undefined <nop|trap|halt>     What to do on undefined opcodes [default: nop]
+  -m, --microcode                     Drive the simulator from the microcode ROM
+      --rom <LEFT>,<RIGHT>            Drive the simulator from dumps of the two microcode
+                                      EEPROMs (Intel HEX for .hex files), implying --microcode
+      --ram256                        Model the 256-byte RAM upgrade (not for threaded),
+                                      which assembly selects with its #ruledef
+  -o, --output <FILE>                 Where `asm` writes the binary image
+  -r, --record <FILE>                 Record a trace; JSON Lines for .json and .jsonl files,
+                                      otherwise binary
+      --vcd <FILE>                    Dump the simulator's signals to a VCD file
+      --period <NS>                   Clock period in the VCD file [default: 1000]
+  -n, --iterations <N>                Number of runs for `bench` [default: 1000]
+  -h, --help                          Print this help
+
+Exit codes:
+  0  The program halted, or `validate` found no mistakes
+  1  A file could not be read, assembled or written
+  2  Invalid command line
+  3  An undefined opcode was trapped
+  4  The limit was reached before the program halted
+  5  The co-simulated backends diverged
+  6  `validate` found mistakes in the microcode";
+
+const EXIT_FAILURE: u8 = 1;
+const EXIT_USAGE: u8 = 2;
+const EXIT_FAULT: u8 = 3;
+const EXIT_LIMIT: u8 = 4;
+const EXIT_DIVERGED: u8 = 5;
+const EXIT_INVALID: u8 = 6;
+
+/// Memory size of the programs accepted by `disasm`.
+const MEM_SIZE: usize = 16;
+
+/// Steps the debugger can undo.
+const HISTORY_LIMIT: usize = 1 << 20;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Command {
+    Run,
+    Step,
+    Trace,
+    Debug,
+    Replay,
+    Asm,
+    Disasm,
+    Eeprom,
+    Validate,
+    Bench,
+    Help,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Backend {
+    Interp,
+    Sim,
+    Threaded,
+    Cosim,
+}
+
+/// How output values are printed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Display {
+    Unsigned,
+    Signed,
+    Hex,
+}
+
+#[derive(Debug)]
+struct Options {
+    command: Command,
+    path: PathBuf,
+    backend: Backend,
+    format: Option<Format>,
+    limit: Option<u64>,
+    display: Display,
+    policy: OpcodePolicy,
+    output: Option<PathBuf>,
+    record: Option<PathBuf>,
+    microcode: bool,
+    rom: Option<(PathBuf, PathBuf)>,
+    variant: Variant,
+    vcd: Option<PathBuf>,
+    period: u64,
+    iterations: u32,
+}
+
+/// Why the CPU stopped running.
+enum Stop {
+    Halted,
+    Limit,
+}
+
+#[derive(Debug)]
+struct UsageError(String);
+
+impl fmt::Display for UsageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl Error for UsageError {}
+
+/// Prints output values as they are latched. When tracing, values are tagged so they stand out
+/// from the CPU state.
+struct Console {
+    display: Display,
+    tagged: bool,
+    quiet: bool,
+}
+
+impl Output for Console {
+    fn out(&mut self, value: u8, time: u64) {
+        if self.quiet {
+            return;
+        }
+
+        let value = match self.display {
+            Display::Unsigned => value.to_string(),
+            Display::Signed => (value as i8).to_string(),
+            Display::Hex => format!("{:#04x}", value),
+        };
+
+        if self.tagged {
+            println!("{:>6} out {}", time, value);
+        } else {
+            println!("{}", value);
+        }
+    }
+}
+
+/// Describes the CPU state on a single line for `step` and `trace`.
+trait Inspect: Cpu {
+    fn describe(&self) -> String {
+        format!(
+            "pc={:x} a={:02x} flags={}",
+            self.pc(),
+            self.a(),
+            self.flags()
+        )
+    }
+}
+
+impl<O: Output> Inspect for EaterVm<O> {}
+
+impl<O: Output> Inspect for EaterThreaded<O> {}
+
+impl<R: Inspect, C: Cpu, O: Output> Inspect for CoSim<R, C, O> {
+    fn describe(&self) -> String {
+        self.reference().describe()
+    }
+}
+
+impl<C: Inspect, S: Sink> Inspect for Recorder<C, S> {
+    fn describe(&self) -> String {
+        self.cpu().describe()
+    }
+}
+
+impl<W: Write, O: Output> Inspect for VcdWriter<W, O> {
+    fn describe(&self) -> String {
+        self.sim().describe()
+    }
+}
+
+impl<C: Inspect + ?Sized> Inspect for &mut C {
+    fn describe(&self) -> String {
+        (**self).describe()
+    }
+}
+
+impl<O: Output> Inspect for EaterSim<O> {
+    fn describe(&self) -> String {
+        format!(
+            "t{} pc={:x} a={:02x} b={:02x} mar={:x} ir={:02x} bus={:02x} flags={}",
+            self.step_counter(),
+            self.pc(),
+            self.a(),
+            self.b(),
+            self.mar(),
+            self.ir(),
+            self.bus(),
+            self.flags()
+        )
+    }
+}
+
+fn parse_value<T: FromStr>(name: &str, value: &str) -> Result<T, UsageError> {
+    value
+        .parse()
+        .map_err(|_| UsageError(format!("Invalid value `{}` for {}", value, name)))
+}
+
+fn choose<T: Copy>(name: &str, value: &str, choices: &[(&str, T)]) -> Result<T, UsageError> {
+    choices
+        .iter()
+        .find(|(choice, _)| *choice == value)
+        .map(|&(_, choice)| choice)
+        .ok_or_else(|| UsageError(format!("Invalid value `{}` for {}", value, name)))
+}
+
+/// Take the program file argument, which may be any OS string.
+fn set_path(path: &mut Option<PathBuf>, arg: OsString) -> Result<(), UsageError> {
+    match path.replace(PathBuf::from(&arg)) {
+        Some(_) => Err(UsageError(format!(
+            "Unexpected argument `{}`",
+            arg.to_string_lossy()
+        ))),
+        None => Ok(()),
+    }
+}
+
+fn parse_args(args: Vec<OsString>) -> Result<Options, UsageError> {
+    let mut args = args.into_iter().peekable();
+
+    let command = match args.peek().and_then(|arg| arg.to_str()) {
+        Some("run") => Command::Run,
+        Some("step") => Command::Step,
+        Some("trace") => Command::Trace,
+        Some("debug") => Command::Debug,
+        Some("replay") => Command::Replay,
+        Some("asm") => Command::Asm,
+        Some("disasm") => Command::Disasm,
+        Some("eeprom") => Command::Eeprom,
+        Some("validate") => Command::Validate,
+        Some("bench") => Command::Bench,
+        Some("help") => Command::Help,
+        // A bare file runs the program
+        _ => Command::Run,
+    };
+    if command != Command::Run || args.peek().and_then(|arg| arg.to_str()) == Some("run") {
+        args.next();
+    }
+
+    let mut options = Options {
+        command,
+        path: PathBuf::new(),
+        backend: Backend::Sim,
+        format: None,
+        limit: None,
+        display: Display::Unsigned,
+        policy: OpcodePolicy::Nop,
+        output: None,
+        record: None,
+        microcode: false,
+        rom: None,
+        variant: Variant::Original,
+        vcd: None,
+        period: 1000,
+        iterations: 1000,
+    };
+    let mut path = None;
+
+    while let Some(arg) = args.next() {
+        let arg = match arg.into_string() {
+            Ok(arg) => arg,
+            Err(arg) if !arg.to_string_lossy().starts_with('-') => {
+                set_path(&mut path, arg)?;
+                continue;
+            }
+            Err(arg) => {
+                return Err(UsageError(format!(
+                    "Invalid option `{}`",
+                    arg.to_string_lossy()
+                )))
+            }
+        };
+        if arg == "-h" || arg == "--help" {
+            options.command = Command::Help;
+            return Ok(options);
+        }
+        if !arg.starts_with('-') {
+            set_path(&mut path, arg.into())?;
+            continue;
+        }
+        if arg == "-m" || arg == "--microcode" {
+            options.microcode = true;
+            continue;
+        }
+        if arg == "--ram256" {
+            options.variant = Variant::Ram256;
+            continue;
+        }
+
+        // Options take a value, either as `--name=value` or as the next argument
+        let (name, value) = match arg.split_once('=') {
+            Some((name, value)) => (name.to_string(), value.to_string()),
+            None => {
+                let value = args
+                    .next()
+                    .ok_or_else(|| UsageError(format!("Missing value for {}", arg)))?
+                    .into_string()
+                    .map_err(|value| {
+                        UsageError(format!(
+                            "Invalid value `{}` for {}",
+                            value.to_string_lossy(),
+                            arg
+                        ))
+                    })?;
+                (arg, value)
+            }
+        };
+
+        match name.as_str() {
+            "-b" | "--backend" => {
+                options.backend = choose(
+                    &name,
+                    &value,
+                    &[
+                        ("interp", Backend::Interp),
+                        ("sim", Backend::Sim),
+                        ("threaded", Backend::Threaded),
+                        ("cosim", Backend::Cosim),
+                    ],
+                )?
+            }
+            "-f" | "--format" => options.format = Some(parse_value(&name, &value)?),
+            "-l" | "--limit" => options.limit = Some(parse_value(&name, &value)?),
+            "-d" | "--display" => {
+                options.display = choose(
+                    &name,
+                    &value,
+                    &[
+                        ("unsigned", Display::Unsigned),
+                        ("signed", Display::Signed),
+                        ("hex", Display::Hex),
+                    ],
+                )?
+            }
+            "-u" | "--undefined" => {
+                options.policy = choose(
+                    &name,
+                    &value,
+                    &[
+                        ("nop", OpcodePolicy::Nop),
+                        ("trap", OpcodePolicy::Trap),
+                        ("halt", OpcodePolicy::Halt),
+                    ],
+                )?
+            }
+            "-o" | "--output" => options.output = Some(PathBuf::from(value)),
+            "-r" | "--record" => options.record = Some(PathBuf::from(value)),
+            "--rom" => {
+                let (left, right) = value.split_once(',').ok_or_else(|| {
+                    UsageError("--rom takes the left and right dumps as LEFT,RIGHT".to_string())
+                })?;
+                options.rom = Some((PathBuf::from(left), PathBuf::from(right)));
+            }
+            "--vcd" => options.vcd = Some(PathBuf::from(value)),
+            "--period" => options.period = parse_value(&name, &value)?,
+            "-n" | "--iterations" => options.iterations = parse_value(&name, &value)?,
+            _ => return Err(UsageError(format!("Unknown option `{}`", name))),
+        }
+    }
+
+    if options.rom.is_some() {
+        if matches!(options.backend, Backend::Interp | Backend::Threaded) {
+            return Err(UsageError("--rom requires the simulator".to_string()));
+        }
+        options.microcode = true;
+    }
+    if matches!(options.backend, Backend::Interp | Backend::Threaded) && options.microcode {
+        return Err(UsageError("--microcode requires the simulator".to_string()));
+    }
+    if options.variant == Variant::Ram256
+        && (options.backend == Backend::Threaded || options.command == Command::Disasm)
+    {
+        return Err(UsageError(
+            "--ram256 is not supported by the threaded backend or disasm".to_string(),
+        ));
+    }
+    if options.backend != Backend::Sim && options.vcd.is_some() {
+        return Err(UsageError("--vcd requires the simulator".to_string()));
+    }
+    if matches!(options.backend, Backend::Threaded | Backend::Cosim)
+        && options.command == Command::Debug
+    {
+        return Err(UsageError(
+            "The debugger requires the interp or sim backend".to_string(),
+        ));
     }
-    let path = path.unwrap();
+    if options.period < 2 {
+        return Err(UsageError("--period must be at least 2".to_string()));
+    }
+    if !matches!(options.command, Command::Help | Command::Validate) {
+        options.path = path.ok_or_else(|| UsageError("Missing program file".to_string()))?;
+    }
+
+    Ok(options)
+}
+
+/// Read a program image, assembling it or parsing hex text when needed. Assembly source also
+/// tells which build of the machine it is written for.
+fn load_image(
+    path: &Path,
+    format: Option<Format>,
+) -> Result<(Vec<u8>, Option<Variant>), Box<dyn Error>> {
+    let data = fs::read(path).map_err(|err| format!("{}: {}", path.display(), err))?;
+
+    let image = match format.unwrap_or_else(|| Format::detect(Some(path), &data)) {
+        Format::Binary => (data, None),
+        Format::Hex => {
+            let text =
+                String::from_utf8(data).map_err(|err| format!("{}: {}", path.display(), err))?;
+            let image =
+                image::parse_hex(&text).map_err(|err| format!("{}: {}", path.display(), err))?;
+            (image, None)
+        }
+        Format::Asm => {
+            let program = asm::assemble_file(path)?;
+            (program.image, Some(program.variant))
+        }
+    };
+
+    Ok(image)
+}
+
+/// The microcode for the machine, read from the EEPROM dumps given with `--rom` if there are any.
+fn load_microcode(options: &Options) -> Result<Microcode, Box<dyn Error>> {
+    match &options.rom {
+        Some((left, right)) => {
+            let read = |path: &Path| -> Result<Vec<u8>, String> {
+                let data = fs::read(path).map_err(|err| format!("{}: {}", path.display(), err))?;
+                eeprom::parse_dump(path, &data)
+                    .map_err(|err| format!("{}: {}", path.display(), err))
+            };
+            let (left, right) = (read(left)?, read(right)?);
+            Ok(eeprom::microcode(&left, &right, options.variant.steps())?)
+        }
+        None => Ok(Microcode::for_variant(options.variant)),
+    }
+}
+
+/// Run the CPU until it halts or reaches the limit, printing its state for `step` and `trace`.
+fn execute<C: Inspect>(cpu: &mut C, options: &Options) -> Result<Stop, EaterError> {
+    loop {
+        if cpu.halted() {
+            return Ok(Stop::Halted);
+        }
+        if options.limit.is_some_and(|limit| cpu.cycles() >= limit) {
+            return Ok(Stop::Limit);
+        }
+
+        match options.command {
+            Command::Step => {
+                cpu.step_instruction()?;
+                println!("{:>6} {}", cpu.cycles(), cpu.describe());
+            }
+            Command::Trace => {
+                cpu.step_clock()?;
+                println!("{:>6} {}", cpu.cycles(), cpu.describe());
+            }
+            _ => {
+                cpu.step_clock()?;
+            }
+        }
+    }
+}
+
+/// Run the CPU while streaming its trace to a file, as JSON Lines for `.json` and `.jsonl` files,
+/// otherwise in the binary format.
+fn record<C: Inspect>(cpu: C, path: &Path, options: &Options) -> Result<Stop, Box<dyn Error>> {
+    let file = File::create(path).map_err(|err| format!("{}: {}", path.display(), err))?;
+    let writer = BufWriter::new(file);
+
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("json") | Some("jsonl") => record_to(cpu, JsonWriter::new(writer), path, options),
+        _ => {
+            let sink =
+                BinaryWriter::new(writer).map_err(|err| format!("{}: {}", path.display(), err))?;
+            record_to(cpu, sink, path, options)
+        }
+    }
+}
+
+fn record_to<C: Inspect, S: Sink>(
+    cpu: C,
+    sink: S,
+    path: &Path,
+    options: &Options,
+) -> Result<Stop, Box<dyn Error>> {
+    // Keep the trace leading up to a fault
+    let mut recorder = Recorder::with_sink(cpu, sink);
+    let stop = execute(&mut recorder, options);
+    recorder
+        .finish()
+        .map_err(|err| format!("{}: {}", path.display(), err))?;
+
+    Ok(stop?)
+}
+
+/// Load the program into a CPU and run it, or benchmark it.
+fn simulate<C: Inspect>(
+    mut cpu: C,
+    image: &[u8],
+    options: &Options,
+) -> Result<Stop, Box<dyn Error>> {
+    cpu.set_opcode_policy(options.policy);
+    cpu.load_padded(image)?;
+
+    if options.command != Command::Bench {
+        return match &options.record {
+            Some(path) => record(cpu, path, options),
+            None => Ok(execute(&mut cpu, options)?),
+        };
+    }
+
+    let start = Instant::now();
+    let mut cycles = 0;
+    for _ in 0..options.iterations {
+        cpu.load_padded(image)?;
+        cpu.reset();
+        execute(&mut cpu, options)?;
+        cycles += cpu.cycles();
+    }
+    let elapsed = start.elapsed();
+
+    let runs = options.iterations.max(1) as f64;
+    println!(
+        "{} runs, {} cycles each, {:.3?} total, {:.1} ns/run, {:.2} Mcycles/s",
+        options.iterations,
+        cycles / options.iterations.max(1) as u64,
+        elapsed,
+        elapsed.as_nanos() as f64 / runs,
+        cycles as f64 / elapsed.as_secs_f64().max(f64::EPSILON) / 1e6
+    );
+
+    Ok(Stop::Halted)
+}
+
+/// Check that an address typed at the debugger prompt is in memory.
+fn check_addr<C: Cpu>(cpu: &C, addr: u8) -> Result<u8, String> {
+    match addr as usize {
+        len if len < cpu.mem().len() => Ok(addr),
+        _ => Err(format!("Address {:#x} is outside of memory", addr)),
+    }
+}
+
+/// Execute one debugger command. Returns `false` when the debugger should exit.
+fn debug_command<C: Inspect + Rewind>(
+    debugger: &mut Debugger<C>,
+    command: debugger::Command,
+    image: &[u8],
+) -> Result<bool, Box<dyn Error>> {
+    use debugger::Command as Cmd;
+
+    let event = match command {
+        Cmd::Step(count) => debugger.run(Unit::Instruction, Some(count)),
+        Cmd::Tick(count) => debugger.run(Unit::Clock, Some(count)),
+        Cmd::Continue(count) => debugger.run(Unit::Instruction, count),
+        Cmd::Back(count) => Ok(debugger.run_back(Some(count))),
+        Cmd::ReverseContinue(count) => Ok(debugger.run_back(count)),
+        Cmd::Break(addr) => {
+            debugger.add_breakpoint(check_addr(debugger.cpu(), addr)?);
+            return Ok(true);
+        }
+        Cmd::Delete(addr) => {
+            if !debugger.remove_breakpoint(addr) {
+                println!("No breakpoint at {:#x}", addr);
+            }
+            return Ok(true);
+        }
+        Cmd::Watch(watch) => {
+            if let Watch::Mem(addr) = watch {
+                check_addr(debugger.cpu(), addr)?;
+            }
+            debugger.add_watch(watch);
+            return Ok(true);
+        }
+        Cmd::Unwatch(watch) => {
+            if !debugger.remove_watch(watch) {
+                println!("Not watching {}", watch);
+            }
+            return Ok(true);
+        }
+        Cmd::Info => {
+            let breakpoints: Vec<_> = debugger
+                .breakpoints()
+                .map(|addr| format!("{:#x}", addr))
+                .collect();
+            let watches: Vec<_> = debugger.watches().map(|w| w.to_string()).collect();
+            println!("breakpoints: {}", breakpoints.join(", "));
+            println!("watchpoints: {}", watches.join(", "));
+            return Ok(true);
+        }
+        Cmd::Regs => {
+            let cpu = debugger.cpu();
+            let halted = if cpu.halted() { " halted" } else { "" };
+            println!("{:>6} {}{}", cpu.cycles(), cpu.describe(), halted);
+            return Ok(true);
+        }
+        Cmd::Mem => {
+            let cpu = debugger.cpu();
+            for (addr, &byte) in cpu.mem().iter().enumerate() {
+                let marker = if addr == cpu.pc() as usize { '>' } else { ' ' };
+                let inst = disasm::format_byte(byte);
+                println!("{} {:#04x}: {:02x}  {}", marker, addr, byte, inst);
+            }
+            return Ok(true);
+        }
+        Cmd::Poke(addr, value) => {
+            let addr = check_addr(debugger.cpu(), addr)?;
+            debugger.cpu_mut().poke(addr, value);
+            return Ok(true);
+        }
+        Cmd::Reset => {
+            debugger.cpu_mut().reset();
+            Ok(Event::Done)
+        }
+        Cmd::Reload => {
+            debugger.cpu_mut().load_padded(image)?;
+            debugger.cpu_mut().reset();
+            Ok(Event::Done)
+        }
+        Cmd::Help => {
+            println!("{}", debugger::HELP);
+            return Ok(true);
+        }
+        Cmd::Quit => return Ok(false),
+    };
+
+    match event? {
+        Event::Done => (),
+        Event::Halted => println!("Halted"),
+        Event::Breakpoint(addr) => println!("Breakpoint at {:#x}", addr),
+        Event::Start => println!("Reached the start of the history"),
+        Event::Watch { watch, old, new } => {
+            println!("{} changed from {:#04x} to {:#04x}", watch, old, new)
+        }
+    }
+    let cpu = debugger.cpu();
+    println!("{:>6} {}", cpu.cycles(), cpu.describe());
+
+    Ok(true)
+}
+
+/// Run the interactive debugger until the user quits.
+fn debug<C>(cpu: C, image: &[u8], options: &Options) -> Result<(), Box<dyn Error>>
+where
+    C: Inspect + Rewind,
+{
+    let mut debugger = Debugger::new(cpu);
+    debugger.cpu_mut().set_history_limit(HISTORY_LIMIT);
+    debugger.cpu_mut().set_opcode_policy(options.policy);
+    debugger.cpu_mut().load_padded(image)?;
+
+    let mut editor = DefaultEditor::new()?;
+    let mut last = None;
+
+    println!("Type `help` for a list of commands");
+    loop {
+        let line = match editor.readline("(eater) ") {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => return Ok(()),
+            Err(err) => return Err(err.into()),
+        };
+
+        let command = if line.trim().is_empty() {
+            match last {
+                Some(command) => command,
+                None => continue,
+            }
+        } else {
+            editor.add_history_entry(line.as_str())?;
+            match line.parse() {
+                Ok(command) => command,
+                Err(err) => {
+                    println!("error: {}", err);
+                    continue;
+                }
+            }
+        };
+        last = Some(command);
+
+        match debug_command(&mut debugger, command, image) {
+            Ok(true) => (),
+            Ok(false) => return Ok(()),
+            Err(err) => println!("error: {}", err),
+        }
+    }
+}
+
+fn cli(args: Vec<OsString>) -> Result<u8, Box<dyn Error>> {
+    let mut options = parse_args(args)?;
+    if options.command == Command::Help {
+        println!("{}", USAGE);
+        return Ok(0);
+    }
+
+    if options.command == Command::Validate {
+        let diagnostics = validate::validate(&load_microcode(&options)?);
+        for diagnostic in &diagnostics {
+            println!("{}", diagnostic);
+        }
+        return Ok(if diagnostics.is_empty() {
+            0
+        } else {
+            EXIT_INVALID
+        });
+    }
+
+    if options.command == Command::Eeprom {
+        let path = &options.path;
+        let image = eeprom::image(&load_microcode(&options)?);
+        let data = match eeprom::is_ihex(path) {
+            true => image::format_ihex(&image).into_bytes(),
+            false => image,
+        };
+        fs::write(path, data).map_err(|err| format!("{}: {}", path.display(), err))?;
+        return Ok(0);
+    }
+
+    if options.command == Command::Replay {
+        let path = &options.path;
+        let file = File::open(path).map_err(|err| format!("{}: {}", path.display(), err))?;
+        let records = trace::read(BufReader::new(file))
+            .map_err(|err| format!("{}: {}", path.display(), err))?;
+        for record in records {
+            println!("{}", record);
+        }
+        return Ok(0);
+    }
+
+    let (image, assembled) = load_image(&options.path, options.format)?;
+
+    // Assembly source picks the build with its `#ruledef`
+    match assembled {
+        Some(Variant::Original) if options.variant == Variant::Ram256 => {
+            return Err(UsageError(format!(
+                "{} is written for the 16-byte build, but --ram256 was given",
+                options.path.display()
+            ))
+            .into());
+        }
+        Some(Variant::Ram256)
+            if options.backend == Backend::Threaded || options.command == Command::Disasm =>
+        {
+            return Err(UsageError(format!(
+                "{} is written for the 256-byte RAM upgrade, which is not supported by the \
+                 threaded backend or disasm",
+                options.path.display()
+            ))
+            .into());
+        }
+        Some(variant) => options.variant = variant,
+        None => (),
+    }
+    let microcode = load_microcode(&options)?;
+
+    match options.command {
+        Command::Asm => {
+            match &options.output {
+                Some(path) => {
+                    fs::write(path, &image).map_err(|err| format!("{}: {}", path.display(), err))?
+                }
+                None => print!("{}", image::format_hex(&image)),
+            }
+            return Ok(0);
+        }
+        Command::Disasm => {
+            if image.len() > MEM_SIZE {
+                return Err(EaterError::ImageTooLarge {
+                    len: image.len(),
+                    size: MEM_SIZE,
+                }
+                .into());
+            }
+
+            let mut image = image;
+            image.resize(MEM_SIZE, 0);
+            print!("{}", disasm::disassemble(&image));
+            return Ok(0);
+        }
+        _ => (),
+    }
+
+    let console = Console {
+        display: options.display,
+        tagged: matches!(
+            options.command,
+            Command::Step | Command::Trace | Command::Debug
+        ),
+        quiet: options.command == Command::Bench,
+    };
+    let sim = |console| {
+        let mut sim = EaterSim::with_output(console);
+        sim.set_variant(options.variant);
+        if options.microcode {
+            sim.set_microcode(Some(microcode.clone()));
+        }
+        sim
+    };
+    let interp = |console| {
+        let mut vm = EaterVm::with_output(console);
+        vm.set_variant(options.variant);
+        vm
+    };
+
+    if options.command == Command::Debug {
+        match options.backend {
+            Backend::Interp => debug(interp(console), &image, &options)?,
+            Backend::Sim => debug(sim(console), &image, &options)?,
+            Backend::Threaded | Backend::Cosim => unreachable!("Rejected by parse_args"),
+        }
+        return Ok(0);
+    }
+
+    let stop = match (options.backend, &options.vcd) {
+        (Backend::Interp, _) => simulate(interp(console), &image, &options)?,
+        (Backend::Threaded, _) => simulate(EaterThreaded::with_output(console), &image, &options)?,
+        (Backend::Sim, Some(path)) => {
+            let file = File::create(path).map_err(|err| format!("{}: {}", path.display(), err))?;
+            let mut vcd = VcdWriter::new(sim(console), BufWriter::new(file), options.period)?;
+            let stop = simulate(&mut vcd, &image, &options);
+            vcd.finish()?;
+            stop?
+        }
+        (Backend::Sim, None) => simulate(sim(console), &image, &options)?,
+        (Backend::Cosim, _) => {
+            let reference = |probe| {
+                let mut vm = EaterVm::with_output(probe);
+                vm.set_variant(options.variant);
+                vm
+            };
+            let candidate = |probe| {
+                let mut sim = EaterSim::with_output(probe);
+                sim.set_variant(options.variant);
+                if options.microcode {
+                    sim.set_microcode(Some(microcode.clone()));
+                }
+                sim
+            };
+            let mut cosim = CoSim::with_backends(reference, candidate, console);
+            let stop = simulate(&mut cosim, &image, &options);
+            if let Some(divergence) = cosim.divergence() {
+                return Err(divergence.clone().into());
+            }
+            stop?
+        }
+    };
+
+    match stop {
+        Stop::Halted => Ok(0),
+        Stop::Limit => {
+            eprintln!(
+                "Limit of {} cycles reached",
+                options.limit.unwrap_or_default()
+            );
+            Ok(EXIT_LIMIT)
+        }
+    }
+}
 
-    let mut sim = EaterSim::new();
-    sim.load(&fs::read(path)?);
-    sim.run();
+fn main() -> ExitCode {
+    let code = match cli(env::args_os().skip(1).collect()) {
+        Ok(code) => code,
+        Err(err) if err.is::<UsageError>() => {
+            eprintln!("error: {}\n\n{}", err, USAGE);
+            EXIT_USAGE
+        }
+        Err(err) if err.is::<AsmError>() => {
+            eprintln!("{}", err);
+            EXIT_FAILURE
+        }
+        Err(err) if err.is::<Divergence>() => {
+            eprintln!("error: {}", err);
+            EXIT_DIVERGED
+        }
+        Err(err) => {
+            eprintln!("error: {}", err);
+            match err.downcast_ref::<EaterError>() {
+                Some(EaterError::UndefinedOpcode { .. }) => EXIT_FAULT,
+                _ => EXIT_FAILURE,
+            }
+        }
+    };
 
-    Ok(())
+    ExitCode::from(code)
 }
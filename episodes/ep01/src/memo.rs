@@ -0,0 +1,349 @@
+//! An executor that memoizes whole-machine state transitions.
+//!
+//! Memory and registers fit in 19 bytes and the machine has no inputs, so a run of instructions
+//! from a given state always ends in the same state with the same outputs. Programs that revisit
+//! states, like loops that run forever or programs that are run over and over, only need to be
+//! interpreted once.
+
+use crate::cpu::{Cpu, Flags, OpcodePolicy, Phase};
+use crate::error::EaterError;
+use crate::interp::EaterVm;
+use crate::output::{Output, Stdout};
+use std::collections::HashMap;
+
+/// The most instructions a cached window executes.
+const WINDOW_LEN: u64 = 64;
+
+/// The most windows kept in the cache before it is cleared.
+const CACHE_LEN: usize = 1 << 16;
+
+/// Everything that decides what the next instructions do.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+struct State {
+    mem: [u8; 16],
+    pc: u8,
+    a: u8,
+    flags: Flags,
+}
+
+/// The effect of running a window of instructions from a `State`.
+#[derive(Clone, Debug)]
+struct Window {
+    end: State,
+    ir: u8,
+    halt: bool,
+    fault: Option<EaterError>,
+    instructions: u64,
+    /// Output values, with the number of instructions executed before each one.
+    outputs: Vec<(u8, u64)>,
+}
+
+/// Collects timestamped output from the fallback interpreter.
+#[derive(Debug, Default)]
+struct Timed(Vec<(u8, u64)>);
+
+impl Output for Timed {
+    fn out(&mut self, value: u8, time: u64) {
+        self.0.push((value, time));
+    }
+}
+
+/// Runs programs like `EaterVm`, caching the result of each window of instructions by the state
+/// it started from.
+///
+/// These are not basic blocks: every window is a fixed 64 instructions, ending early only when the
+/// CPU halts or faults, and starts wherever the previous one ended. Since the starting state
+/// determines everything, windows don't need to end at jumps. A program that is run again from
+/// the same state replays a handful of cached windows instead of executing every instruction, but
+/// a single run only hits the cache once a window starts from a state seen before.
+/// Cache misses are executed by an `EaterVm`. The cache is kept when loading or resetting, and is
+/// only cleared when it fills up or the opcode policy changes.
+///
+/// Only `run` uses the cache; stepping executes single instructions.
+#[derive(Debug)]
+pub struct EaterMemo<O = Stdout> {
+    state: State,
+    ir: u8,
+    halt: bool,
+    fault: Option<EaterError>,
+    policy: OpcodePolicy,
+    cycles: u64,
+    cache: HashMap<State, Window>,
+    fallback: EaterVm<Timed>,
+    hits: u64,
+    misses: u64,
+    output: O,
+}
+
+impl EaterMemo {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Default for EaterMemo {
+    fn default() -> Self {
+        Self::with_output(Stdout)
+    }
+}
+
+impl<O: Output> EaterMemo<O> {
+    pub fn with_output(output: O) -> Self {
+        Self {
+            state: State {
+                mem: [0; 16],
+                pc: 0,
+                a: 0,
+                flags: Flags::CLEAR,
+            },
+            ir: 0,
+            halt: false,
+            fault: None,
+            policy: OpcodePolicy::Nop,
+            cycles: 0,
+            cache: HashMap::new(),
+            fallback: EaterVm::with_output(Timed::default()),
+            hits: 0,
+            misses: 0,
+            output,
+        }
+    }
+
+    pub fn output(&self) -> &O {
+        &self.output
+    }
+
+    pub fn output_mut(&mut self) -> &mut O {
+        &mut self.output
+    }
+
+    pub fn into_output(self) -> O {
+        self.output
+    }
+
+    /// The number of windows `run` found in the cache and had to execute, respectively.
+    pub fn stats(&self) -> (u64, u64) {
+        (self.hits, self.misses)
+    }
+
+    /// Execute up to `limit` instructions from the current state on the fallback interpreter.
+    fn execute(&mut self, limit: u64) -> Window {
+        let vm = &mut self.fallback;
+        vm.load(&self.state.mem).expect("Memory sizes match");
+        vm.reset();
+        vm.set_opcode_policy(self.policy);
+        vm.set_registers(self.state.pc, self.state.a, self.state.flags);
+        vm.output_mut().0.clear();
+
+        let mut fault = None;
+        while vm.cycles() < limit {
+            match vm.step_instruction() {
+                Ok(false) => (),
+                Ok(true) => break,
+                Err(err) => {
+                    fault = Some(err);
+                    break;
+                }
+            }
+        }
+
+        let mut mem = [0; 16];
+        mem.copy_from_slice(vm.mem());
+        Window {
+            end: State {
+                mem,
+                pc: vm.pc(),
+                a: vm.a(),
+                flags: vm.flags(),
+            },
+            ir: vm.ir(),
+            halt: vm.halted(),
+            fault,
+            instructions: vm.cycles(),
+            outputs: std::mem::take(&mut vm.output_mut().0),
+        }
+    }
+
+    fn apply(&mut self, window: &Window) {
+        for &(value, time) in &window.outputs {
+            self.output.out(value, self.cycles + time);
+        }
+
+        self.state = window.end;
+        self.ir = window.ir;
+        self.halt = window.halt;
+        self.fault = window.fault.clone();
+        self.cycles += window.instructions;
+    }
+
+    fn status(&self) -> Result<bool, EaterError> {
+        match &self.fault {
+            Some(fault) => Err(fault.clone()),
+            None => Ok(self.halt),
+        }
+    }
+}
+
+impl<O: Output> Cpu for EaterMemo<O> {
+    fn load(&mut self, mem: &[u8]) -> Result<(), EaterError> {
+        EaterError::check_image(mem.len(), self.state.mem.len())?;
+        self.state.mem.copy_from_slice(mem);
+
+        Ok(())
+    }
+
+    fn reset(&mut self) {
+        self.state.pc = 0;
+        self.state.a = 0;
+        self.state.flags = Flags::CLEAR;
+        self.ir = 0;
+        self.halt = false;
+        self.fault = None;
+        self.cycles = 0;
+    }
+
+    fn set_opcode_policy(&mut self, policy: OpcodePolicy) {
+        if policy != self.policy {
+            self.cache.clear();
+        }
+        self.policy = policy;
+    }
+
+    fn poke(&mut self, addr: u8, value: u8) {
+        let len = self.state.mem.len();
+        self.state.mem[addr as usize % len] = value;
+    }
+
+    fn step_instruction(&mut self) -> Result<bool, EaterError> {
+        if !self.halt {
+            let window = self.execute(1);
+            self.apply(&window);
+        }
+
+        self.status()
+    }
+
+    fn step_clock(&mut self) -> Result<bool, EaterError> {
+        self.step_instruction()
+    }
+
+    fn run(&mut self) -> Result<(), EaterError> {
+        while !self.halt {
+            let start = self.state;
+            let window = match self.cache.remove(&start) {
+                Some(window) => {
+                    self.hits += 1;
+                    window
+                }
+                None => {
+                    self.misses += 1;
+                    if self.cache.len() >= CACHE_LEN {
+                        self.cache.clear();
+                    }
+                    self.execute(WINDOW_LEN)
+                }
+            };
+
+            self.apply(&window);
+            self.cache.insert(start, window);
+        }
+
+        self.status().map(|_| ())
+    }
+
+    fn pc(&self) -> u8 {
+        self.state.pc
+    }
+
+    fn a(&self) -> u8 {
+        self.state.a
+    }
+
+    fn ir(&self) -> u8 {
+        self.ir
+    }
+
+    fn phase(&self) -> Phase {
+        Phase::Instruction
+    }
+
+    fn flags(&self) -> Flags {
+        self.state.flags
+    }
+
+    fn mem(&self) -> &[u8] {
+        &self.state.mem
+    }
+
+    fn halted(&self) -> bool {
+        self.halt
+    }
+
+    fn cycles(&self) -> u64 {
+        self.cycles
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::diff::compare;
+    use crate::fixtures::{EXAMPLE, SELF_MODIFYING};
+    use std::sync::mpsc;
+
+    /// Run a program to completion on the memoizing executor and on `EaterVm`.
+    fn run_both(memo: &mut EaterMemo<Vec<u8>>, program: &[u8; 16]) {
+        let (tx, rx) = mpsc::channel();
+        let mut vm = EaterVm::with_output(tx);
+        vm.load(program).unwrap();
+        vm.run().unwrap();
+
+        memo.output_mut().clear();
+        memo.load(program).unwrap();
+        memo.reset();
+        memo.run().unwrap();
+
+        let expected: Vec<_> = rx.try_iter().map(|(value, _)| value).collect();
+        assert_eq!(memo.output(), &expected);
+        assert_eq!(memo.cycles(), vm.cycles());
+        assert_eq!(compare(memo, &vm), []);
+    }
+
+    #[test]
+    fn test_vm_cache_hits() {
+        // Stepping is checked against `EaterVm` in `cosim`; only `run` goes through the cache
+        let mut memo = EaterMemo::with_output(Vec::new());
+        run_both(&mut memo, EXAMPLE);
+        let (hits, misses) = memo.stats();
+        assert!(misses > 0);
+
+        // The second run replays every window from the cache
+        run_both(&mut memo, EXAMPLE);
+        assert_eq!(memo.stats(), (hits + misses, misses));
+
+        // Blocks that rewrite the program are keyed by the memory they started from
+        run_both(&mut memo, &SELF_MODIFYING);
+        assert_eq!(memo.output(), &[0xe0, 0xe0, 0xf0]);
+    }
+
+    #[test]
+    fn test_vm_cached_trap() {
+        let mut memo = EaterMemo::with_output(Vec::new());
+        memo.set_opcode_policy(OpcodePolicy::Trap);
+        memo.poke(1, 0x9a);
+
+        for _ in 0..2 {
+            memo.reset();
+            assert_eq!(
+                memo.run(),
+                Err(EaterError::UndefinedOpcode {
+                    addr: 1,
+                    inst: 0x9a
+                })
+            );
+            assert_eq!(memo.pc(), 2);
+            assert_eq!(memo.cycles(), 2);
+        }
+        assert_eq!(memo.stats(), (1, 1));
+    }
+}
@@ -0,0 +1,275 @@
+use crate::cpu::{Flags, Variant};
+use crate::isa::{self, Isa, Op};
+use bitflags::bitflags;
+use serde::{Deserialize, Serialize};
+
+bitflags! {
+    /// Control lines driven by the microcode EEPROMs. The bit order matches the 16-bit control
+    /// word programmed into the ROMs; the high byte is the left EEPROM.
+    #[derive(Default, Serialize, Deserialize)]
+    #[serde(transparent)]
+    pub struct Control: u16 {
+        const HLT = 0b1000_0000_0000_0000; // Halt clock
+        const MI = 0b0100_0000_0000_0000; // Memory address register in
+        const RI = 0b0010_0000_0000_0000; // RAM data in
+        const RO = 0b0001_0000_0000_0000; // RAM data out
+        const IO = 0b0000_1000_0000_0000; // Instruction register out
+        const II = 0b0000_0100_0000_0000; // Instruction register in
+        const AI = 0b0000_0010_0000_0000; // A register in
+        const AO = 0b0000_0001_0000_0000; // A register out
+        const EO = 0b0000_0000_1000_0000; // ALU out
+        const SU = 0b0000_0000_0100_0000; // ALU subtract
+        const BI = 0b0000_0000_0010_0000; // B register in
+        const OI = 0b0000_0000_0001_0000; // Output register in
+        const CE = 0b0000_0000_0000_1000; // Program counter enable
+        const CO = 0b0000_0000_0000_0100; // Program counter out
+        const J = 0b0000_0000_0000_0010; // Jump (program counter in)
+        const FI = 0b0000_0000_0000_0001; // Flags in
+    }
+}
+
+/// Every control line with its name, from the most significant bit of the control word down.
+pub const CONTROL_LINES: [(&str, Control); 16] = [
+    ("HLT", Control::HLT),
+    ("MI", Control::MI),
+    ("RI", Control::RI),
+    ("RO", Control::RO),
+    ("IO", Control::IO),
+    ("II", Control::II),
+    ("AI", Control::AI),
+    ("AO", Control::AO),
+    ("EO", Control::EO),
+    ("SU", Control::SU),
+    ("BI", Control::BI),
+    ("OI", Control::OI),
+    ("CE", Control::CE),
+    ("CO", Control::CO),
+    ("J", Control::J),
+    ("FI", Control::FI),
+];
+
+/// Number of addressable steps per instruction (the step counter is 3 bits wide).
+pub const STEPS: usize = 8;
+
+/// Number of opcodes (the instruction register's high nibble).
+pub const OPCODES: usize = 16;
+
+/// Number of flag combinations (Z and C).
+pub const FLAG_STATES: usize = 4;
+
+/// A microcode table, indexed by flags, opcode and step, exactly like the address lines of the
+/// control logic EEPROMs.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Microcode {
+    table: [[[Control; STEPS]; OPCODES]; FLAG_STATES],
+    steps: u8,
+}
+
+// Raw control bits, for building control words in const context here and in `isa`.
+pub(crate) const HLT: u16 = Control::HLT.bits();
+pub(crate) const MI: u16 = Control::MI.bits();
+pub(crate) const RI: u16 = Control::RI.bits();
+pub(crate) const RO: u16 = Control::RO.bits();
+pub(crate) const IO: u16 = Control::IO.bits();
+pub(crate) const II: u16 = Control::II.bits();
+pub(crate) const AI: u16 = Control::AI.bits();
+pub(crate) const AO: u16 = Control::AO.bits();
+pub(crate) const EO: u16 = Control::EO.bits();
+pub(crate) const SU: u16 = Control::SU.bits();
+pub(crate) const BI: u16 = Control::BI.bits();
+pub(crate) const OI: u16 = Control::OI.bits();
+pub(crate) const CE: u16 = Control::CE.bits();
+pub(crate) const CO: u16 = Control::CO.bits();
+pub(crate) const J: u16 = Control::J.bits();
+pub(crate) const FI: u16 = Control::FI.bits();
+
+/// The fetch steps (T0 - T1) shared by every instruction.
+pub(crate) const FETCH: [u16; 2] = [MI | CO, RO | II | CE];
+
+/// The steps that skip over the operand byte on the RAM upgrade, when a jump is not taken.
+const RAM256_SKIP: [u16; 2] = [CO | MI, CE];
+
+impl Microcode {
+    /// Create a microcode table with every control word cleared. `steps` is the number of
+    /// T-states before the step counter resets.
+    pub fn empty(steps: u8) -> Self {
+        assert!(
+            (1..=STEPS as u8).contains(&steps),
+            "Step count must be between 1 and {}",
+            STEPS
+        );
+
+        Self {
+            table: [[[Control::empty(); STEPS]; OPCODES]; FLAG_STATES],
+            steps,
+        }
+    }
+
+    /// The microcode for the RAM upgrade (`Variant::Ram256`), which takes an extra T-state to
+    /// fetch operands.
+    pub fn ram256() -> Self {
+        Self::for_variant(Variant::Ram256)
+    }
+
+    /// The microcode for a build of the machine, built from the control words in
+    /// `isa::INSTRUCTIONS`. Undefined opcodes only execute the fetch steps.
+    pub fn for_variant(variant: Variant) -> Self {
+        Self::for_isa(variant, &isa::ISA)
+    }
+
+    /// The microcode for a build of the machine, built from the control words in an instruction
+    /// table.
+    pub(crate) fn for_isa(variant: Variant, isa: &Isa) -> Self {
+        let mut microcode = Self::empty(variant.steps());
+
+        for (flags, table) in microcode.table.iter_mut().enumerate() {
+            let flags = Flags::from_bits_truncate(flags as u8);
+
+            for steps in table.iter_mut() {
+                for (step, &bits) in steps.iter_mut().zip(FETCH.iter()) {
+                    *step = Control::from_bits_truncate(bits);
+                }
+            }
+
+            for inst in isa.instructions() {
+                let taken = match inst.op {
+                    Op::Jump(condition) => condition.holds(flags),
+                    _ => true,
+                };
+                let execute = &mut table[inst.opcode as usize][FETCH.len()..];
+
+                match (variant, taken) {
+                    (Variant::Original, true) => {
+                        execute[..3].copy_from_slice(&inst.microcode);
+                    }
+                    (Variant::Ram256, true) => {
+                        execute[..4].copy_from_slice(&inst.microcode_ram256);
+                    }
+                    (Variant::Original, false) => (),
+                    (Variant::Ram256, false) => {
+                        for (step, &bits) in execute.iter_mut().zip(RAM256_SKIP.iter()) {
+                            *step = Control::from_bits_truncate(bits);
+                        }
+                    }
+                }
+            }
+        }
+
+        microcode
+    }
+
+    /// Number of T-states per instruction before the step counter resets.
+    pub fn steps(&self) -> u8 {
+        self.steps
+    }
+
+    /// Look up the control word for the given flags, opcode (0 - 15) and step (0 - 7).
+    pub fn control(&self, flags: Flags, opcode: u8, step: u8) -> Control {
+        self.table[flags.bits() as usize][opcode as usize & 0xf][step as usize & 0x7]
+    }
+
+    /// Replace the control word for the given flags, opcode and step.
+    pub fn set_control(&mut self, flags: Flags, opcode: u8, step: u8, control: Control) {
+        self.table[flags.bits() as usize][opcode as usize & 0xf][step as usize & 0x7] = control;
+    }
+}
+
+impl Default for Microcode {
+    /// The microcode programmed into the original build's EEPROMs.
+    fn default() -> Self {
+        Self::for_variant(Variant::Original)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_microcode_fetch() {
+        let microcode = Microcode::default();
+
+        for flags in 0..FLAG_STATES as u8 {
+            let flags = Flags::from_bits_truncate(flags);
+
+            for opcode in 0..OPCODES as u8 {
+                assert_eq!(
+                    microcode.control(flags, opcode, 0),
+                    Control::MI | Control::CO
+                );
+                assert_eq!(
+                    microcode.control(flags, opcode, 1),
+                    Control::RO | Control::II | Control::CE
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_microcode_add() {
+        let microcode = Microcode::default();
+
+        assert_eq!(microcode.steps(), 5);
+        assert_eq!(
+            microcode.control(Flags::CLEAR, 0x2, 2),
+            Control::IO | Control::MI
+        );
+        assert_eq!(
+            microcode.control(Flags::CLEAR, 0x2, 3),
+            Control::RO | Control::BI
+        );
+        assert_eq!(
+            microcode.control(Flags::CLEAR, 0x2, 4),
+            Control::EO | Control::AI | Control::FI
+        );
+        assert_eq!(microcode.control(Flags::CLEAR, 0x2, 5), Control::empty());
+    }
+
+    #[test]
+    fn test_microcode_conditional_jumps() {
+        let microcode = Microcode::default();
+        let jump = Control::IO | Control::J;
+
+        assert_eq!(microcode.control(Flags::CLEAR, 0x7, 2), Control::empty());
+        assert_eq!(microcode.control(Flags::C, 0x7, 2), jump);
+        assert_eq!(microcode.control(Flags::Z, 0x7, 2), Control::empty());
+        assert_eq!(microcode.control(Flags::Z | Flags::C, 0x7, 2), jump);
+
+        assert_eq!(microcode.control(Flags::CLEAR, 0x8, 2), Control::empty());
+        assert_eq!(microcode.control(Flags::C, 0x8, 2), Control::empty());
+        assert_eq!(microcode.control(Flags::Z, 0x8, 2), jump);
+        assert_eq!(microcode.control(Flags::Z | Flags::C, 0x8, 2), jump);
+
+        // Unconditional jumps ignore the flags
+        for flags in 0..FLAG_STATES as u8 {
+            let flags = Flags::from_bits_truncate(flags);
+            assert_eq!(microcode.control(flags, 0x6, 2), jump);
+        }
+    }
+
+    #[test]
+    fn test_microcode_ram256() {
+        let microcode = Microcode::ram256();
+        let jump = Control::RO | Control::J;
+
+        assert_eq!(microcode.steps(), 6);
+        assert_eq!(
+            microcode.control(Flags::CLEAR, 0x2, 2),
+            Control::CO | Control::MI
+        );
+        assert_eq!(
+            microcode.control(Flags::CLEAR, 0x2, 3),
+            Control::RO | Control::MI | Control::CE
+        );
+        assert_eq!(
+            microcode.control(Flags::CLEAR, 0x2, 5),
+            Control::EO | Control::AI | Control::FI
+        );
+
+        // Jumps not taken skip over the operand byte
+        assert_eq!(microcode.control(Flags::CLEAR, 0x7, 3), Control::CE);
+        assert_eq!(microcode.control(Flags::C, 0x7, 3), jump);
+        assert_eq!(microcode.control(Flags::C, 0x8, 3), Control::CE);
+        assert_eq!(microcode.control(Flags::Z, 0x8, 3), jump);
+    }
+}
@@ -0,0 +1,52 @@
+use std::sync::mpsc::{Sender, SyncSender};
+
+/// A device attached to the output register.
+///
+/// Every OUT instruction delivers the A register along with a timestamp. The timestamp is the
+/// number of clock ticks the backend had executed when the value was latched (instructions for
+/// the interpreter, T-states for the simulator).
+pub trait Output {
+    fn out(&mut self, value: u8, time: u64);
+}
+
+/// Prints each output value on its own line, like the decimal display on the breadboard.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Stdout;
+
+impl Output for Stdout {
+    fn out(&mut self, value: u8, _time: u64) {
+        println!("{}", value);
+    }
+}
+
+/// Collects output values in memory, discarding timestamps.
+impl Output for Vec<u8> {
+    fn out(&mut self, value: u8, _time: u64) {
+        self.push(value);
+    }
+}
+
+/// Sends `(value, time)` pairs over a channel. Values are dropped once the receiver hangs up.
+impl Output for Sender<(u8, u64)> {
+    fn out(&mut self, value: u8, time: u64) {
+        let _ = self.send((value, time));
+    }
+}
+
+impl Output for SyncSender<(u8, u64)> {
+    fn out(&mut self, value: u8, time: u64) {
+        let _ = self.send((value, time));
+    }
+}
+
+impl<O: Output + ?Sized> Output for &mut O {
+    fn out(&mut self, value: u8, time: u64) {
+        (**self).out(value, time);
+    }
+}
+
+impl<O: Output + ?Sized> Output for Box<O> {
+    fn out(&mut self, value: u8, time: u64) {
+        (**self).out(value, time);
+    }
+}
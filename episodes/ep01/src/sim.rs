@@ -1,98 +1,178 @@
-use bitflags::bitflags;
-
-#[derive(Debug, Default)]
-pub struct EaterSim {
-    mem: [u8; 16],
+use crate::alu::alu;
+use crate::cpu::{Cpu, Flags, OpcodePolicy, Phase, Variant};
+use crate::error::EaterError;
+use crate::halting::{self, Checked, RunReport};
+use crate::history::{History, Rewind};
+use crate::isa::{Isa, Op, Operand, ISA};
+use crate::microcode::{Control, Microcode, STEPS};
+use crate::output::{Output, Stdout};
+use crate::snapshot::{Snapshot, SnapshotError};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug)]
+pub struct EaterSim<O = Stdout> {
+    mem: Vec<u8>,
+    variant: Variant,
+    isa: &'static Isa,
     pc: u8,
     a: u8,
     cycle: EaterCycle,
     flags: Flags,
     halt: bool,
+    b: u8,
+    mar: u8,
+    ir: u8,
+    out: u8,
+    bus: u8,
+    // When present, every T-state is driven by a control word from this table
+    microcode: Option<Box<Microcode>>,
+    step_counter: u8,
+    fault: Option<EaterError>,
+    policy: OpcodePolicy,
+    cycles: u64,
+    history: History<Registers>,
+    output: O,
 }
 
-bitflags! {
-    #[derive(Default)]
-    struct Flags: u8 {
-        const CLEAR = 0;
-        const Z = 0b01;
-        const C = 0b10;
-    }
+/// Everything a T-state can change apart from memory, for the undo log.
+#[derive(Debug)]
+struct Registers {
+    pc: u8,
+    a: u8,
+    cycle: EaterCycle,
+    flags: Flags,
+    halt: bool,
+    b: u8,
+    mar: u8,
+    ir: u8,
+    out: u8,
+    bus: u8,
+    step_counter: u8,
+    fault: Option<EaterError>,
+    cycles: u64,
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
 enum EaterCycle {
-    LatchPC,         // Memory In + Counter Out
-    Fetch(u8),       // RAM Out + Instruction In + Counter Enable
-    Execute3(Inst3), // Instruction-specific
-    Execute4(Inst4), // Instruction-specific
-    Execute5(Inst5), // Instruction-specific
+    #[default]
+    LatchPC, // Memory In + Counter Out
+    Fetch(u8),      // RAM Out + Instruction In + Counter Enable
+    Execute3(Inst), // Instruction-specific
+    Operand(Inst),  // Operand byte fetch on the RAM upgrade
+    Execute4(Inst), // Instruction-specific
+    Execute5(Inst), // Instruction-specific
 }
 
-impl Default for EaterCycle {
-    fn default() -> Self {
-        EaterCycle::LatchPC
-    }
+/// An instruction in flight, decoded through the instruction table.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct Inst {
+    op: Op,
+    operand: Operand,
+    /// The operand from the instruction's low nibble. The RAM upgrade reads it from memory
+    /// instead.
+    arg: u8,
 }
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
-enum Inst3 {
-    Nop,
-    Lda(u8),
-    Add(u8),
-    Sub(u8),
-    Sta(u8),
-    Ldi(u8),
-    Jmp(u8),
-    Jc(u8),
-    Jz(u8),
-    Out,
-    Hlt,
-}
+impl Inst {
+    const NOP: Inst = Inst {
+        op: Op::Nop,
+        operand: Operand::None,
+        arg: 0,
+    };
+
+    const HLT: Inst = Inst {
+        op: Op::Hlt,
+        operand: Operand::None,
+        arg: 0,
+    };
+
+    /// Decode an instruction byte, or `None` for undefined opcodes.
+    fn decode(isa: &Isa, value: u8) -> Option<Self> {
+        let inst = isa.decode(value >> 4)?;
+
+        Some(Inst {
+            op: inst.op,
+            operand: inst.operand,
+            arg: match inst.operand {
+                Operand::None => 0,
+                _ => value & 0xf,
+            },
+        })
+    }
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
-enum Inst4 {
-    Nop,
-    Lda(u8),
-    Add(u8),
-    Sub(u8),
-    Sta(u8),
-    Ldi,
-    Jmp,
-    Jc,
-    Jz,
-    Out,
+    /// Whether the operand is a memory address that is latched in the MAR.
+    fn uses_memory(self) -> bool {
+        self.op.uses_memory(self.operand)
+    }
 }
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
-enum Inst5 {
-    Nop,
-    Lda,
-    Add(u8),
-    Sub(u8),
-    Sta,
-    Ldi,
-    Jmp,
-    Jc,
-    Jz,
-    Out,
+/// The complete state of an `EaterSim`, including the in-flight instruction cycle and the
+/// microcode table, apart from its output device.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SimSnapshot {
+    mem: Vec<u8>,
+    variant: Variant,
+    pc: u8,
+    a: u8,
+    cycle: EaterCycle,
+    flags: Flags,
+    halt: bool,
+    b: u8,
+    mar: u8,
+    ir: u8,
+    out: u8,
+    bus: u8,
+    microcode: Option<Box<Microcode>>,
+    step_counter: u8,
+    fault: Option<EaterError>,
+    policy: OpcodePolicy,
+    cycles: u64,
 }
 
-impl From<u8> for Inst3 {
-    fn from(value: u8) -> Self {
-        match value >> 4 {
-            0x0 => Inst3::Nop,
-            0x1 => Inst3::Lda(value),
-            0x2 => Inst3::Add(value),
-            0x3 => Inst3::Sub(value),
-            0x4 => Inst3::Sta(value),
-            0x5 => Inst3::Ldi(value & 0xf),
-            0x6 => Inst3::Jmp(value & 0xf),
-            0x7 => Inst3::Jc(value & 0xf),
-            0x8 => Inst3::Jz(value & 0xf),
-            0xe => Inst3::Out,
-            0xf => Inst3::Hlt,
-            _ => panic!("Unknown opcode: {:x?}", value),
+impl Snapshot for SimSnapshot {
+    const BACKEND: &'static str = "sim";
+
+    fn validate(&self) -> Result<(), SnapshotError> {
+        let size = self.mem.len();
+        if size != self.variant.mem_size() {
+            return Err(SnapshotError::Invalid(
+                "Memory size doesn't match the variant",
+            ));
+        }
+        if self.pc as usize >= size {
+            return Err(SnapshotError::Invalid("PC is outside of memory"));
+        }
+        if self.mar as usize >= size {
+            return Err(SnapshotError::Invalid("MAR is outside of memory"));
+        }
+
+        // The instruction in flight is executed without bounds checks, like the registers
+        let addr = match &self.cycle {
+            EaterCycle::LatchPC => None,
+            EaterCycle::Fetch(pc) => Some(*pc),
+            EaterCycle::Execute3(inst)
+            | EaterCycle::Operand(inst)
+            | EaterCycle::Execute4(inst)
+            | EaterCycle::Execute5(inst) => Some(inst.arg),
+        };
+        if addr.is_some_and(|addr| addr as usize >= size) {
+            return Err(SnapshotError::Invalid(
+                "Instruction cycle addresses outside of memory",
+            ));
+        }
+
+        if let Some(microcode) = &self.microcode {
+            if !(1..=STEPS as u8).contains(&microcode.steps()) {
+                return Err(SnapshotError::Invalid(
+                    "Microcode step count is out of range",
+                ));
+            }
+            if self.step_counter >= microcode.steps() {
+                return Err(SnapshotError::Invalid("Step counter is out of range"));
+            }
         }
+
+        Ok(())
     }
 }
 
@@ -100,128 +180,386 @@ impl EaterSim {
     pub fn new() -> Self {
         Self::default()
     }
+}
+
+impl Default for EaterSim {
+    fn default() -> Self {
+        Self::with_output(Stdout)
+    }
+}
 
-    pub fn load(&mut self, mem: &[u8]) {
-        // TODO: Return Result when mem slice length is not equal to 16.
-        self.mem.copy_from_slice(mem);
+impl<O: Output> EaterSim<O> {
+    pub fn with_output(output: O) -> Self {
+        Self {
+            mem: vec![0; Variant::Original.mem_size()],
+            variant: Variant::Original,
+            isa: &ISA,
+            pc: 0,
+            a: 0,
+            cycle: EaterCycle::LatchPC,
+            flags: Flags::CLEAR,
+            halt: false,
+            b: 0,
+            mar: 0,
+            ir: 0,
+            out: 0,
+            bus: 0,
+            microcode: None,
+            step_counter: 0,
+            fault: None,
+            policy: OpcodePolicy::Nop,
+            cycles: 0,
+            history: History::default(),
+            output,
+        }
+    }
+
+    /// Switch between the built-in instruction behavior (`None`) and driving every T-state with
+    /// control words from a microcode table. The undefined opcode policy does not apply to the
+    /// microcode mode; undefined opcodes do whatever the table says.
+    ///
+    /// The step counter is reset, so this should be called at an instruction boundary.
+    pub fn set_microcode(&mut self, microcode: Option<Microcode>) {
+        self.microcode = microcode.map(Box::new);
+        self.cycle = EaterCycle::LatchPC;
+        self.step_counter = 0;
+        self.history.clear();
+    }
+
+    pub fn microcode(&self) -> Option<&Microcode> {
+        self.microcode.as_deref()
+    }
+
+    /// Switch to another build of the machine. Memory is cleared and the CPU is reset.
+    ///
+    /// The microcode table is kept, so the microcode mode needs a table written for the variant,
+    /// like `Microcode::ram256`.
+    pub fn set_variant(&mut self, variant: Variant) {
+        self.variant = variant;
+        self.mem = vec![0; variant.mem_size()];
+        self.reset();
+    }
+
+    /// Decode with another instruction table. The microcode mode only follows its table, which
+    /// `Microcode::for_isa` builds from an instruction table.
+    #[cfg(test)]
+    pub(crate) fn set_isa(&mut self, isa: &'static Isa) {
+        self.isa = isa;
+    }
+
+    pub fn variant(&self) -> Variant {
+        self.variant
+    }
+
+    /// The B register, which holds the ALU's second operand.
+    pub fn b(&self) -> u8 {
+        self.b
+    }
+
+    /// The memory address register.
+    pub fn mar(&self) -> u8 {
+        self.mar
+    }
+
+    /// The instruction register.
+    pub fn ir(&self) -> u8 {
+        self.ir
+    }
+
+    /// The output register, shown on the decimal display.
+    pub fn out(&self) -> u8 {
+        self.out
+    }
+
+    /// The value on the bus during the last clock tick, or zero when nothing drove it.
+    pub fn bus(&self) -> u8 {
+        self.bus
+    }
+
+    /// The T-state that will be executed by the next clock tick (0 - 4 on the original build,
+    /// and 0 - 5 with the RAM upgrade).
+    pub fn step_counter(&self) -> u8 {
+        // The operand fetch delays the last two steps on the RAM upgrade
+        let operand = match self.variant {
+            Variant::Original => 0,
+            Variant::Ram256 => 1,
+        };
+
+        match self.microcode {
+            Some(_) => self.step_counter,
+            None => match self.cycle {
+                EaterCycle::LatchPC => 0,
+                EaterCycle::Fetch(_) => 1,
+                EaterCycle::Execute3(_) => 2,
+                EaterCycle::Operand(_) => 3,
+                EaterCycle::Execute4(_) => 3 + operand,
+                EaterCycle::Execute5(_) => 4 + operand,
+            },
+        }
+    }
+
+    /// The control word that will be asserted by the next clock tick. Only the microcode mode
+    /// models control lines.
+    pub fn control(&self) -> Option<Control> {
+        self.microcode
+            .as_ref()
+            .map(|microcode| microcode.control(self.flags, self.ir >> 4, self.step_counter))
+    }
+
+    pub fn output(&self) -> &O {
+        &self.output
+    }
+
+    pub fn output_mut(&mut self) -> &mut O {
+        &mut self.output
+    }
+
+    pub fn into_output(self) -> O {
+        self.output
+    }
+
+    /// Capture the complete machine state, apart from the output device.
+    pub fn snapshot(&self) -> SimSnapshot {
+        SimSnapshot {
+            mem: self.mem.clone(),
+            variant: self.variant,
+            pc: self.pc,
+            a: self.a,
+            cycle: self.cycle.clone(),
+            flags: self.flags,
+            halt: self.halt,
+            b: self.b,
+            mar: self.mar,
+            ir: self.ir,
+            out: self.out,
+            bus: self.bus,
+            microcode: self.microcode.clone(),
+            step_counter: self.step_counter,
+            fault: self.fault.clone(),
+            policy: self.policy,
+            cycles: self.cycles,
+        }
+    }
+
+    /// Return to a captured state, including its microcode table. The output device is left
+    /// alone.
+    pub fn restore(&mut self, snapshot: &SimSnapshot) {
+        self.mem = snapshot.mem.clone();
+        self.variant = snapshot.variant;
+        self.pc = snapshot.pc;
+        self.a = snapshot.a;
+        self.cycle = snapshot.cycle.clone();
+        self.flags = snapshot.flags;
+        self.halt = snapshot.halt;
+        self.b = snapshot.b;
+        self.mar = snapshot.mar;
+        self.ir = snapshot.ir;
+        self.out = snapshot.out;
+        self.bus = snapshot.bus;
+        self.microcode = snapshot.microcode.clone();
+        self.step_counter = snapshot.step_counter;
+        self.fault = snapshot.fault.clone();
+        self.policy = snapshot.policy;
+        self.cycles = snapshot.cycles;
+        self.history.clear();
+    }
+
+    /// Set the architectural registers, for starting differential tests from arbitrary states.
+    pub(crate) fn set_registers(&mut self, pc: u8, a: u8, flags: Flags) {
+        self.pc = pc & self.variant.addr_mask();
+        self.a = a;
+        self.flags = flags;
+        self.history.clear();
+    }
+
+    /// Run until the CPU halts, returns to a state it was in before, or executes `limit`
+    /// instructions. A trapped fault is returned as an error.
+    pub fn run_checked(&mut self, limit: u64) -> Result<RunReport, EaterError> {
+        halting::run_checked(self, limit)
+    }
+
+    /// Surface a trapped fault as an error, otherwise report the halt status.
+    fn status(&self) -> Result<bool, EaterError> {
+        match &self.fault {
+            Some(fault) => Err(fault.clone()),
+            None => Ok(self.halt),
+        }
     }
 
     fn step(&mut self) -> bool {
         if self.halt {
             return self.halt;
         }
+        if self.history.is_recording() {
+            self.history.record(Registers {
+                pc: self.pc,
+                a: self.a,
+                cycle: self.cycle.clone(),
+                flags: self.flags,
+                halt: self.halt,
+                b: self.b,
+                mar: self.mar,
+                ir: self.ir,
+                out: self.out,
+                bus: self.bus,
+                step_counter: self.step_counter,
+                fault: self.fault.clone(),
+                cycles: self.cycles,
+            });
+        }
+        let time = self.cycles;
+        self.cycles += 1;
+
+        if let Some(control) = self.control() {
+            self.clock(control, time);
+            return self.halt;
+        }
+
+        // Nothing drives the bus unless the current T-state asserts an output
+        self.bus = 0;
 
         self.cycle = match self.cycle {
             EaterCycle::LatchPC => {
                 let pc = self.pc;
+                self.bus = pc;
+                self.mar = self.bus;
                 EaterCycle::Fetch(pc)
             }
             EaterCycle::Fetch(pc) => {
-                self.pc += 1;
-                self.pc &= 0xf;
-
-                let addr = (pc & 0xf) as usize;
-                let inst = self.mem[addr];
+                self.pc = self.pc.wrapping_add(1) & self.variant.addr_mask();
+
+                let addr = pc;
+                let inst = self.mem[addr as usize];
+                self.bus = inst;
+                self.ir = self.bus;
+
+                match (Inst::decode(self.isa, inst), self.policy) {
+                    (Some(inst), _) => EaterCycle::Execute3(inst),
+                    (None, OpcodePolicy::Nop) => EaterCycle::Execute3(Inst::NOP),
+                    (None, OpcodePolicy::Halt) => EaterCycle::Execute3(Inst::HLT),
+                    (None, OpcodePolicy::Trap) => {
+                        self.fault = Some(EaterError::UndefinedOpcode { addr, inst });
+                        self.halt = true;
+                        return self.halt;
+                    }
+                }
+            }
+            EaterCycle::Execute3(inst) if self.variant == Variant::Ram256 => {
+                match inst.op {
+                    Op::Hlt => {
+                        self.halt = true;
+                        return self.halt;
+                    }
+                    Op::Out => self.latch_output(time),
+                    _ if inst.operand == Operand::None => (),
+                    // Counter Out + Memory In: address the operand byte
+                    _ => {
+                        self.bus = self.pc;
+                        self.mar = self.bus;
+                    }
+                }
 
-                EaterCycle::Execute3(Inst3::from(inst))
+                EaterCycle::Operand(inst)
             }
             EaterCycle::Execute3(inst) => {
-                let inst = match inst {
-                    Inst3::Nop => Inst4::Nop,
-                    Inst3::Lda(addr) => Inst4::Lda(addr),
-                    Inst3::Add(addr) => Inst4::Add(addr),
-                    Inst3::Sub(addr) => Inst4::Sub(addr),
-                    Inst3::Sta(addr) => Inst4::Sta(addr),
-                    Inst3::Ldi(imm) => {
-                        self.a = imm;
-                        Inst4::Ldi
+                match inst.op {
+                    Op::Hlt => {
+                        self.halt = true;
+                        return self.halt;
                     }
-                    Inst3::Jmp(pc) => {
-                        self.pc = pc;
-                        Inst4::Jmp
+                    Op::Out => self.latch_output(time),
+                    _ if inst.operand == Operand::None => (),
+                    _ if inst.uses_memory() => self.latch_address(inst.arg),
+                    // Instruction Out + A In
+                    Op::Load => {
+                        self.bus = inst.arg;
+                        self.a = self.bus;
                     }
-                    Inst3::Jc(pc) => {
-                        if self.flags & Flags::C == Flags::C {
-                            self.pc = pc;
-                        }
-                        Inst4::Jc
+                    // Instruction Out + B In
+                    Op::Add | Op::Sub => {
+                        self.bus = inst.arg;
+                        self.b = self.bus;
                     }
-                    Inst3::Jz(pc) => {
-                        if self.flags & Flags::Z == Flags::Z {
-                            self.pc = pc;
+                    // Instruction Out + Jump
+                    Op::Jump(condition) => {
+                        if condition.holds(self.flags) {
+                            self.bus = inst.arg;
+                            self.pc = self.bus;
                         }
-                        Inst4::Jz
                     }
-                    Inst3::Out => {
-                        println!("{}", self.a);
-                        Inst4::Out
+                    Op::Nop | Op::Store => (),
+                }
+
+                EaterCycle::Execute4(inst)
+            }
+            EaterCycle::Operand(inst) => {
+                let mask = self.variant.addr_mask();
+
+                match inst.op {
+                    _ if inst.operand == Operand::None => (),
+                    // RAM Out + Memory In + Counter Enable
+                    _ if inst.uses_memory() => {
+                        self.bus = self.mem[self.mar as usize];
+                        self.mar = self.bus;
+                        self.pc = self.pc.wrapping_add(1) & mask;
                     }
-                    Inst3::Hlt => {
-                        self.halt = true;
-                        return self.halt;
+                    // RAM Out + A In + Counter Enable
+                    Op::Load => {
+                        self.bus = self.mem[self.mar as usize];
+                        self.a = self.bus;
+                        self.pc = self.pc.wrapping_add(1) & mask;
+                    }
+                    // RAM Out + B In + Counter Enable
+                    Op::Add | Op::Sub => {
+                        self.bus = self.mem[self.mar as usize];
+                        self.b = self.bus;
+                        self.pc = self.pc.wrapping_add(1) & mask;
                     }
-                };
+                    // RAM Out + Jump
+                    Op::Jump(condition) if condition.holds(self.flags) => {
+                        self.bus = self.mem[self.mar as usize];
+                        self.pc = self.bus;
+                    }
+                    // Counter Enable, skipping the operand
+                    _ => {
+                        self.pc = self.pc.wrapping_add(1) & mask;
+                    }
+                }
 
                 EaterCycle::Execute4(inst)
             }
             EaterCycle::Execute4(inst) => {
-                let inst = match inst {
-                    Inst4::Nop => Inst5::Nop,
-                    Inst4::Lda(addr) => {
-                        let addr = addr & 0xf;
-                        self.a = self.mem[addr as usize];
-                        Inst5::Lda
+                match inst.op {
+                    // RAM Out + A In
+                    Op::Load if inst.uses_memory() => {
+                        self.bus = self.mem[self.mar as usize];
+                        self.a = self.bus;
                     }
-                    Inst4::Add(addr) => {
-                        let addr = addr & 0xf;
-                        Inst5::Add(self.mem[addr as usize])
+                    // RAM Out + B In
+                    Op::Add | Op::Sub if inst.uses_memory() => {
+                        self.bus = self.mem[self.mar as usize];
+                        self.b = self.bus;
                     }
-                    Inst4::Sub(addr) => {
-                        let addr = addr & 0xf;
-                        Inst5::Sub(self.mem[addr as usize])
+                    // A Out + RAM In
+                    Op::Store if inst.uses_memory() => {
+                        self.bus = self.a;
+                        self.store(self.mar as usize);
                     }
-                    Inst4::Sta(addr) => {
-                        let addr = addr & 0xf;
-                        self.mem[addr as usize] = self.a;
-                        Inst5::Sta
+                    // Immediate operands are already in B
+                    Op::Add | Op::Sub if inst.operand == Operand::Immediate => {
+                        self.latch_sum(inst.op == Op::Sub);
                     }
-                    Inst4::Ldi => Inst5::Ldi,
-                    Inst4::Jmp => Inst5::Jmp,
-                    Inst4::Jc => Inst5::Jc,
-                    Inst4::Jz => Inst5::Jz,
-                    Inst4::Out => Inst5::Out,
-                };
+                    _ => (),
+                }
 
                 EaterCycle::Execute5(inst)
             }
             EaterCycle::Execute5(inst) => {
-                match inst {
-                    Inst5::Add(b) => {
-                        let carry = (self.a as u16).wrapping_add(b as u16);
-
-                        self.a = carry as u8;
-                        self.flags = if self.a == 0 {
-                            Flags::Z
-                        } else if carry >= 0x100 {
-                            Flags::C
-                        } else {
-                            Flags::CLEAR
-                        };
+                if let Op::Add | Op::Sub = inst.op {
+                    if inst.uses_memory() {
+                        self.latch_sum(inst.op == Op::Sub);
                     }
-                    Inst5::Sub(b) => {
-                        let carry = (self.a as u16).wrapping_sub(b as u16);
-
-                        self.a = carry as u8;
-                        self.flags = if self.a == 0 {
-                            Flags::Z
-                        } else if carry >= 0x100 {
-                            Flags::C
-                        } else {
-                            Flags::CLEAR
-                        };
-                    }
-                    _ => (),
-                };
+                }
 
                 EaterCycle::LatchPC
             }
@@ -230,18 +568,282 @@ impl EaterSim {
         self.halt
     }
 
-    pub fn run(&mut self) {
+    /// RAM In: write the bus to memory.
+    fn store(&mut self, addr: usize) {
+        self.history.store(addr, self.mem[addr]);
+        self.mem[addr] = self.bus;
+    }
+
+    /// A Out + Output In: show A on the display.
+    fn latch_output(&mut self, time: u64) {
+        self.bus = self.a;
+        self.out = self.bus;
+        self.output.out(self.out, time);
+    }
+
+    /// Sum Out + A In + Flags In: store the ALU result.
+    fn latch_sum(&mut self, subtract: bool) {
+        let (sum, flags) = alu(self.a, self.b, subtract);
+
+        self.bus = sum;
+        self.a = self.bus;
+        self.flags = flags;
+    }
+
+    /// Instruction Out + Memory In: move the operand into the memory address register.
+    fn latch_address(&mut self, inst: u8) {
+        self.bus = inst & 0xf;
+        self.mar = self.bus;
+    }
+
+    /// Execute one T-state from its control word.
+    ///
+    /// Outputs drive the bus first, then every register with an active input line latches the
+    /// bus simultaneously on the rising clock edge.
+    fn clock(&mut self, control: Control, time: u64) {
+        let (sum, flags) = alu(self.a, self.b, control.contains(Control::SU));
+        let mask = self.variant.addr_mask();
+
+        // Unlike the real bus, contention is resolved by letting low bits win, and a floating
+        // bus reads zero thanks to the pull-down resistors.
+        let drivers = [
+            (Control::RO, self.mem[(self.mar & mask) as usize]),
+            (Control::IO, self.ir & 0xf),
+            (Control::AO, self.a),
+            (Control::EO, sum),
+            (Control::CO, self.pc),
+        ];
+        let mut driven = drivers
+            .iter()
+            .filter(|(line, _)| control.contains(*line))
+            .map(|&(_, value)| value)
+            .peekable();
+        self.bus = match driven.peek() {
+            Some(_) => driven.fold(0xff, |bus, value| bus & value),
+            None => 0,
+        };
+
+        if control.contains(Control::HLT) {
+            // The clock stops before any register latches or the step counter advances
+            self.halt = true;
+            return;
+        }
+
+        if control.contains(Control::RI) {
+            self.store((self.mar & mask) as usize);
+        }
+        if control.contains(Control::MI) {
+            self.mar = self.bus & mask;
+        }
+        if control.contains(Control::II) {
+            self.ir = self.bus;
+        }
+        if control.contains(Control::AI) {
+            self.a = self.bus;
+        }
+        if control.contains(Control::BI) {
+            self.b = self.bus;
+        }
+        if control.contains(Control::OI) {
+            self.out = self.bus;
+            self.output.out(self.out, time);
+        }
+        if control.contains(Control::FI) {
+            self.flags = flags;
+        }
+        if control.contains(Control::J) {
+            // The counter's synchronous load wins over counting
+            self.pc = self.bus & mask;
+        } else if control.contains(Control::CE) {
+            self.pc = self.pc.wrapping_add(1) & mask;
+        }
+
+        let steps = self
+            .microcode
+            .as_ref()
+            .map_or(5, |microcode| microcode.steps());
+        self.step_counter = (self.step_counter + 1) % steps;
+    }
+}
+
+impl<O: Output> Cpu for EaterSim<O> {
+    fn load(&mut self, mem: &[u8]) -> Result<(), EaterError> {
+        EaterError::check_image(mem.len(), self.mem.len())?;
+        self.mem.copy_from_slice(mem);
+        self.history.clear();
+
+        Ok(())
+    }
+
+    fn reset(&mut self) {
+        self.pc = 0;
+        self.a = 0;
+        self.cycle = EaterCycle::LatchPC;
+        self.flags = Flags::CLEAR;
+        self.halt = false;
+        self.b = 0;
+        self.mar = 0;
+        self.ir = 0;
+        self.out = 0;
+        self.bus = 0;
+        self.step_counter = 0;
+        self.fault = None;
+        self.cycles = 0;
+        self.history.clear();
+    }
+
+    fn set_opcode_policy(&mut self, policy: OpcodePolicy) {
+        self.policy = policy;
+    }
+
+    fn poke(&mut self, addr: u8, value: u8) {
+        let len = self.mem.len();
+        self.mem[addr as usize % len] = value;
+        self.history.clear();
+    }
+
+    fn step_instruction(&mut self) -> Result<bool, EaterError> {
+        while !self.step() && self.step_counter() != 0 {}
+
+        self.status()
+    }
+
+    fn step_clock(&mut self) -> Result<bool, EaterError> {
+        self.step();
+        self.status()
+    }
+
+    fn pc(&self) -> u8 {
+        self.pc
+    }
+
+    fn a(&self) -> u8 {
+        self.a
+    }
+
+    fn ir(&self) -> u8 {
+        self.ir
+    }
+
+    fn phase(&self) -> Phase {
+        Phase::Step(self.step_counter())
+    }
+
+    fn flags(&self) -> Flags {
+        self.flags
+    }
+
+    fn mem(&self) -> &[u8] {
+        &self.mem
+    }
+
+    fn halted(&self) -> bool {
+        self.halt
+    }
+
+    fn cycles(&self) -> u64 {
+        self.cycles
+    }
+}
+
+impl<O: Output> Checked for EaterSim<O> {
+    // The behavioral instruction cycle follows from the step counter and the registers
+    type State = (Vec<u8>, [u8; 8], Flags);
+
+    fn state(&self) -> Self::State {
+        let registers = [
+            self.pc,
+            self.a,
+            self.b,
+            self.mar,
+            self.ir,
+            self.out,
+            self.bus,
+            self.step_counter(),
+        ];
+
+        (self.mem.clone(), registers, self.flags)
+    }
+
+    fn step_checked(&mut self, outputs: &mut Vec<u8>) -> Result<bool, EaterError> {
         loop {
-            if self.step() {
-                break;
+            let out = match self.control() {
+                Some(control) => control.contains(Control::OI),
+                None => matches!(self.cycle, EaterCycle::Execute3(Inst { op: Op::Out, .. })),
+            };
+            let halted = self.step();
+            if out && !halted {
+                outputs.push(self.out);
+            }
+
+            if halted || self.step_counter() == 0 {
+                return self.status();
             }
         }
     }
 }
 
+impl<O: Output> Rewind for EaterSim<O> {
+    fn set_history_limit(&mut self, limit: usize) {
+        self.history.set_limit(limit);
+    }
+
+    fn history_len(&self) -> usize {
+        self.history.len()
+    }
+
+    fn step_back(&mut self) -> bool {
+        let delta = match self.history.pop() {
+            Some(delta) => delta,
+            None => return false,
+        };
+
+        let Registers {
+            pc,
+            a,
+            cycle,
+            flags,
+            halt,
+            b,
+            mar,
+            ir,
+            out,
+            bus,
+            step_counter,
+            fault,
+            cycles,
+        } = delta.registers;
+        self.pc = pc;
+        self.a = a;
+        self.cycle = cycle;
+        self.flags = flags;
+        self.halt = halt;
+        self.b = b;
+        self.mar = mar;
+        self.ir = ir;
+        self.out = out;
+        self.bus = bus;
+        self.step_counter = step_counter;
+        self.fault = fault;
+        self.cycles = cycles;
+        if let Some((addr, old)) = delta.write {
+            self.mem[addr] = old;
+        }
+
+        true
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::alu::conformance_cases;
+    use crate::fixtures::COUNTER;
+    use crate::isa::Condition;
+
+    fn inst(byte: u8) -> Inst {
+        Inst::decode(&ISA, byte).unwrap()
+    }
 
     #[test]
     fn test_vm_nop() {
@@ -252,7 +854,7 @@ mod tests {
         assert_eq!(sim.cycle, EaterCycle::Fetch(0));
         sim.step();
         assert_eq!(sim.pc, 1);
-        assert_eq!(sim.cycle, EaterCycle::Execute3(Inst3::Nop));
+        assert_eq!(sim.cycle, EaterCycle::Execute3(inst(0x00)));
 
         for _ in 0..3 {
             sim.step();
@@ -279,17 +881,17 @@ mod tests {
 
         sim.step();
         assert_eq!(sim.pc, 1);
-        assert_eq!(sim.cycle, EaterCycle::Execute3(Inst3::Lda(0x1f)));
+        assert_eq!(sim.cycle, EaterCycle::Execute3(inst(0x1f)));
         assert_eq!(sim.a, 0);
 
         sim.step();
         assert_eq!(sim.pc, 1);
-        assert_eq!(sim.cycle, EaterCycle::Execute4(Inst4::Lda(0x1f)));
+        assert_eq!(sim.cycle, EaterCycle::Execute4(inst(0x1f)));
         assert_eq!(sim.a, 0);
 
         sim.step();
         assert_eq!(sim.pc, 1);
-        assert_eq!(sim.cycle, EaterCycle::Execute5(Inst5::Lda));
+        assert_eq!(sim.cycle, EaterCycle::Execute5(inst(0x1f)));
         assert_eq!(sim.a, 0x55);
 
         sim.step();
@@ -320,17 +922,17 @@ mod tests {
 
         sim.step();
         assert_eq!(sim.pc, 1);
-        assert_eq!(sim.cycle, EaterCycle::Execute3(Inst3::Add(0x2f)));
+        assert_eq!(sim.cycle, EaterCycle::Execute3(inst(0x2f)));
         assert_eq!(sim.a, 0);
 
         sim.step();
         assert_eq!(sim.pc, 1);
-        assert_eq!(sim.cycle, EaterCycle::Execute4(Inst4::Add(0x2f)));
+        assert_eq!(sim.cycle, EaterCycle::Execute4(inst(0x2f)));
         assert_eq!(sim.a, 0);
 
         sim.step();
         assert_eq!(sim.pc, 1);
-        assert_eq!(sim.cycle, EaterCycle::Execute5(Inst5::Add(0x60)));
+        assert_eq!(sim.cycle, EaterCycle::Execute5(inst(0x2f)));
 
         sim.step();
         assert_eq!(sim.pc, 1);
@@ -345,17 +947,17 @@ mod tests {
 
         sim.step();
         assert_eq!(sim.pc, 2);
-        assert_eq!(sim.cycle, EaterCycle::Execute3(Inst3::Add(0x2f)));
+        assert_eq!(sim.cycle, EaterCycle::Execute3(inst(0x2f)));
         assert_eq!(sim.a, 0x60);
 
         sim.step();
         assert_eq!(sim.pc, 2);
-        assert_eq!(sim.cycle, EaterCycle::Execute4(Inst4::Add(0x2f)));
+        assert_eq!(sim.cycle, EaterCycle::Execute4(inst(0x2f)));
         assert_eq!(sim.a, 0x60);
 
         sim.step();
         assert_eq!(sim.pc, 2);
-        assert_eq!(sim.cycle, EaterCycle::Execute5(Inst5::Add(0x60)));
+        assert_eq!(sim.cycle, EaterCycle::Execute5(inst(0x2f)));
         assert_eq!(sim.a, 0x60);
 
         sim.step();
@@ -371,17 +973,17 @@ mod tests {
 
         sim.step();
         assert_eq!(sim.pc, 3);
-        assert_eq!(sim.cycle, EaterCycle::Execute3(Inst3::Add(0x2f)));
+        assert_eq!(sim.cycle, EaterCycle::Execute3(inst(0x2f)));
         assert_eq!(sim.a, 0xc0);
 
         sim.step();
         assert_eq!(sim.pc, 3);
-        assert_eq!(sim.cycle, EaterCycle::Execute4(Inst4::Add(0x2f)));
+        assert_eq!(sim.cycle, EaterCycle::Execute4(inst(0x2f)));
         assert_eq!(sim.a, 0xc0);
 
         sim.step();
         assert_eq!(sim.pc, 3);
-        assert_eq!(sim.cycle, EaterCycle::Execute5(Inst5::Add(0x60)));
+        assert_eq!(sim.cycle, EaterCycle::Execute5(inst(0x2f)));
         assert_eq!(sim.a, 0xc0);
         assert_eq!(sim.flags, Flags::CLEAR);
 
@@ -413,24 +1015,24 @@ mod tests {
 
         sim.step();
         assert_eq!(sim.pc, 1);
-        assert_eq!(sim.cycle, EaterCycle::Execute3(Inst3::Sub(0x3f)));
+        assert_eq!(sim.cycle, EaterCycle::Execute3(inst(0x3f)));
         assert_eq!(sim.a, 0);
 
         sim.step();
         assert_eq!(sim.pc, 1);
-        assert_eq!(sim.cycle, EaterCycle::Execute4(Inst4::Sub(0x3f)));
+        assert_eq!(sim.cycle, EaterCycle::Execute4(inst(0x3f)));
         assert_eq!(sim.a, 0);
 
         sim.step();
         assert_eq!(sim.pc, 1);
-        assert_eq!(sim.cycle, EaterCycle::Execute5(Inst5::Sub(0x60)));
+        assert_eq!(sim.cycle, EaterCycle::Execute5(inst(0x3f)));
         assert_eq!(sim.flags, Flags::CLEAR);
 
         sim.step();
         assert_eq!(sim.pc, 1);
         assert_eq!(sim.cycle, EaterCycle::LatchPC);
         assert_eq!(sim.a, 0xa0);
-        assert_eq!(sim.flags, Flags::C);
+        assert_eq!(sim.flags, Flags::CLEAR);
 
         // Second instruction
         sim.step();
@@ -440,25 +1042,25 @@ mod tests {
 
         sim.step();
         assert_eq!(sim.pc, 2);
-        assert_eq!(sim.cycle, EaterCycle::Execute3(Inst3::Sub(0x3f)));
+        assert_eq!(sim.cycle, EaterCycle::Execute3(inst(0x3f)));
         assert_eq!(sim.a, 0xa0);
 
         sim.step();
         assert_eq!(sim.pc, 2);
-        assert_eq!(sim.cycle, EaterCycle::Execute4(Inst4::Sub(0x3f)));
+        assert_eq!(sim.cycle, EaterCycle::Execute4(inst(0x3f)));
         assert_eq!(sim.a, 0xa0);
 
         sim.step();
         assert_eq!(sim.pc, 2);
-        assert_eq!(sim.cycle, EaterCycle::Execute5(Inst5::Sub(0x60)));
+        assert_eq!(sim.cycle, EaterCycle::Execute5(inst(0x3f)));
         assert_eq!(sim.a, 0xa0);
-        assert_eq!(sim.flags, Flags::C);
+        assert_eq!(sim.flags, Flags::CLEAR);
 
         sim.step();
         assert_eq!(sim.pc, 2);
         assert_eq!(sim.cycle, EaterCycle::LatchPC);
         assert_eq!(sim.a, 0x40);
-        assert_eq!(sim.flags, Flags::CLEAR);
+        assert_eq!(sim.flags, Flags::C);
 
         // Third instruction
         sim.step();
@@ -468,25 +1070,25 @@ mod tests {
 
         sim.step();
         assert_eq!(sim.pc, 3);
-        assert_eq!(sim.cycle, EaterCycle::Execute3(Inst3::Sub(0x3f)));
+        assert_eq!(sim.cycle, EaterCycle::Execute3(inst(0x3f)));
         assert_eq!(sim.a, 0x40);
 
         sim.step();
         assert_eq!(sim.pc, 3);
-        assert_eq!(sim.cycle, EaterCycle::Execute4(Inst4::Sub(0x3f)));
+        assert_eq!(sim.cycle, EaterCycle::Execute4(inst(0x3f)));
         assert_eq!(sim.a, 0x40);
 
         sim.step();
         assert_eq!(sim.pc, 3);
-        assert_eq!(sim.cycle, EaterCycle::Execute5(Inst5::Sub(0x60)));
+        assert_eq!(sim.cycle, EaterCycle::Execute5(inst(0x3f)));
         assert_eq!(sim.a, 0x40);
-        assert_eq!(sim.flags, Flags::CLEAR);
+        assert_eq!(sim.flags, Flags::C);
 
         sim.step();
         assert_eq!(sim.pc, 3);
         assert_eq!(sim.cycle, EaterCycle::LatchPC);
         assert_eq!(sim.a, 0xe0);
-        assert_eq!(sim.flags, Flags::C);
+        assert_eq!(sim.flags, Flags::CLEAR);
         assert_eq!(
             sim.mem,
             [0x3f, 0x3f, 0x3f, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x60]
@@ -507,18 +1109,18 @@ mod tests {
 
         sim.step();
         assert_eq!(sim.pc, 1);
-        assert_eq!(sim.cycle, EaterCycle::Execute3(Inst3::Sta(0x4f)));
+        assert_eq!(sim.cycle, EaterCycle::Execute3(inst(0x4f)));
         assert_eq!(sim.a, 0x55);
 
         sim.step();
         assert_eq!(sim.pc, 1);
-        assert_eq!(sim.cycle, EaterCycle::Execute4(Inst4::Sta(0x4f)));
+        assert_eq!(sim.cycle, EaterCycle::Execute4(inst(0x4f)));
         assert_eq!(sim.a, 0x55);
         assert_eq!(sim.mem[15], 0);
 
         sim.step();
         assert_eq!(sim.pc, 1);
-        assert_eq!(sim.cycle, EaterCycle::Execute5(Inst5::Sta));
+        assert_eq!(sim.cycle, EaterCycle::Execute5(inst(0x4f)));
         assert_eq!(sim.a, 0x55);
         assert_eq!(sim.mem[15], 0x55);
 
@@ -546,17 +1148,17 @@ mod tests {
 
         sim.step();
         assert_eq!(sim.pc, 1);
-        assert_eq!(sim.cycle, EaterCycle::Execute3(Inst3::Ldi(0xf)));
+        assert_eq!(sim.cycle, EaterCycle::Execute3(inst(0x5f)));
         assert_eq!(sim.a, 0);
 
         sim.step();
         assert_eq!(sim.pc, 1);
-        assert_eq!(sim.cycle, EaterCycle::Execute4(Inst4::Ldi));
+        assert_eq!(sim.cycle, EaterCycle::Execute4(inst(0x5f)));
         assert_eq!(sim.a, 0xf);
 
         sim.step();
         assert_eq!(sim.pc, 1);
-        assert_eq!(sim.cycle, EaterCycle::Execute5(Inst5::Ldi));
+        assert_eq!(sim.cycle, EaterCycle::Execute5(inst(0x5f)));
         assert_eq!(sim.a, 0xf);
 
         sim.step();
@@ -580,17 +1182,17 @@ mod tests {
 
         sim.step();
         assert_eq!(sim.pc, 1);
-        assert_eq!(sim.cycle, EaterCycle::Execute3(Inst3::Jmp(0xf)));
+        assert_eq!(sim.cycle, EaterCycle::Execute3(inst(0x6f)));
         assert_eq!(sim.a, 0);
 
         sim.step();
         assert_eq!(sim.pc, 0xf);
-        assert_eq!(sim.cycle, EaterCycle::Execute4(Inst4::Jmp));
+        assert_eq!(sim.cycle, EaterCycle::Execute4(inst(0x6f)));
         assert_eq!(sim.a, 0);
 
         sim.step();
         assert_eq!(sim.pc, 0xf);
-        assert_eq!(sim.cycle, EaterCycle::Execute5(Inst5::Jmp));
+        assert_eq!(sim.cycle, EaterCycle::Execute5(inst(0x6f)));
         assert_eq!(sim.a, 0);
 
         sim.step();
@@ -616,17 +1218,17 @@ mod tests {
 
         sim.step();
         assert_eq!(sim.pc, 1);
-        assert_eq!(sim.cycle, EaterCycle::Execute3(Inst3::Jc(0xf)));
+        assert_eq!(sim.cycle, EaterCycle::Execute3(inst(0x7f)));
         assert_eq!(sim.a, 0);
 
         sim.step();
         assert_eq!(sim.pc, 1);
-        assert_eq!(sim.cycle, EaterCycle::Execute4(Inst4::Jc));
+        assert_eq!(sim.cycle, EaterCycle::Execute4(inst(0x7f)));
         assert_eq!(sim.a, 0);
 
         sim.step();
         assert_eq!(sim.pc, 1);
-        assert_eq!(sim.cycle, EaterCycle::Execute5(Inst5::Jc));
+        assert_eq!(sim.cycle, EaterCycle::Execute5(inst(0x7f)));
         assert_eq!(sim.a, 0);
 
         sim.step();
@@ -646,17 +1248,17 @@ mod tests {
 
         sim.step();
         assert_eq!(sim.pc, 2);
-        assert_eq!(sim.cycle, EaterCycle::Execute3(Inst3::Jc(0xf)));
+        assert_eq!(sim.cycle, EaterCycle::Execute3(inst(0x7f)));
         assert_eq!(sim.a, 0);
 
         sim.step();
         assert_eq!(sim.pc, 0xf);
-        assert_eq!(sim.cycle, EaterCycle::Execute4(Inst4::Jc));
+        assert_eq!(sim.cycle, EaterCycle::Execute4(inst(0x7f)));
         assert_eq!(sim.a, 0);
 
         sim.step();
         assert_eq!(sim.pc, 0xf);
-        assert_eq!(sim.cycle, EaterCycle::Execute5(Inst5::Jc));
+        assert_eq!(sim.cycle, EaterCycle::Execute5(inst(0x7f)));
         assert_eq!(sim.a, 0);
 
         sim.step();
@@ -685,17 +1287,17 @@ mod tests {
 
         sim.step();
         assert_eq!(sim.pc, 1);
-        assert_eq!(sim.cycle, EaterCycle::Execute3(Inst3::Jz(0xf)));
+        assert_eq!(sim.cycle, EaterCycle::Execute3(inst(0x8f)));
         assert_eq!(sim.a, 0);
 
         sim.step();
         assert_eq!(sim.pc, 1);
-        assert_eq!(sim.cycle, EaterCycle::Execute4(Inst4::Jz));
+        assert_eq!(sim.cycle, EaterCycle::Execute4(inst(0x8f)));
         assert_eq!(sim.a, 0);
 
         sim.step();
         assert_eq!(sim.pc, 1);
-        assert_eq!(sim.cycle, EaterCycle::Execute5(Inst5::Jz));
+        assert_eq!(sim.cycle, EaterCycle::Execute5(inst(0x8f)));
         assert_eq!(sim.a, 0);
 
         sim.step();
@@ -715,17 +1317,17 @@ mod tests {
 
         sim.step();
         assert_eq!(sim.pc, 2);
-        assert_eq!(sim.cycle, EaterCycle::Execute3(Inst3::Jz(0xf)));
+        assert_eq!(sim.cycle, EaterCycle::Execute3(inst(0x8f)));
         assert_eq!(sim.a, 0);
 
         sim.step();
         assert_eq!(sim.pc, 0xf);
-        assert_eq!(sim.cycle, EaterCycle::Execute4(Inst4::Jz));
+        assert_eq!(sim.cycle, EaterCycle::Execute4(inst(0x8f)));
         assert_eq!(sim.a, 0);
 
         sim.step();
         assert_eq!(sim.pc, 0xf);
-        assert_eq!(sim.cycle, EaterCycle::Execute5(Inst5::Jz));
+        assert_eq!(sim.cycle, EaterCycle::Execute5(inst(0x8f)));
         assert_eq!(sim.a, 0);
 
         sim.step();
@@ -751,15 +1353,489 @@ mod tests {
 
         sim.step();
         assert_eq!(sim.pc, 1);
-        assert_eq!(sim.cycle, EaterCycle::Execute3(Inst3::Hlt));
+        assert_eq!(sim.cycle, EaterCycle::Execute3(inst(0xf0)));
         assert!(!sim.halt);
 
         sim.step();
         assert_eq!(sim.pc, 1);
-        assert_eq!(sim.cycle, EaterCycle::Execute3(Inst3::Hlt));
+        assert_eq!(sim.cycle, EaterCycle::Execute3(inst(0xf0)));
         assert!(sim.halt);
         assert_eq!(sim.a, 0);
         assert_eq!(sim.flags, Flags::CLEAR);
         assert_eq!(sim.mem, [0xf0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
     }
+
+    #[test]
+    fn test_vm_snapshot() {
+        for microcode in [None, Some(Microcode::default())] {
+            let mut sim = EaterSim::with_output(Vec::new());
+            sim.set_microcode(microcode);
+            sim.load(include_bytes!("example.bin")).unwrap();
+
+            // Stop in the middle of an ADD
+            for _ in 0..38 {
+                sim.step_clock().unwrap();
+            }
+            assert_eq!(sim.step_counter(), 3);
+            let latches = (sim.b(), sim.mar(), sim.bus());
+
+            let snapshot = sim.snapshot();
+            let start = sim.output().len();
+            sim.run().unwrap();
+            let (output, cycles) = (sim.output()[start..].to_vec(), sim.cycles());
+
+            sim.reset();
+            sim.set_microcode(None);
+            sim.poke(0, 0xf0);
+            sim.restore(&snapshot);
+            assert_eq!(sim.step_counter(), 3);
+            assert_eq!((sim.b(), sim.mar(), sim.bus()), latches);
+
+            sim.output_mut().truncate(start);
+            sim.run().unwrap();
+            assert_eq!(sim.output()[start..], output[..]);
+            assert_eq!(sim.cycles(), cycles);
+        }
+    }
+
+    #[test]
+    fn test_vm_snapshot_cycle() {
+        let jmp = Inst {
+            op: Op::Jump(Condition::Always),
+            operand: Operand::Address,
+            arg: 16,
+        };
+        let cycles = [
+            EaterCycle::Fetch(16),
+            EaterCycle::Execute3(jmp),
+            EaterCycle::Execute4(Inst {
+                op: Op::Load,
+                ..jmp
+            }),
+        ];
+
+        for cycle in cycles.iter() {
+            let mut snapshot = EaterSim::new().snapshot();
+            snapshot.cycle = cycle.clone();
+            assert!(matches!(
+                SimSnapshot::from_bytes(&snapshot.to_bytes()),
+                Err(SnapshotError::Invalid(
+                    "Instruction cycle addresses outside of memory"
+                ))
+            ));
+            assert!(matches!(
+                SimSnapshot::from_json(&snapshot.to_json()),
+                Err(SnapshotError::Invalid(
+                    "Instruction cycle addresses outside of memory"
+                ))
+            ));
+
+            // The same addresses are fine with the RAM upgrade
+            let mut sim = EaterSim::new();
+            sim.set_variant(Variant::Ram256);
+            let mut snapshot = sim.snapshot();
+            snapshot.cycle = cycle.clone();
+            assert_eq!(
+                SimSnapshot::from_json(&snapshot.to_json()).unwrap(),
+                snapshot
+            );
+        }
+    }
+
+    #[test]
+    fn test_vm_step_back() {
+        for microcode in [None, Some(Microcode::default())] {
+            let mut sim = EaterSim::with_output(Vec::new());
+            sim.set_microcode(microcode);
+
+            sim.load(&COUNTER).unwrap();
+            sim.set_history_limit(1000);
+
+            let mut snapshots = vec![sim.snapshot()];
+            for _ in 0..200 {
+                sim.step_clock().unwrap();
+                snapshots.push(sim.snapshot());
+            }
+
+            // Rewind T-state by T-state, including through stores to memory
+            for snapshot in snapshots.iter().rev().skip(1) {
+                assert!(sim.step_back());
+                assert_eq!(&sim.snapshot(), snapshot);
+            }
+            assert!(!sim.step_back());
+
+            // Rewinding to an instruction boundary mid-run lands on the matching state
+            for _ in 0..200 {
+                sim.step_clock().unwrap();
+            }
+            assert!(sim.run_back_to(|sim| sim.step_counter() == 0 && sim.pc() == 5));
+            assert_eq!(sim.snapshot(), snapshots[175]);
+        }
+    }
+
+    #[test]
+    fn test_vm_step_instruction() {
+        let mut sim = EaterSim::new();
+
+        sim.mem[0] = 0x5a; // LDI 10
+        sim.mem[1] = 0xf0; // HLT
+
+        assert_eq!(sim.step_instruction(), Ok(false));
+        assert_eq!(sim.pc, 1);
+        assert_eq!(sim.cycle, EaterCycle::LatchPC);
+        assert_eq!(sim.a, 10);
+
+        assert_eq!(sim.step_instruction(), Ok(true));
+        assert!(sim.halted());
+
+        sim.reset();
+        assert_eq!(sim.pc, 0);
+        assert_eq!(sim.a, 0);
+        assert_eq!(sim.cycle, EaterCycle::LatchPC);
+        assert!(!sim.halted());
+        assert_eq!(
+            sim.mem(),
+            [0x5a, 0xf0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]
+        );
+    }
+
+    #[test]
+    fn test_vm_out() {
+        let (tx, rx) = std::sync::mpsc::channel();
+        let mut sim = EaterSim::with_output(tx);
+
+        sim.mem[0] = 0x5a; // LDI 10
+        sim.mem[1] = 0xe0; // OUT
+        sim.mem[2] = 0xf0; // HLT
+
+        sim.run().unwrap();
+        assert_eq!(sim.cycles(), 13);
+        drop(sim);
+
+        // OUT latches the A register during T2 of the second instruction
+        assert_eq!(rx.iter().collect::<Vec<_>>(), [(10, 7)]);
+    }
+
+    #[test]
+    fn test_vm_load() {
+        let mut sim = EaterSim::new();
+
+        assert_eq!(
+            sim.load(&[]),
+            Err(EaterError::ImageTooSmall { len: 0, size: 16 })
+        );
+        assert_eq!(
+            sim.load_padded(&[0; 20]),
+            Err(EaterError::ImageTooLarge { len: 20, size: 16 })
+        );
+
+        sim.load_padded(&[0x1e, 0x2f]).unwrap();
+        assert_eq!(
+            sim.mem,
+            [0x1e, 0x2f, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]
+        );
+    }
+
+    #[test]
+    fn test_vm_undefined_nop() {
+        let mut sim = EaterSim::new();
+
+        sim.mem[0] = 0xc5; // Undefined
+
+        sim.step();
+        sim.step();
+        assert_eq!(sim.cycle, EaterCycle::Execute3(inst(0x00)));
+        assert_eq!(sim.step_instruction(), Ok(false));
+        assert_eq!(sim.pc, 1);
+        assert_eq!(sim.cycle, EaterCycle::LatchPC);
+    }
+
+    #[test]
+    fn test_vm_undefined_trap() {
+        let mut sim = EaterSim::new();
+        sim.set_opcode_policy(OpcodePolicy::Trap);
+
+        sim.mem[0] = 0xb0; // Undefined
+
+        sim.step();
+        assert!(sim.step());
+        assert_eq!(sim.cycle, EaterCycle::Fetch(0));
+        assert_eq!(
+            sim.step_clock(),
+            Err(EaterError::UndefinedOpcode {
+                addr: 0,
+                inst: 0xb0
+            })
+        );
+    }
+
+    #[test]
+    fn test_vm_undefined_halt() {
+        let mut sim = EaterSim::new();
+        sim.set_opcode_policy(OpcodePolicy::Halt);
+
+        sim.mem[0] = 0x9f; // Undefined
+
+        assert_eq!(sim.step_instruction(), Ok(true));
+        assert_eq!(sim.cycle, EaterCycle::Execute3(inst(0xf0)));
+        assert_eq!(sim.cycles, 3);
+    }
+
+    #[test]
+    fn test_microcode_add() {
+        let mut sim = EaterSim::new();
+        sim.set_microcode(Some(Microcode::default()));
+
+        sim.mem[0] = 0x2f; // ADD 15
+        sim.mem[15] = 0xff; // Value to add
+        sim.a = 0x01;
+
+        assert_eq!(sim.step_counter(), 0);
+        assert_eq!(sim.control(), Some(Control::MI | Control::CO));
+        sim.step();
+        assert_eq!(sim.bus, 0);
+        assert_eq!(sim.mar, 0);
+
+        assert_eq!(sim.step_counter(), 1);
+        assert_eq!(sim.control(), Some(Control::RO | Control::II | Control::CE));
+        sim.step();
+        assert_eq!(sim.bus, 0x2f);
+        assert_eq!(sim.ir, 0x2f);
+        assert_eq!(sim.pc, 1);
+
+        assert_eq!(sim.control(), Some(Control::IO | Control::MI));
+        sim.step();
+        assert_eq!(sim.bus, 0xf);
+        assert_eq!(sim.mar, 0xf);
+
+        assert_eq!(sim.control(), Some(Control::RO | Control::BI));
+        sim.step();
+        assert_eq!(sim.bus, 0xff);
+        assert_eq!(sim.b, 0xff);
+        assert_eq!(sim.a, 0x01);
+        assert_eq!(sim.flags, Flags::CLEAR);
+
+        assert_eq!(sim.control(), Some(Control::EO | Control::AI | Control::FI));
+        sim.step();
+        assert_eq!(sim.bus, 0);
+        assert_eq!(sim.a, 0);
+        assert_eq!(sim.flags, Flags::Z | Flags::C);
+        assert_eq!(sim.step_counter(), 0);
+        assert_eq!(sim.cycles, 5);
+    }
+
+    #[test]
+    fn test_microcode_jc() {
+        let mut sim = EaterSim::new();
+        sim.set_microcode(Some(Microcode::default()));
+
+        sim.mem[0] = 0x7f; // JC 15
+        sim.mem[1] = 0x7f; // JC 15
+
+        assert_eq!(sim.step_instruction(), Ok(false));
+        assert_eq!(sim.pc, 1);
+
+        sim.flags = Flags::C;
+        assert_eq!(sim.step_instruction(), Ok(false));
+        assert_eq!(sim.pc, 0xf);
+        assert_eq!(sim.cycles, 10);
+    }
+
+    #[test]
+    fn test_microcode_hlt() {
+        let mut sim = EaterSim::new();
+        sim.set_microcode(Some(Microcode::default()));
+
+        sim.mem[0] = 0xf0; // HLT
+
+        assert_eq!(sim.step_instruction(), Ok(true));
+        assert_eq!(sim.control(), Some(Control::HLT));
+        assert_eq!(sim.step_counter(), 2);
+        assert_eq!(sim.pc, 1);
+        assert_eq!(sim.cycles, 3);
+    }
+
+    #[test]
+    fn test_microcode_matches_behavior() {
+        // Count by threes until the carry flag is set, storing the result between each OUT
+        let program = [
+            0x1e, 0x2f, 0xe0, 0x4d, 0x76, 0x61, 0x1d, 0xe0, 0xf0, 0, 0, 0, 0, 0, 0, 0x03,
+        ];
+
+        let mut behavior = EaterSim::with_output(Vec::new());
+        behavior.load(&program).unwrap();
+        behavior.run().unwrap();
+
+        let mut microcode = EaterSim::with_output(Vec::new());
+        microcode.set_microcode(Some(Microcode::default()));
+        microcode.load(&program).unwrap();
+        microcode.run().unwrap();
+
+        assert_eq!(microcode.output(), behavior.output());
+        assert_eq!(microcode.pc, behavior.pc);
+        assert_eq!(microcode.a, behavior.a);
+        assert_eq!(microcode.mem, behavior.mem);
+        assert_eq!(microcode.cycles, behavior.cycles);
+        assert_eq!(microcode.output()[84..], [0xff, 0x02, 0x02]);
+    }
+
+    #[test]
+    fn test_vm_registers() {
+        let mut sim = EaterSim::new();
+
+        sim.mem[0] = 0x2f; // ADD 15
+        sim.mem[1] = 0xe0; // OUT
+        sim.mem[15] = 0x60; // Value to add
+        sim.a = 0x01;
+
+        // Memory In + Counter Out
+        sim.step();
+        assert_eq!((sim.bus(), sim.mar()), (0, 0));
+
+        // RAM Out + Instruction In + Counter Enable
+        sim.step();
+        assert_eq!((sim.bus(), sim.ir()), (0x2f, 0x2f));
+
+        // Instruction Out + Memory In
+        sim.step();
+        assert_eq!((sim.bus(), sim.mar()), (0xf, 0xf));
+
+        // RAM Out + B In
+        sim.step();
+        assert_eq!((sim.bus(), sim.b()), (0x60, 0x60));
+        assert_eq!(sim.a, 0x01);
+
+        // Sum Out + A In + Flags In
+        sim.step();
+        assert_eq!((sim.bus(), sim.a), (0x61, 0x61));
+        assert_eq!(sim.b(), 0x60);
+
+        // OUT: A Out + Output In
+        for _ in 0..3 {
+            sim.step();
+        }
+        assert_eq!(sim.ir(), 0xe0);
+        assert_eq!((sim.bus(), sim.out()), (0x61, 0x61));
+
+        // Nothing drives the bus during the remaining T-states
+        sim.step();
+        assert_eq!((sim.bus(), sim.out()), (0, 0x61));
+    }
+
+    #[test]
+    fn test_vm_registers_match_microcode() {
+        let program = [
+            0x1e, 0x2f, 0xe0, 0x4d, 0x7a, 0x8a, 0x53, 0x6b, 0, 0, 0xf0, 0xe0, 0xf0, 0, 0x10, 0x20,
+        ];
+
+        let mut behavior = EaterSim::with_output(Vec::new());
+        behavior.load(&program).unwrap();
+
+        let mut microcode = EaterSim::with_output(Vec::new());
+        microcode.set_microcode(Some(Microcode::default()));
+        microcode.load(&program).unwrap();
+
+        loop {
+            let halt = behavior.step();
+            assert_eq!(microcode.step(), halt);
+
+            let panel = |sim: &EaterSim<Vec<u8>>| {
+                (
+                    sim.step_counter(),
+                    sim.pc,
+                    sim.mar(),
+                    sim.ir(),
+                    sim.a,
+                    sim.b(),
+                    sim.out(),
+                    sim.bus(),
+                    sim.mem.clone(),
+                )
+            };
+            assert_eq!(panel(&microcode), panel(&behavior));
+
+            if halt {
+                break;
+            }
+        }
+
+        assert_eq!(behavior.output(), &[0x30, 0x03]);
+    }
+
+    #[test]
+    fn test_vm_ram256() {
+        let program = [
+            0x50, 200, // LDI 200
+            0x20, 0x80, // ADD 0x80
+            0x70, 0x08, // JC 8
+            0xf0, // HLT
+            0x00, // NOP
+            0xe0, // OUT
+            0x40, 0xff, // STA 0xff
+            0xf0, // HLT
+        ];
+
+        let mut behavior = EaterSim::with_output(Vec::new());
+        behavior.set_variant(Variant::Ram256);
+        behavior.load_padded(&program).unwrap();
+        behavior.poke(0x80, 100);
+
+        let mut microcode = EaterSim::with_output(Vec::new());
+        microcode.set_variant(Variant::Ram256);
+        microcode.set_microcode(Some(Microcode::ram256()));
+        microcode.load_padded(&program).unwrap();
+        microcode.poke(0x80, 100);
+
+        loop {
+            let halt = behavior.step();
+            assert_eq!(microcode.step(), halt);
+
+            let panel = |sim: &EaterSim<Vec<u8>>| {
+                (
+                    sim.step_counter(),
+                    sim.pc,
+                    sim.mar(),
+                    sim.ir(),
+                    sim.a,
+                    sim.b(),
+                    sim.out(),
+                    sim.bus(),
+                )
+            };
+            assert_eq!(panel(&microcode), panel(&behavior));
+            assert_eq!(microcode.mem, behavior.mem);
+
+            if halt {
+                break;
+            }
+        }
+
+        // Five instructions of six T-states, then HLT
+        assert_eq!(behavior.output(), &[44]);
+        assert_eq!((behavior.pc, behavior.a, behavior.cycles), (12, 44, 33));
+        assert_eq!(behavior.mem[0xff], 44);
+    }
+
+    #[test]
+    fn test_vm_alu_conformance() {
+        for (a, b, subtract, result, flags) in conformance_cases() {
+            let op = if subtract { 0x3f } else { 0x2f }; // SUB 15 or ADD 15
+
+            // LDA 14, ADD/SUB 15, STA 13, LDI 0, HLT
+            let program = [0x1e, op, 0x4d, 0x50, 0xf0, 0, 0, 0, 0, 0, 0, 0, 0, 0, a, b];
+
+            for microcode in [None, Some(Microcode::default())] {
+                let mut sim = EaterSim::new();
+                sim.set_microcode(microcode);
+                sim.load(&program).unwrap();
+                sim.run().unwrap();
+
+                // Flags are only latched by ADD and SUB, so loading zero leaves them untouched
+                let case = (a, b, subtract, sim.microcode.is_some());
+                assert_eq!(sim.mem[13], result, "{:x?}", case);
+                assert_eq!(sim.flags, flags, "{:x?}", case);
+                assert_eq!(sim.a, 0);
+            }
+        }
+    }
 }
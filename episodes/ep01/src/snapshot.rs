@@ -0,0 +1,257 @@
+//! Versioned binary and JSON encodings for machine snapshots.
+//!
+//! Both encodings carry the format version and the name of the backend a snapshot belongs to, so
+//! a snapshot can't be restored into the wrong backend or misread by a newer release.
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fmt;
+
+/// Magic bytes at the start of the binary encoding.
+const MAGIC: &[u8; 4] = b"EATS";
+
+/// Version of both encodings.
+pub const VERSION: u32 = 3;
+
+#[derive(Debug)]
+pub enum SnapshotError {
+    /// The data is not a snapshot, or is truncated or corrupt.
+    Malformed(String),
+    /// The snapshot was written by an unsupported version of the format.
+    Version(u32),
+    /// The snapshot belongs to a different backend.
+    Backend {
+        expected: &'static str,
+        found: String,
+    },
+    /// The snapshot decoded, but describes a state the backend can't be in.
+    Invalid(&'static str),
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SnapshotError::Malformed(msg) => write!(f, "Malformed snapshot: {}", msg),
+            SnapshotError::Version(version) => write!(
+                f,
+                "Snapshot version {} is not supported (expected {})",
+                version, VERSION
+            ),
+            SnapshotError::Backend { expected, found } => write!(
+                f,
+                "Snapshot of a `{}` backend can't be restored into `{}`",
+                found, expected
+            ),
+            SnapshotError::Invalid(msg) => write!(f, "Invalid snapshot: {}", msg),
+        }
+    }
+}
+
+impl Error for SnapshotError {}
+
+impl From<bincode::Error> for SnapshotError {
+    fn from(err: bincode::Error) -> Self {
+        SnapshotError::Malformed(err.to_string())
+    }
+}
+
+impl From<serde_json::Error> for SnapshotError {
+    fn from(err: serde_json::Error) -> Self {
+        SnapshotError::Malformed(err.to_string())
+    }
+}
+
+/// The JSON envelope, with the state's fields next to the header.
+#[derive(Serialize)]
+struct Json<'a, T> {
+    version: u32,
+    backend: &'a str,
+    #[serde(flatten)]
+    state: &'a T,
+}
+
+#[derive(Deserialize)]
+struct JsonHeader {
+    version: u32,
+    backend: String,
+}
+
+/// The state of a backend, as returned by its `snapshot` method.
+pub trait Snapshot: Serialize + DeserializeOwned {
+    /// Name of the backend, stored in both encodings.
+    const BACKEND: &'static str;
+
+    /// Check the invariants the backend relies on when it is restored.
+    fn validate(&self) -> Result<(), SnapshotError>;
+
+    /// Encode as a magic number followed by the version, the backend name and the state, in
+    /// `bincode`'s encoding.
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        bincode::serialize_into(&mut bytes, &(VERSION, Self::BACKEND, self))
+            .expect("Snapshots can always be serialized");
+
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self, SnapshotError> {
+        let mut data = bytes
+            .strip_prefix(MAGIC)
+            .ok_or_else(|| SnapshotError::Malformed("Not a binary snapshot".to_string()))?;
+
+        // The header is checked before the state, whose layout depends on it
+        let version: u32 = bincode::deserialize_from(&mut data)?;
+        if version != VERSION {
+            return Err(SnapshotError::Version(version));
+        }
+        let backend: String = bincode::deserialize_from(&mut data)?;
+        if backend != Self::BACKEND {
+            return Err(SnapshotError::Backend {
+                expected: Self::BACKEND,
+                found: backend,
+            });
+        }
+
+        let state: Self = bincode::deserialize_from(&mut data)?;
+        if !data.is_empty() {
+            return Err(SnapshotError::Malformed("Trailing data".to_string()));
+        }
+        state.validate()?;
+
+        Ok(state)
+    }
+
+    /// Encode as a JSON object with `version` and `backend` fields alongside the state.
+    fn to_json(&self) -> String {
+        let json = Json {
+            version: VERSION,
+            backend: Self::BACKEND,
+            state: self,
+        };
+
+        serde_json::to_string_pretty(&json).expect("Snapshots can always be serialized")
+    }
+
+    fn from_json(json: &str) -> Result<Self, SnapshotError> {
+        let value: serde_json::Value = serde_json::from_str(json)?;
+
+        let header = JsonHeader::deserialize(&value)?;
+        if header.version != VERSION {
+            return Err(SnapshotError::Version(header.version));
+        }
+        if header.backend != Self::BACKEND {
+            return Err(SnapshotError::Backend {
+                expected: Self::BACKEND,
+                found: header.backend,
+            });
+        }
+
+        let state = Self::deserialize(value)?;
+        state.validate()?;
+
+        Ok(state)
+    }
+
+    /// Decode either encoding, telling them apart by the binary encoding's magic number.
+    fn decode(data: &[u8]) -> Result<Self, SnapshotError> {
+        if data.starts_with(MAGIC) {
+            return Self::from_bytes(data);
+        }
+
+        let json = std::str::from_utf8(data)
+            .map_err(|_| SnapshotError::Malformed("Not a snapshot".to_string()))?;
+        Self::from_json(json)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::microcode::Microcode;
+    use crate::{Cpu, EaterSim, EaterVm, SimSnapshot, VmSnapshot};
+
+    fn sim_snapshot() -> SimSnapshot {
+        let mut sim = EaterSim::with_output(Vec::new());
+        sim.set_microcode(Some(Microcode::default()));
+        sim.load(include_bytes!("example.bin")).unwrap();
+        for _ in 0..12 {
+            sim.step_clock().unwrap();
+        }
+
+        sim.snapshot()
+    }
+
+    fn vm_snapshot() -> VmSnapshot {
+        let mut vm = EaterVm::with_output(Vec::new());
+        vm.load(include_bytes!("example.bin")).unwrap();
+        for _ in 0..12 {
+            vm.step_clock().unwrap();
+        }
+
+        vm.snapshot()
+    }
+
+    #[test]
+    fn test_snapshot_round_trip() {
+        let sim = sim_snapshot();
+        assert_eq!(SimSnapshot::from_bytes(&sim.to_bytes()).unwrap(), sim);
+        assert_eq!(SimSnapshot::from_json(&sim.to_json()).unwrap(), sim);
+        assert_eq!(SimSnapshot::decode(sim.to_json().as_bytes()).unwrap(), sim);
+
+        let vm = vm_snapshot();
+        assert_eq!(VmSnapshot::decode(&vm.to_bytes()).unwrap(), vm);
+        assert_eq!(VmSnapshot::from_json(&vm.to_json()).unwrap(), vm);
+
+        let json = vm.to_json();
+        assert!(json.starts_with("{\n  \"version\": 3,\n  \"backend\": \"interp\",\n"));
+        assert!(json.contains("\"pc\": 4,"));
+    }
+
+    #[test]
+    fn test_snapshot_errors() {
+        let sim = sim_snapshot();
+
+        assert!(matches!(
+            VmSnapshot::from_bytes(&sim.to_bytes()),
+            Err(SnapshotError::Backend { expected: "interp", ref found }) if found == "sim"
+        ));
+        assert!(matches!(
+            VmSnapshot::from_json(&sim.to_json()),
+            Err(SnapshotError::Backend { .. })
+        ));
+
+        let mut bytes = sim.to_bytes();
+        bytes[4] = 4;
+        assert!(matches!(
+            SimSnapshot::from_bytes(&bytes),
+            Err(SnapshotError::Version(4))
+        ));
+        let json = sim.to_json().replace("\"version\": 3", "\"version\": 7");
+        assert!(matches!(
+            SimSnapshot::from_json(&json),
+            Err(SnapshotError::Version(7))
+        ));
+
+        let bytes = sim.to_bytes();
+        assert!(matches!(
+            SimSnapshot::from_bytes(&bytes[..bytes.len() - 1]),
+            Err(SnapshotError::Malformed(_))
+        ));
+        assert!(matches!(
+            SimSnapshot::decode(b"junk"),
+            Err(SnapshotError::Malformed(_))
+        ));
+
+        let json = sim.to_json().replace("\"mar\": 2,", "\"mar\": 16,");
+        assert!(matches!(
+            SimSnapshot::from_json(&json),
+            Err(SnapshotError::Invalid(_))
+        ));
+        let json = sim.to_json().replace("\"flags\": 0", "\"flags\": 4");
+        assert!(matches!(
+            SimSnapshot::from_json(&json),
+            Err(SnapshotError::Malformed(_))
+        ));
+    }
+}
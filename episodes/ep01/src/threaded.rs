@@ -0,0 +1,321 @@
+//! A fast interpreter that runs predecoded, direct-threaded code.
+
+use crate::alu::alu;
+use crate::cpu::{Cpu, Flags, OpcodePolicy, Phase};
+use crate::error::EaterError;
+use crate::isa::{Condition, Isa, Op, ISA};
+use crate::output::{Output, Stdout};
+
+/// Executes one instruction. Handlers are called after the fetch, with the instruction byte.
+type Handler<O> = fn(&mut EaterThreaded<O>, u8);
+
+/// A memory cell decoded into the handler for its instruction.
+struct Slot<O> {
+    handler: Handler<O>,
+    inst: u8,
+}
+
+// Derived impls would require `O: Copy`
+impl<O> Clone for Slot<O> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<O> Copy for Slot<O> {}
+
+impl<O> std::fmt::Debug for Slot<O> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Slot({:#04x})", self.inst)
+    }
+}
+
+/// An interpreter with the same behavior as `EaterVm`, tuned for speed.
+///
+/// Every memory cell is decoded ahead of time into a pointer to the handler for its instruction,
+/// so executing an instruction is a single indirect call with no `match` on the opcode. Writes to
+/// memory decode the written cell again, so self-modifying code still works.
+#[derive(Debug)]
+pub struct EaterThreaded<O = Stdout> {
+    mem: [u8; 16],
+    code: [Slot<O>; 16],
+    isa: &'static Isa,
+    pc: u8,
+    a: u8,
+    ir: u8,
+    flags: Flags,
+    halt: bool,
+    fault: Option<EaterError>,
+    policy: OpcodePolicy,
+    cycles: u64,
+    output: O,
+}
+
+impl EaterThreaded {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Default for EaterThreaded {
+    fn default() -> Self {
+        Self::with_output(Stdout)
+    }
+}
+
+impl<O: Output> EaterThreaded<O> {
+    pub fn with_output(output: O) -> Self {
+        Self {
+            mem: [0; 16],
+            code: [decode(&ISA, 0); 16],
+            isa: &ISA,
+            pc: 0,
+            a: 0,
+            ir: 0,
+            flags: Flags::CLEAR,
+            halt: false,
+            fault: None,
+            policy: OpcodePolicy::Nop,
+            cycles: 0,
+            output,
+        }
+    }
+
+    pub fn output(&self) -> &O {
+        &self.output
+    }
+
+    pub fn output_mut(&mut self) -> &mut O {
+        &mut self.output
+    }
+
+    pub fn into_output(self) -> O {
+        self.output
+    }
+
+    /// Decode with another instruction table. Memory is decoded again.
+    #[cfg(test)]
+    pub(crate) fn set_isa(&mut self, isa: &'static Isa) {
+        self.isa = isa;
+        for addr in 0..self.mem.len() {
+            self.store(addr, self.mem[addr]);
+        }
+    }
+
+    /// Write a byte to memory, keeping its decoded slot in sync.
+    fn store(&mut self, addr: usize, value: u8) {
+        self.mem[addr] = value;
+        self.code[addr] = decode(self.isa, value);
+    }
+
+    fn status(&self) -> Result<bool, EaterError> {
+        match &self.fault {
+            Some(fault) => Err(fault.clone()),
+            None => Ok(self.halt),
+        }
+    }
+
+    fn step(&mut self) -> bool {
+        if self.halt {
+            return self.halt;
+        }
+
+        let slot = self.code[self.pc as usize];
+        self.ir = slot.inst;
+        self.cycles += 1;
+        self.pc = (self.pc + 1) & 0xf;
+        (slot.handler)(self, slot.inst);
+
+        self.halt
+    }
+}
+
+/// Pick the handler for an instruction byte.
+fn decode<O: Output>(isa: &Isa, inst: u8) -> Slot<O> {
+    let handler: Handler<O> = match isa.decode(inst >> 4) {
+        None => undefined,
+        Some(inst) => match (inst.op, inst.uses_memory()) {
+            (Op::Nop, _) => nop,
+            (Op::Load, true) => lda,
+            (Op::Load, false) => ldi,
+            (Op::Add, true) => add,
+            (Op::Add, false) => adi,
+            (Op::Sub, true) => sub,
+            (Op::Sub, false) => sui,
+            (Op::Store, _) => sta,
+            (Op::Jump(Condition::Always), _) => jmp,
+            (Op::Jump(Condition::Carry), _) => jc,
+            (Op::Jump(Condition::Zero), _) => jz,
+            (Op::Jump(Condition::NotCarry), _) => jnc,
+            (Op::Jump(Condition::NotZero), _) => jnz,
+            (Op::Out, _) => out,
+            (Op::Hlt, _) => hlt,
+        },
+    };
+
+    Slot { handler, inst }
+}
+
+fn nop<O: Output>(_cpu: &mut EaterThreaded<O>, _inst: u8) {}
+
+fn lda<O: Output>(cpu: &mut EaterThreaded<O>, inst: u8) {
+    cpu.a = cpu.mem[(inst & 0xf) as usize];
+}
+
+fn add<O: Output>(cpu: &mut EaterThreaded<O>, inst: u8) {
+    let (sum, flags) = alu(cpu.a, cpu.mem[(inst & 0xf) as usize], false);
+    cpu.a = sum;
+    cpu.flags = flags;
+}
+
+fn adi<O: Output>(cpu: &mut EaterThreaded<O>, inst: u8) {
+    let (sum, flags) = alu(cpu.a, inst & 0xf, false);
+    cpu.a = sum;
+    cpu.flags = flags;
+}
+
+fn sub<O: Output>(cpu: &mut EaterThreaded<O>, inst: u8) {
+    let (difference, flags) = alu(cpu.a, cpu.mem[(inst & 0xf) as usize], true);
+    cpu.a = difference;
+    cpu.flags = flags;
+}
+
+fn sui<O: Output>(cpu: &mut EaterThreaded<O>, inst: u8) {
+    let (difference, flags) = alu(cpu.a, inst & 0xf, true);
+    cpu.a = difference;
+    cpu.flags = flags;
+}
+
+fn sta<O: Output>(cpu: &mut EaterThreaded<O>, inst: u8) {
+    cpu.store((inst & 0xf) as usize, cpu.a);
+}
+
+fn ldi<O: Output>(cpu: &mut EaterThreaded<O>, inst: u8) {
+    cpu.a = inst & 0xf;
+}
+
+fn jmp<O: Output>(cpu: &mut EaterThreaded<O>, inst: u8) {
+    cpu.pc = inst & 0xf;
+}
+
+fn jc<O: Output>(cpu: &mut EaterThreaded<O>, inst: u8) {
+    if cpu.flags.contains(Flags::C) {
+        cpu.pc = inst & 0xf;
+    }
+}
+
+fn jz<O: Output>(cpu: &mut EaterThreaded<O>, inst: u8) {
+    if cpu.flags.contains(Flags::Z) {
+        cpu.pc = inst & 0xf;
+    }
+}
+
+fn jnc<O: Output>(cpu: &mut EaterThreaded<O>, inst: u8) {
+    if !cpu.flags.contains(Flags::C) {
+        cpu.pc = inst & 0xf;
+    }
+}
+
+fn jnz<O: Output>(cpu: &mut EaterThreaded<O>, inst: u8) {
+    if !cpu.flags.contains(Flags::Z) {
+        cpu.pc = inst & 0xf;
+    }
+}
+
+fn out<O: Output>(cpu: &mut EaterThreaded<O>, _inst: u8) {
+    // The timestamp is the cycle count before this instruction, as on `EaterVm`
+    cpu.output.out(cpu.a, cpu.cycles - 1);
+}
+
+fn hlt<O: Output>(cpu: &mut EaterThreaded<O>, _inst: u8) {
+    cpu.halt = true;
+}
+
+fn undefined<O: Output>(cpu: &mut EaterThreaded<O>, inst: u8) {
+    match cpu.policy {
+        OpcodePolicy::Nop => (),
+        OpcodePolicy::Trap => {
+            let addr = cpu.pc.wrapping_sub(1) & 0xf;
+            cpu.fault = Some(EaterError::UndefinedOpcode { addr, inst });
+            cpu.halt = true;
+        }
+        OpcodePolicy::Halt => cpu.halt = true,
+    }
+}
+
+impl<O: Output> Cpu for EaterThreaded<O> {
+    fn load(&mut self, mem: &[u8]) -> Result<(), EaterError> {
+        EaterError::check_image(mem.len(), self.mem.len())?;
+        for (addr, &value) in mem.iter().enumerate() {
+            self.store(addr, value);
+        }
+
+        Ok(())
+    }
+
+    fn reset(&mut self) {
+        self.pc = 0;
+        self.a = 0;
+        self.ir = 0;
+        self.flags = Flags::CLEAR;
+        self.halt = false;
+        self.fault = None;
+        self.cycles = 0;
+    }
+
+    fn set_opcode_policy(&mut self, policy: OpcodePolicy) {
+        self.policy = policy;
+    }
+
+    fn poke(&mut self, addr: u8, value: u8) {
+        self.store(addr as usize % self.mem.len(), value);
+    }
+
+    fn step_instruction(&mut self) -> Result<bool, EaterError> {
+        self.step();
+        self.status()
+    }
+
+    fn step_clock(&mut self) -> Result<bool, EaterError> {
+        self.step();
+        self.status()
+    }
+
+    fn run(&mut self) -> Result<(), EaterError> {
+        while !self.step() {}
+
+        self.status().map(|_| ())
+    }
+
+    fn pc(&self) -> u8 {
+        self.pc
+    }
+
+    fn a(&self) -> u8 {
+        self.a
+    }
+
+    fn ir(&self) -> u8 {
+        self.ir
+    }
+
+    fn phase(&self) -> Phase {
+        Phase::Instruction
+    }
+
+    fn flags(&self) -> Flags {
+        self.flags
+    }
+
+    fn mem(&self) -> &[u8] {
+        &self.mem
+    }
+
+    fn halted(&self) -> bool {
+        self.halt
+    }
+
+    fn cycles(&self) -> u64 {
+        self.cycles
+    }
+}
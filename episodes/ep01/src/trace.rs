@@ -0,0 +1,657 @@
+//! Opt-in execution traces, with JSON Lines and compact binary encodings.
+
+use crate::cpu::{Cpu, Flags, OpcodePolicy, Phase};
+use crate::disasm;
+use crate::error::EaterError;
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use std::fmt;
+use std::io::{self, BufRead, Read, Write};
+
+/// Magic bytes at the start of the binary format.
+const MAGIC: &[u8; 4] = b"EATR";
+
+/// Version of the binary format.
+const VERSION: u8 = 1;
+
+/// Phase byte used for `Phase::Instruction` in the binary format.
+const PHASE_INSTRUCTION: u8 = 0xff;
+
+/// A memory byte written during a step.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MemWrite {
+    pub addr: u8,
+    pub value: u8,
+}
+
+/// One step of execution: an instruction on the interpreter, or a T-state on the simulator.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Record {
+    /// Clock ticks executed before the step.
+    pub time: u64,
+    /// The phase the step executed.
+    pub phase: Phase,
+    /// Program counter before the step.
+    pub pc: u8,
+    /// The instruction the step belongs to.
+    pub inst: u8,
+    /// A register after the step.
+    pub a: u8,
+    /// Flags after the step.
+    pub flags: Flags,
+    /// Memory written during the step.
+    pub writes: Vec<MemWrite>,
+}
+
+impl fmt::Display for Record {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:>6} {:<11} pc={:x} {:<12} a={:02x} flags={}",
+            self.time,
+            self.phase,
+            self.pc,
+            disasm::format_byte(self.inst),
+            self.a,
+            self.flags
+        )?;
+        for write in &self.writes {
+            write!(f, " mem[{:#x}]={:02x}", write.addr, write.value)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// The JSON Lines representation of a record, which spells out the phase, instruction and flags
+/// for other tools.
+#[derive(Serialize, Deserialize)]
+struct JsonRecord {
+    time: u64,
+    phase: String,
+    pc: u8,
+    inst: u8,
+    #[serde(default, skip_deserializing)]
+    asm: String,
+    a: u8,
+    carry: bool,
+    zero: bool,
+    writes: Vec<MemWrite>,
+}
+
+fn invalid_data<E: Into<Box<dyn std::error::Error + Send + Sync>>>(error: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+fn parse_phase(phase: &str) -> Option<Phase> {
+    match phase {
+        "instruction" => Some(Phase::Instruction),
+        _ => phase.strip_prefix('t')?.parse().ok().map(Phase::Step),
+    }
+}
+
+/// Where a `Recorder` sends records as they are produced.
+pub trait Sink {
+    fn record(&mut self, record: Record) -> io::Result<()>;
+
+    /// Flush any buffered output.
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Keeps every record in memory, for tests and replay.
+impl Sink for Vec<Record> {
+    fn record(&mut self, record: Record) -> io::Result<()> {
+        self.push(record);
+        Ok(())
+    }
+}
+
+/// Streams records as JSON Lines, one object per line.
+#[derive(Debug)]
+pub struct JsonWriter<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> JsonWriter<W> {
+    pub fn new(writer: W) -> Self {
+        Self { writer }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+
+    fn write(&mut self, record: &Record) -> io::Result<()> {
+        let json = JsonRecord {
+            time: record.time,
+            phase: record.phase.to_string(),
+            pc: record.pc,
+            inst: record.inst,
+            asm: disasm::format_byte(record.inst),
+            a: record.a,
+            carry: record.flags.contains(Flags::C),
+            zero: record.flags.contains(Flags::Z),
+            writes: record.writes.clone(),
+        };
+
+        serde_json::to_writer(&mut self.writer, &json)?;
+        writeln!(self.writer)
+    }
+}
+
+impl<W: Write> Sink for JsonWriter<W> {
+    fn record(&mut self, record: Record) -> io::Result<()> {
+        self.write(&record)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Write records as JSON Lines, one object per line.
+pub fn write_json<'a, W, I>(writer: W, records: I) -> io::Result<()>
+where
+    W: Write,
+    I: IntoIterator<Item = &'a Record>,
+{
+    let mut writer = JsonWriter::new(writer);
+    for record in records {
+        writer.write(record)?;
+    }
+
+    Ok(())
+}
+
+/// Read records written by `write_json`. Blank lines are skipped.
+pub fn read_json<R: BufRead>(reader: R) -> io::Result<Vec<Record>> {
+    let mut records = Vec::new();
+
+    for line in reader.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let json: JsonRecord = serde_json::from_str(&line)?;
+        let phase = parse_phase(&json.phase)
+            .ok_or_else(|| invalid_data(format!("Invalid phase `{}`", json.phase)))?;
+        let mut flags = Flags::CLEAR;
+        flags.set(Flags::C, json.carry);
+        flags.set(Flags::Z, json.zero);
+
+        records.push(Record {
+            time: json.time,
+            phase,
+            pc: json.pc,
+            inst: json.inst,
+            a: json.a,
+            flags,
+            writes: json.writes,
+        });
+    }
+
+    Ok(records)
+}
+
+/// Streams records in the compact binary format.
+///
+/// The format is a 4-byte magic number and a version byte, followed by one entry per record: the
+/// time as an LEB128 varint, then the phase (`0xff` for whole instructions), PC, instruction, A,
+/// flags and number of memory writes as bytes, then an address and value byte for each write.
+#[derive(Debug)]
+pub struct BinaryWriter<W: Write> {
+    writer: W,
+    buf: Vec<u8>,
+}
+
+impl<W: Write> BinaryWriter<W> {
+    /// Write the header.
+    pub fn new(mut writer: W) -> io::Result<Self> {
+        writer.write_all(MAGIC)?;
+        writer.write_all(&[VERSION])?;
+
+        Ok(Self {
+            writer,
+            buf: Vec::new(),
+        })
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+
+    fn write(&mut self, record: &Record) -> io::Result<()> {
+        let buf = &mut self.buf;
+        buf.clear();
+
+        let mut time = record.time;
+        while time >= 0x80 {
+            buf.push(time as u8 | 0x80);
+            time >>= 7;
+        }
+        buf.push(time as u8);
+
+        let phase = match record.phase {
+            Phase::Instruction => PHASE_INSTRUCTION,
+            Phase::Step(step) => step,
+        };
+        let writes = u8::try_from(record.writes.len())
+            .map_err(|_| invalid_data("Too many memory writes in one record"))?;
+        buf.extend([
+            phase,
+            record.pc,
+            record.inst,
+            record.a,
+            record.flags.bits(),
+            writes,
+        ]);
+        for write in &record.writes {
+            buf.extend([write.addr, write.value]);
+        }
+
+        self.writer.write_all(buf)
+    }
+}
+
+impl<W: Write> Sink for BinaryWriter<W> {
+    fn record(&mut self, record: Record) -> io::Result<()> {
+        self.write(&record)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Write records in the compact binary format described on `BinaryWriter`.
+pub fn write_binary<'a, W, I>(writer: W, records: I) -> io::Result<()>
+where
+    W: Write,
+    I: IntoIterator<Item = &'a Record>,
+{
+    let mut writer = BinaryWriter::new(writer)?;
+    for record in records {
+        writer.write(record)?;
+    }
+
+    Ok(())
+}
+
+/// Read records written by `write_binary`.
+pub fn read_binary<R: Read>(mut reader: R) -> io::Result<Vec<Record>> {
+    let mut header = [0; 5];
+    reader.read_exact(&mut header)?;
+    if &header[..4] != MAGIC {
+        return Err(invalid_data("Not a binary trace"));
+    }
+    if header[4] != VERSION {
+        return Err(invalid_data(format!(
+            "Unsupported binary trace version {}",
+            header[4]
+        )));
+    }
+
+    let mut data = Vec::new();
+    reader.read_to_end(&mut data)?;
+
+    let mut bytes = data.into_iter();
+    let mut next = || {
+        bytes
+            .next()
+            .ok_or_else(|| invalid_data("Truncated binary trace"))
+    };
+    let mut records = Vec::new();
+
+    loop {
+        // The end of the data is only valid between records
+        let mut byte = match next() {
+            Ok(byte) => byte,
+            Err(_) => return Ok(records),
+        };
+
+        let mut time = 0;
+        let mut shift = 0;
+        while byte & 0x80 != 0 {
+            if shift >= 64 {
+                return Err(invalid_data("Invalid time in binary trace"));
+            }
+            time |= u64::from(byte & 0x7f) << shift;
+            shift += 7;
+            byte = next()?;
+        }
+        time |= u64::from(byte) << shift;
+
+        let phase = match next()? {
+            PHASE_INSTRUCTION => Phase::Instruction,
+            step => Phase::Step(step),
+        };
+        let pc = next()?;
+        let inst = next()?;
+        let a = next()?;
+        let flags = Flags::from_bits_truncate(next()?);
+        let mut writes = Vec::new();
+        for _ in 0..next()? {
+            writes.push(MemWrite {
+                addr: next()?,
+                value: next()?,
+            });
+        }
+
+        records.push(Record {
+            time,
+            phase,
+            pc,
+            inst,
+            a,
+            flags,
+            writes,
+        });
+    }
+}
+
+/// Read records in either format, telling them apart by the binary format's magic number.
+pub fn read<R: BufRead>(mut reader: R) -> io::Result<Vec<Record>> {
+    if reader.fill_buf()?.starts_with(MAGIC) {
+        read_binary(reader)
+    } else {
+        read_json(reader)
+    }
+}
+
+/// Wraps a CPU and records every step it executes.
+///
+/// The recorder is a `Cpu` itself, so it can stand in for the backend anywhere. Whole
+/// instructions are recorded one T-state at a time on backends that model them. Records go to a
+/// `Sink` as they are produced; `Recorder::new` keeps them in memory.
+///
+/// Write errors don't interrupt execution; the first one is returned by `finish`.
+#[derive(Debug)]
+pub struct Recorder<C, S = Vec<Record>> {
+    cpu: C,
+    sink: S,
+    before: Vec<u8>,
+    error: Option<io::Error>,
+}
+
+impl<C: Cpu> Recorder<C> {
+    pub fn new(cpu: C) -> Self {
+        Self::with_sink(cpu, Vec::new())
+    }
+
+    pub fn records(&self) -> &[Record] {
+        &self.sink
+    }
+
+    /// Remove and return the records collected so far.
+    pub fn take_records(&mut self) -> Vec<Record> {
+        std::mem::take(&mut self.sink)
+    }
+}
+
+impl<C: Cpu, S: Sink> Recorder<C, S> {
+    pub fn with_sink(cpu: C, sink: S) -> Self {
+        Self {
+            cpu,
+            sink,
+            before: Vec::new(),
+            error: None,
+        }
+    }
+
+    pub fn cpu(&self) -> &C {
+        &self.cpu
+    }
+
+    /// Access the CPU directly. Changes made through it are not recorded.
+    pub fn cpu_mut(&mut self) -> &mut C {
+        &mut self.cpu
+    }
+
+    pub fn into_inner(self) -> C {
+        self.cpu
+    }
+
+    /// Flush the sink and return it, or the first write error.
+    pub fn finish(mut self) -> io::Result<S> {
+        if let Some(err) = self.error.take() {
+            return Err(err);
+        }
+
+        self.sink.flush()?;
+        Ok(self.sink)
+    }
+}
+
+impl<C: Cpu, S: Sink> Cpu for Recorder<C, S> {
+    fn load(&mut self, mem: &[u8]) -> Result<(), EaterError> {
+        self.cpu.load(mem)
+    }
+
+    fn reset(&mut self) {
+        self.cpu.reset();
+    }
+
+    fn set_opcode_policy(&mut self, policy: OpcodePolicy) {
+        self.cpu.set_opcode_policy(policy);
+    }
+
+    fn poke(&mut self, addr: u8, value: u8) {
+        self.cpu.poke(addr, value);
+    }
+
+    fn step_instruction(&mut self) -> Result<bool, EaterError> {
+        loop {
+            let halted = self.step_clock()?;
+            if halted || matches!(self.phase(), Phase::Instruction | Phase::Step(0)) {
+                return Ok(halted);
+            }
+        }
+    }
+
+    fn step_clock(&mut self) -> Result<bool, EaterError> {
+        if self.cpu.halted() {
+            return self.cpu.step_clock();
+        }
+
+        let time = self.cpu.cycles();
+        let phase = self.cpu.phase();
+        let pc = self.cpu.pc();
+        self.before.clear();
+        self.before.extend_from_slice(self.cpu.mem());
+
+        let result = self.cpu.step_clock();
+
+        // The instruction register still holds the previous instruction while the PC is latched
+        let inst = match phase {
+            Phase::Step(0) => self.before[pc as usize % self.before.len()],
+            _ => self.cpu.ir(),
+        };
+        let writes = self
+            .before
+            .iter()
+            .zip(self.cpu.mem())
+            .enumerate()
+            .filter(|(_, (old, new))| old != new)
+            .map(|(addr, (_, &value))| MemWrite {
+                addr: addr as u8,
+                value,
+            })
+            .collect();
+
+        if self.error.is_none() {
+            let record = Record {
+                time,
+                phase,
+                pc,
+                inst,
+                a: self.cpu.a(),
+                flags: self.cpu.flags(),
+                writes,
+            };
+            self.error = self.sink.record(record).err();
+        }
+
+        result
+    }
+
+    fn pc(&self) -> u8 {
+        self.cpu.pc()
+    }
+
+    fn a(&self) -> u8 {
+        self.cpu.a()
+    }
+
+    fn ir(&self) -> u8 {
+        self.cpu.ir()
+    }
+
+    fn phase(&self) -> Phase {
+        self.cpu.phase()
+    }
+
+    fn flags(&self) -> Flags {
+        self.cpu.flags()
+    }
+
+    fn mem(&self) -> &[u8] {
+        self.cpu.mem()
+    }
+
+    fn halted(&self) -> bool {
+        self.cpu.halted()
+    }
+
+    fn cycles(&self) -> u64 {
+        self.cpu.cycles()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::STORE_THREES;
+    use crate::{EaterSim, EaterVm};
+
+    fn record<C: Cpu>(cpu: C) -> Vec<Record> {
+        let mut recorder = Recorder::new(cpu);
+        recorder.load(&STORE_THREES).unwrap();
+        recorder.run().unwrap();
+
+        assert_eq!(recorder.records().len() as u64, recorder.cycles());
+        recorder.take_records()
+    }
+
+    #[test]
+    fn test_trace_interp() {
+        let records = record(EaterVm::with_output(Vec::new()));
+
+        assert_eq!(
+            records[2],
+            Record {
+                time: 2,
+                phase: Phase::Instruction,
+                pc: 2,
+                inst: 0x4e,
+                a: 3,
+                flags: Flags::CLEAR,
+                writes: vec![MemWrite { addr: 14, value: 3 }],
+            }
+        );
+        assert_eq!(records.last().unwrap().inst, 0xf0);
+        assert!(records.iter().all(|r| r.phase == Phase::Instruction));
+    }
+
+    #[test]
+    fn test_trace_sim() {
+        let records = record(EaterSim::with_output(Vec::new()));
+
+        // LDA 14, ADD 15, STA 14; the store happens in T3
+        let phases: Vec<_> = records[..15].iter().map(|r| r.phase).collect();
+        let steps: Vec<_> = (0..15).map(|t| Phase::Step(t % 5)).collect();
+        assert_eq!(phases, steps);
+
+        let insts: Vec<_> = records[..15].iter().map(|r| r.inst).collect();
+        assert_eq!(insts[..5], [0x1e; 5]);
+        assert_eq!(insts[5..10], [0x2f; 5]);
+        assert_eq!(insts[10..], [0x4e; 5]);
+
+        let writes: Vec<_> = records[..15]
+            .iter()
+            .enumerate()
+            .filter(|(_, r)| !r.writes.is_empty())
+            .map(|(i, r)| (i, r.writes.clone()))
+            .collect();
+        assert_eq!(writes, [(13, vec![MemWrite { addr: 14, value: 3 }])]);
+
+        // Interpreter and simulator agree at instruction boundaries
+        let interp = record(EaterVm::with_output(Vec::new()));
+        let sim: Vec<_> = records
+            .chunks(5)
+            .map(|chunk| chunk.last().unwrap().a)
+            .collect();
+        let vm: Vec<_> = interp.iter().map(|r| r.a).collect();
+        assert_eq!(sim, vm);
+    }
+
+    #[test]
+    fn test_trace_json_round_trip() {
+        let records = record(EaterSim::with_output(Vec::new()));
+
+        let mut json = Vec::new();
+        write_json(&mut json, &records).unwrap();
+        let text = String::from_utf8(json.clone()).unwrap();
+        assert_eq!(text.lines().count(), records.len());
+        assert!(text.starts_with(
+            "{\"time\":0,\"phase\":\"t0\",\"pc\":0,\"inst\":30,\"asm\":\"lda 14\",\"a\":0,"
+        ));
+
+        assert_eq!(read_json(&json[..]).unwrap(), records);
+        assert_eq!(read(&json[..]).unwrap(), records);
+    }
+
+    #[test]
+    fn test_trace_binary_round_trip() {
+        let mut records = record(EaterVm::with_output(Vec::new()));
+        records[0].time = u64::MAX;
+
+        let mut binary = Vec::new();
+        write_binary(&mut binary, &records).unwrap();
+        assert!(binary.len() < records.len() * 9);
+
+        assert_eq!(read_binary(&binary[..]).unwrap(), records);
+        assert_eq!(read(&binary[..]).unwrap(), records);
+
+        // Truncated in the middle of a record
+        let err = read_binary(&binary[..binary.len() - 1]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(read_binary(&b"EATX\x01"[..]).is_err());
+        assert!(read_binary(&b"EATR\x02"[..]).is_err());
+    }
+
+    #[test]
+    fn test_trace_stream() {
+        let records = record(EaterSim::with_output(Vec::new()));
+
+        let mut recorder = Recorder::with_sink(
+            EaterSim::with_output(Vec::new()),
+            JsonWriter::new(Vec::new()),
+        );
+        recorder.load(&STORE_THREES).unwrap();
+        recorder.step_instruction().unwrap();
+        assert!(!recorder.sink.writer.is_empty());
+        recorder.run().unwrap();
+        let json = recorder.finish().unwrap().into_inner();
+        assert_eq!(read_json(&json[..]).unwrap(), records);
+
+        let sink = BinaryWriter::new(Vec::new()).unwrap();
+        let mut recorder = Recorder::with_sink(EaterSim::with_output(Vec::new()), sink);
+        recorder.load(&STORE_THREES).unwrap();
+        recorder.run().unwrap();
+        let binary = recorder.finish().unwrap().into_inner();
+        assert_eq!(read_binary(&binary[..]).unwrap(), records);
+    }
+}
@@ -0,0 +1,323 @@
+//! Static checks for microcode tables.
+//!
+//! `validate` walks every opcode, step and flag state of a table, such as one read from EEPROM
+//! dumps with `eeprom::microcode`, and reports the control words that can't work on the real
+//! machine: several outputs driving the bus at once, inputs latching a floating bus, FI without
+//! the ALU on the bus, control lines in steps the step counter never reaches, and fetch steps
+//! that differ from the standard fetch or from the rest of the table.
+
+use crate::cpu::Flags;
+use crate::isa;
+use crate::microcode::{
+    Control, Microcode, AI, AO, BI, CO, CONTROL_LINES, EO, FETCH, FLAG_STATES, II, IO, J, MI, OI,
+    OPCODES, RI, RO, STEPS,
+};
+use std::fmt;
+
+/// Control lines that drive the bus.
+pub const BUS_OUTPUTS: Control = Control::from_bits_truncate(CO | RO | IO | AO | EO);
+
+/// Control lines that latch the bus.
+pub const BUS_INPUTS: Control = Control::from_bits_truncate(MI | RI | II | AI | BI | OI | J);
+
+/// A mistake in a single control word.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Problem {
+    /// More than one output drives the bus.
+    BusConflict { outputs: Control },
+    /// Inputs latch the bus while nothing drives it.
+    FloatingBus { inputs: Control },
+    /// FI latches the flags while the ALU isn't on the bus, so they don't match any result.
+    FlagsWithoutSum,
+    /// Control lines are set in a step after the step counter resets.
+    UnreachableStep { control: Control },
+    /// A fetch step differs from the standard fetch.
+    Fetch { expected: Control, found: Control },
+    /// A fetch step differs from the one most of the table uses, which isn't the standard fetch
+    /// either. The instruction register still holds the previous instruction during fetch, so
+    /// every opcode and flag state has to fetch alike.
+    InconsistentFetch { expected: Control, found: Control },
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Problem::BusConflict { outputs } => {
+                write!(f, "bus conflict between {}", names(*outputs))
+            }
+            Problem::FloatingBus { inputs } => {
+                write!(
+                    f,
+                    "{} reads the bus with nothing driving it",
+                    names(*inputs)
+                )
+            }
+            Problem::FlagsWithoutSum => write!(f, "FI without EO"),
+            Problem::UnreachableStep { control } => write!(
+                f,
+                "{} after the step counter resets, so it never runs",
+                names(*control)
+            ),
+            Problem::Fetch { expected, found } => write!(
+                f,
+                "fetch step is {}, expected {}",
+                names(*found),
+                names(*expected)
+            ),
+            Problem::InconsistentFetch { expected, found } => write!(
+                f,
+                "fetch step is {}, but the rest of the table uses {}",
+                names(*found),
+                names(*expected)
+            ),
+        }
+    }
+}
+
+/// A problem and the table entry it was found in.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub opcode: u8,
+    pub step: u8,
+    pub flags: Flags,
+    pub problem: Problem,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "opcode {:#x}", self.opcode)?;
+        if let Some(inst) = isa::decode(self.opcode) {
+            write!(f, " ({})", inst.mnemonic)?;
+        }
+        write!(f, " t{} flags {}: {}", self.step, self.flags, self.problem)
+    }
+}
+
+/// Control line names joined with `|`, in control word order.
+fn names(control: Control) -> String {
+    let names: Vec<_> = CONTROL_LINES
+        .iter()
+        .filter(|(_, line)| control.contains(*line))
+        .map(|(name, _)| *name)
+        .collect();
+
+    if names.is_empty() {
+        "nothing".to_string()
+    } else {
+        names.join("|")
+    }
+}
+
+/// Check one control word, ignoring where it is in the table.
+fn check(control: Control) -> Vec<Problem> {
+    let mut problems = Vec::new();
+    let outputs = control & BUS_OUTPUTS;
+    let inputs = control & BUS_INPUTS;
+
+    if outputs.bits().count_ones() > 1 {
+        problems.push(Problem::BusConflict { outputs });
+    }
+    if outputs.is_empty() && !inputs.is_empty() {
+        problems.push(Problem::FloatingBus { inputs });
+    }
+    if control.contains(Control::FI) && !control.contains(Control::EO) {
+        problems.push(Problem::FlagsWithoutSum);
+    }
+
+    problems
+}
+
+/// The control word most opcodes and flag states use for a step, preferring the earliest entry
+/// on a tie.
+fn consensus(microcode: &Microcode, step: u8) -> Control {
+    let entries: Vec<_> = (0..OPCODES as u8)
+        .flat_map(|opcode| {
+            (0..FLAG_STATES as u8)
+                .map(move |flags| microcode.control(Flags::from_bits_truncate(flags), opcode, step))
+        })
+        .collect();
+    let count = |control| entries.iter().filter(|&&entry| entry == control).count();
+
+    entries
+        .iter()
+        .copied()
+        .rev()
+        .max_by_key(|&control| count(control))
+        .unwrap_or_default()
+}
+
+/// Check every entry of a microcode table, in order of opcode, step and flags.
+pub fn validate(microcode: &Microcode) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+    let consensus: Vec<_> = (0..FETCH.len() as u8)
+        .map(|step| consensus(microcode, step))
+        .collect();
+
+    for opcode in 0..OPCODES as u8 {
+        for step in 0..STEPS as u8 {
+            for flags in 0..FLAG_STATES as u8 {
+                let flags = Flags::from_bits_truncate(flags);
+                let control = microcode.control(flags, opcode, step);
+
+                let problems = if step >= microcode.steps() && control.is_empty() {
+                    Vec::new()
+                } else if step >= microcode.steps() {
+                    vec![Problem::UnreachableStep { control }]
+                } else {
+                    let mut problems = Vec::new();
+                    if let Some(&bits) = FETCH.get(step as usize) {
+                        let expected = Control::from_bits_truncate(bits);
+                        if control != expected {
+                            problems.push(Problem::Fetch {
+                                expected,
+                                found: control,
+                            });
+                        }
+
+                        // Differences from a standard table are already reported above
+                        let common = consensus[step as usize];
+                        if common != expected && control != common {
+                            problems.push(Problem::InconsistentFetch {
+                                expected: common,
+                                found: control,
+                            });
+                        }
+                    }
+                    problems.extend(check(control));
+                    problems
+                };
+
+                diagnostics.extend(problems.into_iter().map(|problem| Diagnostic {
+                    opcode,
+                    step,
+                    flags,
+                    problem,
+                }));
+            }
+        }
+    }
+
+    diagnostics
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::Variant;
+
+    #[test]
+    fn test_validate_reference() {
+        assert_eq!(validate(&Microcode::for_variant(Variant::Original)), []);
+        assert_eq!(validate(&Microcode::for_variant(Variant::Ram256)), []);
+    }
+
+    #[test]
+    fn test_validate_problems() {
+        let mut microcode = Microcode::default();
+        let set = |microcode: &mut Microcode, opcode, step, control| {
+            for flags in 0..FLAG_STATES as u8 {
+                let flags = Flags::from_bits_truncate(flags);
+                microcode.set_control(flags, opcode, step, control);
+            }
+        };
+
+        // A conditional jump with its operand on the bus in only one flag state, alongside A
+        microcode.set_control(Flags::C, 0x7, 2, Control::IO | Control::AO | Control::J);
+        // An ADD that latches B from nothing and the flags without the sum
+        set(&mut microcode, 0x2, 3, Control::BI);
+        set(&mut microcode, 0x2, 4, Control::AI | Control::FI);
+        // An OUT that lost CE in its fetch and runs past the step counter
+        set(&mut microcode, 0xe, 1, Control::RO | Control::II);
+        set(&mut microcode, 0xe, 5, Control::HLT);
+        // A JMP that lost MI in its fetch
+        set(&mut microcode, 0x6, 0, Control::CO);
+
+        let diagnostics = validate(&microcode);
+        let at = |opcode, step| {
+            diagnostics
+                .iter()
+                .filter(|diagnostic| (diagnostic.opcode, diagnostic.step) == (opcode, step))
+                .map(|diagnostic| (diagnostic.flags, diagnostic.problem.clone()))
+                .collect::<Vec<_>>()
+        };
+        let every = |problems: &[Problem]| {
+            (0..FLAG_STATES as u8)
+                .flat_map(|flags| {
+                    let flags = Flags::from_bits_truncate(flags);
+                    problems.iter().map(move |problem| (flags, problem.clone()))
+                })
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(diagnostics.len(), 1 + 4 * 6);
+        assert_eq!(
+            at(0x7, 2),
+            [(
+                Flags::C,
+                Problem::BusConflict {
+                    outputs: Control::IO | Control::AO,
+                }
+            )]
+        );
+        assert_eq!(
+            at(0x2, 3),
+            every(&[Problem::FloatingBus {
+                inputs: Control::BI,
+            }])
+        );
+        assert_eq!(
+            at(0x2, 4),
+            every(&[
+                Problem::FloatingBus {
+                    inputs: Control::AI,
+                },
+                Problem::FlagsWithoutSum,
+            ])
+        );
+        assert_eq!(
+            at(0xe, 1),
+            every(&[Problem::Fetch {
+                expected: Control::RO | Control::II | Control::CE,
+                found: Control::RO | Control::II,
+            }])
+        );
+        assert_eq!(
+            at(0x6, 0),
+            every(&[Problem::Fetch {
+                expected: Control::CO | Control::MI,
+                found: Control::CO,
+            }])
+        );
+        assert_eq!(
+            at(0xe, 5),
+            every(&[Problem::UnreachableStep {
+                control: Control::HLT,
+            }])
+        );
+
+        assert_eq!(
+            diagnostics[0].to_string(),
+            "opcode 0x2 (add) t3 flags --: BI reads the bus with nothing driving it"
+        );
+
+        // A table that lost CE everywhere but in NOP is flagged throughout, and NOP stands out
+        let mut microcode = Microcode::default();
+        for opcode in 1..OPCODES as u8 {
+            set(&mut microcode, opcode, 1, Control::RO | Control::II);
+        }
+        let diagnostics = validate(&microcode);
+        assert_eq!(diagnostics.len(), OPCODES * FLAG_STATES);
+        assert!(diagnostics[FLAG_STATES..]
+            .iter()
+            .all(|diagnostic| diagnostic.problem
+                == Problem::Fetch {
+                    expected: Control::RO | Control::II | Control::CE,
+                    found: Control::RO | Control::II,
+                }));
+        assert_eq!(
+            diagnostics[0].to_string(),
+            "opcode 0x0 (nop) t1 flags --: fetch step is RO|II|CE, but the rest of the table \
+             uses RO|II"
+        );
+    }
+}
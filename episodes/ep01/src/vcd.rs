@@ -0,0 +1,360 @@
+//! Value Change Dump export of the simulator's signals, for viewing runs in GTKWave next to
+//! logic analyzer captures.
+
+use crate::cpu::{Cpu, Flags, OpcodePolicy, Phase, Variant};
+use crate::error::EaterError;
+use crate::microcode::{Control, CONTROL_LINES};
+use crate::output::Output;
+use crate::sim::EaterSim;
+use std::io::{self, Write};
+
+/// Names and widths of the signals that are always dumped. The PC and memory address register
+/// are 8 bits wide on `Variant::Ram256`.
+const SIGNALS: [(&str, u8); 12] = [
+    ("clk", 1),
+    ("step", 3),
+    ("pc", 4),
+    ("a", 8),
+    ("b", 8),
+    ("mar", 4),
+    ("ir", 8),
+    ("out", 8),
+    ("bus", 8),
+    ("c", 1),
+    ("z", 1),
+    ("halt", 1),
+];
+
+const CLK: usize = 0;
+const STEP: usize = 1;
+const PC: usize = 2;
+const A: usize = 3;
+const B: usize = 4;
+const MAR: usize = 5;
+const IR: usize = 6;
+const OUT: usize = 7;
+const BUS: usize = 8;
+const CARRY: usize = 9;
+const ZERO: usize = 10;
+const HALT: usize = 11;
+
+/// Index of the first control line, which follow the other signals.
+const CONTROL: usize = SIGNALS.len();
+
+/// The short identifier of a signal in the dump.
+fn id(signal: usize) -> char {
+    (b'!' + signal as u8) as char
+}
+
+/// Wraps the simulator and dumps its signals after every T-state.
+///
+/// Each T-state starts on the falling clock edge, when the step counter advances and the control
+/// lines and bus settle. Registers latch on the rising edge half a period later. Control lines
+/// are only dumped when the simulator runs from microcode, since the behavioral mode doesn't
+/// model them.
+///
+/// Write errors don't interrupt the simulation; the first one is returned by `finish`.
+#[derive(Debug)]
+pub struct VcdWriter<W: Write, O: Output> {
+    sim: EaterSim<O>,
+    writer: W,
+    period: u64,
+    ticks: u64,
+    values: Vec<Option<u64>>,
+    controls: bool,
+    error: Option<io::Error>,
+}
+
+impl<W: Write, O: Output> VcdWriter<W, O> {
+    /// Write the header and the initial state of the simulator. The clock period is in
+    /// nanoseconds.
+    pub fn new(sim: EaterSim<O>, mut writer: W, period: u64) -> io::Result<Self> {
+        assert!(period >= 2, "Clock period must be at least 2 ns");
+
+        let controls = sim.microcode().is_some();
+        writeln!(writer, "$version eater {} $end", env!("CARGO_PKG_VERSION"))?;
+        writeln!(writer, "$timescale 1ns $end")?;
+        writeln!(writer, "$scope module eater $end")?;
+        for (signal, &(name, width)) in SIGNALS.iter().enumerate() {
+            let width = match signal {
+                PC | MAR if sim.variant() == Variant::Ram256 => 8,
+                _ => width,
+            };
+            writeln!(writer, "$var wire {} {} {} $end", width, id(signal), name)?;
+        }
+        if controls {
+            writeln!(writer, "$scope module control $end")?;
+            for (line, (name, _)) in CONTROL_LINES.iter().enumerate() {
+                writeln!(writer, "$var wire 1 {} {} $end", id(CONTROL + line), name)?;
+            }
+            writeln!(writer, "$upscope $end")?;
+        }
+        writeln!(writer, "$upscope $end")?;
+        writeln!(writer, "$enddefinitions $end")?;
+
+        let signals = CONTROL + if controls { CONTROL_LINES.len() } else { 0 };
+        let mut vcd = Self {
+            sim,
+            writer,
+            period,
+            ticks: 0,
+            values: vec![None; signals],
+            controls,
+            error: None,
+        };
+
+        writeln!(vcd.writer, "#0")?;
+        writeln!(vcd.writer, "$dumpvars")?;
+        vcd.change(CLK, 1)?;
+        vcd.change(STEP, vcd.sim.step_counter().into())?;
+        vcd.change(BUS, vcd.sim.bus().into())?;
+        vcd.change_control(vcd.sim.control())?;
+        vcd.change_registers()?;
+        writeln!(vcd.writer, "$end")?;
+
+        Ok(vcd)
+    }
+
+    pub fn sim(&self) -> &EaterSim<O> {
+        &self.sim
+    }
+
+    /// Access the simulator directly. Changes made through it show up at the next rising edge.
+    pub fn sim_mut(&mut self) -> &mut EaterSim<O> {
+        &mut self.sim
+    }
+
+    /// End the dump, returning the simulator and writer, or the first write error.
+    pub fn finish(mut self) -> io::Result<(EaterSim<O>, W)> {
+        if let Some(err) = self.error.take() {
+            return Err(err);
+        }
+
+        // Mark the end of the last rising edge
+        writeln!(
+            self.writer,
+            "#{}",
+            self.ticks * self.period + self.period / 2
+        )?;
+        self.writer.flush()?;
+
+        Ok((self.sim, self.writer))
+    }
+
+    fn change(&mut self, signal: usize, value: u64) -> io::Result<()> {
+        if self.values[signal] == Some(value) {
+            return Ok(());
+        }
+        self.values[signal] = Some(value);
+
+        match SIGNALS.get(signal).map_or(1, |&(_, width)| width) {
+            1 => writeln!(self.writer, "{}{}", value, id(signal)),
+            _ => writeln!(self.writer, "b{:b} {}", value, id(signal)),
+        }
+    }
+
+    fn change_control(&mut self, control: Option<Control>) -> io::Result<()> {
+        if !self.controls {
+            return Ok(());
+        }
+
+        let control = control.unwrap_or_default();
+        for (line, (_, bit)) in CONTROL_LINES.iter().enumerate() {
+            self.change(CONTROL + line, control.contains(*bit).into())?;
+        }
+
+        Ok(())
+    }
+
+    fn change_registers(&mut self) -> io::Result<()> {
+        let flags = self.sim.flags();
+
+        self.change(PC, self.sim.pc().into())?;
+        self.change(A, self.sim.a().into())?;
+        self.change(B, self.sim.b().into())?;
+        self.change(MAR, self.sim.mar().into())?;
+        self.change(IR, self.sim.ir().into())?;
+        self.change(OUT, self.sim.out().into())?;
+        self.change(CARRY, flags.contains(Flags::C).into())?;
+        self.change(ZERO, flags.contains(Flags::Z).into())?;
+        self.change(HALT, self.sim.halted().into())
+    }
+
+    /// Dump one T-state, which already executed with the given step counter and control word.
+    fn dump(&mut self, step: u8, control: Option<Control>) -> io::Result<()> {
+        let falling = self.ticks * self.period + self.period / 2;
+        self.ticks += 1;
+
+        writeln!(self.writer, "#{}", falling)?;
+        self.change(CLK, 0)?;
+        self.change(STEP, step.into())?;
+        self.change(BUS, self.sim.bus().into())?;
+        self.change_control(control)?;
+
+        writeln!(self.writer, "#{}", self.ticks * self.period)?;
+        self.change(CLK, 1)?;
+        self.change_registers()
+    }
+}
+
+impl<W: Write, O: Output> Cpu for VcdWriter<W, O> {
+    fn load(&mut self, mem: &[u8]) -> Result<(), EaterError> {
+        self.sim.load(mem)
+    }
+
+    fn reset(&mut self) {
+        self.sim.reset();
+    }
+
+    fn set_opcode_policy(&mut self, policy: OpcodePolicy) {
+        self.sim.set_opcode_policy(policy);
+    }
+
+    fn poke(&mut self, addr: u8, value: u8) {
+        self.sim.poke(addr, value);
+    }
+
+    fn step_instruction(&mut self) -> Result<bool, EaterError> {
+        loop {
+            let halted = self.step_clock()?;
+            if halted || self.sim.step_counter() == 0 {
+                return Ok(halted);
+            }
+        }
+    }
+
+    fn step_clock(&mut self) -> Result<bool, EaterError> {
+        if self.sim.halted() {
+            return self.sim.step_clock();
+        }
+
+        let step = self.sim.step_counter();
+        let control = self.sim.control();
+        let result = self.sim.step_clock();
+
+        if self.error.is_none() {
+            self.error = self.dump(step, control).err();
+        }
+
+        result
+    }
+
+    fn pc(&self) -> u8 {
+        self.sim.pc()
+    }
+
+    fn a(&self) -> u8 {
+        self.sim.a()
+    }
+
+    fn ir(&self) -> u8 {
+        self.sim.ir()
+    }
+
+    fn phase(&self) -> Phase {
+        self.sim.phase()
+    }
+
+    fn flags(&self) -> Flags {
+        self.sim.flags()
+    }
+
+    fn mem(&self) -> &[u8] {
+        self.sim.mem()
+    }
+
+    fn halted(&self) -> bool {
+        self.sim.halted()
+    }
+
+    fn cycles(&self) -> u64 {
+        self.sim.cycles()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::microcode::Microcode;
+
+    fn dump(microcode: Option<Microcode>) -> String {
+        let mut sim = EaterSim::with_output(Vec::new());
+        sim.set_microcode(microcode);
+
+        let mut vcd = VcdWriter::new(sim, Vec::new(), 1000).unwrap();
+        vcd.load(include_bytes!("example.bin")).unwrap();
+        vcd.run().unwrap();
+
+        let (sim, writer) = vcd.finish().unwrap();
+        assert_eq!(sim.into_output().last(), Some(&2));
+        String::from_utf8(writer).unwrap()
+    }
+
+    #[test]
+    fn test_vcd_behavior() {
+        let vcd = dump(None);
+
+        assert!(vcd.contains("$timescale 1ns $end\n"));
+        assert!(vcd.contains("$var wire 4 # pc $end\n"));
+        assert!(vcd.contains("$var wire 8 ) bus $end\n"));
+        assert!(!vcd.contains("$scope module control $end"));
+        assert!(vcd.contains("#0\n$dumpvars\n1!\nb0 \"\nb0 )\n"));
+
+        // T1 puts the instruction on the bus, then latches it and increments the PC
+        assert!(vcd.contains("#1500\n0!\nb1 \"\nb11110 )\n#2000\n1!\nb1 #\nb11110 '\n"));
+
+        // Halts in T2 of the last instruction
+        assert!(vcd.ends_with("#1723000\n1!\n1,\n#1723500\n"));
+    }
+
+    #[test]
+    fn test_vcd_microcode() {
+        let vcd = dump(Some(Microcode::default()));
+
+        assert!(vcd.contains("$scope module control $end\n$var wire 1 - HLT $end\n"));
+        assert!(vcd.contains("$var wire 1 < FI $end\n$upscope $end\n$upscope $end\n"));
+
+        // MI and CO are asserted from the start, then RO, II and CE during T1
+        assert!(vcd.contains("$dumpvars\n1!\nb0 \"\nb0 )\n0-\n1.\n"));
+        assert!(vcd.contains("#1500\n0!\nb1 \"\nb11110 )\n0.\n10\n12\n19\n0:\n#2000\n"));
+
+        // Both modes take the same number of T-states
+        assert_eq!(
+            dump(None)
+                .lines()
+                .filter(|line| line.starts_with('#'))
+                .count(),
+            vcd.lines().filter(|line| line.starts_with('#')).count()
+        );
+    }
+
+    #[test]
+    fn test_vcd_ram256() {
+        let mut sim = EaterSim::with_output(Vec::new());
+        sim.set_variant(Variant::Ram256);
+
+        let vcd = VcdWriter::new(sim, Vec::new(), 1000).unwrap();
+        let (_, writer) = vcd.finish().unwrap();
+        let vcd = String::from_utf8(writer).unwrap();
+
+        assert!(vcd.contains("$var wire 8 # pc $end\n"));
+        assert!(vcd.contains("$var wire 8 & mar $end\n"));
+    }
+
+    #[test]
+    fn test_vcd_write_error() {
+        struct Broken;
+
+        impl Write for Broken {
+            fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+                Err(io::Error::other("broken"))
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        assert!(VcdWriter::new(EaterSim::with_output(Vec::new()), Broken, 1000).is_err());
+    }
+}